@@ -17,6 +17,9 @@ pub struct OwwModel {
     pub tract_model: ModelType,
     threshold: f32,
     pub last_detection_time: Instant,
+    /// Chunks processed since the last detection; measures the detection cooldown in audio time
+    /// so offline processing (faster than real time) behaves the same as a live mic
+    pub chunks_since_last_detection: u32,
     // pub detections_buffer: CircularBuffer<DETECTION_BUFFER_SIZE, f32>,
     pub detections_buffer: CircularBuffer<DETECTION_BUFFER_SIZE, f32>,
     pub model_unlock_word: String,
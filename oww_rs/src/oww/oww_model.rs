@@ -18,6 +18,7 @@ use tract_onnx::prelude::{InferenceModelExt, IntoTensor, Tensor as TractTensor,
 
 const MIN_POSITIVE_DETECTIONS: f32 = 3.0;
 const NO_DETECTION_MS: u32 = 2_000;
+const CHUNK_DURATION_MS: u128 = (oww::OWW_MODEL_CHUNK_SIZE * 1000 / crate::VOICE_SAMPLE_RATE) as u128;
 
 #[derive(Embed)]
 #[folder = "speech_models/"]
@@ -66,11 +67,13 @@ impl OwwModel {
         self.detections_buffer.push_back(probability);
 
         let average_detection_probability = self.calculate_average();
-        let since_last_detection = self.last_detection_time.elapsed().as_millis();
+        self.chunks_since_last_detection = self.chunks_since_last_detection.saturating_add(1);
+        let since_last_detection = self.chunks_since_last_detection as u128 * CHUNK_DURATION_MS;
 
         // when detection is done and avg detection is still high. Anf not too often
         if probability < 0.1 && average_detection_probability > self.threshold && since_last_detection > NO_DETECTION_MS as _ {
             self.last_detection_time = Instant::now();
+            self.chunks_since_last_detection = 0;
             return (true, average_detection_probability);
         }
         if average_detection_probability > 0.1 {
@@ -111,6 +114,7 @@ impl OwwModel {
             tract_model,
             threshold,
             last_detection_time: Instant::now(),
+            chunks_since_last_detection: 0,
             detections_buffer,
             model_unlock_word,
         })
@@ -122,12 +126,18 @@ impl OwwModel {
 
         let mut rdr = Cursor::new(model_data);
 
-        let tract_model = tract_onnx::onnx().model_for_read(&mut rdr).unwrap().into_optimized().unwrap().into_runnable().unwrap();
+        // user supplied models may be invalid, report instead of panicking
+        let tract_model = tract_onnx::onnx()
+            .model_for_read(&mut rdr)
+            .and_then(|m| m.into_optimized())
+            .and_then(|m| m.into_runnable())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("invalid ONNX model: {}", e)))?;
         Ok(OwwModel {
             audio: AudioFeaturesTract::create_default(),
             tract_model,
             threshold,
             last_detection_time: Instant::now(),
+            chunks_since_last_detection: 0,
             detections_buffer,
             model_unlock_word,
         })
@@ -2,30 +2,38 @@ use crate::VOICE_SAMPLE_RATE;
 use chrono::Utc;
 use log::{debug, warn};
 
+/// The name every recording is saved under:
+/// `{dir_path}/{prefix}_{me}_{detection_prc}_{timestamp}_{max_rms}.wav`.
+fn wav_filename(detection_prc: u8, me: &str, prefix: &str, max_rms: i16, dir_path: &str) -> String {
+    let ts = Utc::now().format("%y%m%d-%H%M%S").to_string();
+    format!("{dir_path}/{}_{}_{}_{}_{}.wav", prefix, me, detection_prc, ts, max_rms)
+}
+
+/// Save a mono recording and return its file name, or an empty string if it could not be saved.
 pub fn save_full_wav(data: &Vec<i16>, detection_prc: u8, me: &str, prefix: &str, max_rms: i16, dir_path: &str) -> String {
+    try_save_full_wav(data, detection_prc, me, prefix, max_rms, dir_path).unwrap_or_else(|e| {
+        warn!("Error writing to wav file {:?}", e);
+        String::new()
+    })
+}
+
+/// Like [`save_full_wav`], but reports failures instead of logging them, and finalizes the file
+/// so a write error is not lost on drop.
+pub fn try_save_full_wav(data: &[i16], detection_prc: u8, me: &str, prefix: &str, max_rms: i16, dir_path: &str) -> Result<String, hound::Error> {
     let spec = hound::WavSpec {
         channels: 1,
         sample_rate: VOICE_SAMPLE_RATE as _,
         bits_per_sample: 16,
         sample_format: hound::SampleFormat::Int,
     };
-    let now = Utc::now();
-    let ts = now.format("%y%m%d-%H%M%S").to_string();
-
-    let filename = format!("{dir_path}/{}_{}_{}_{}_{}.wav", prefix, me, detection_prc, ts, max_rms);
-    // println!("Saving {}", filename);
-    let mut writer = hound::WavWriter::create(&filename, spec).unwrap();
-
+    let filename = wav_filename(detection_prc, me, prefix, max_rms, dir_path);
+    let mut writer = hound::WavWriter::create(&filename, spec)?;
     for d in data {
-        // if (short_wav && index > RING_BUFFER_SIZE / 2) || !short_wav {
-        if let Err(e) = writer.write_sample(*d) {
-            warn!("Error writing to wav file {:?}", e);
-            break;
-        }
-        // }
+        writer.write_sample(*d)?;
     }
+    writer.finalize()?;
     debug!("Recording saved to {filename}");
-    filename
+    Ok(filename)
 }
 
 pub fn save_full_wav_with_channels(
@@ -44,10 +52,7 @@ pub fn save_full_wav_with_channels(
         bits_per_sample: 16,
         sample_format: hound::SampleFormat::Int,
     };
-    let now = Utc::now();
-    let ts = now.format("%y%m%d-%H%M%S").to_string();
-
-    let filename = format!("{dir_path}/{}_{}_{}_{}_{}.wav", prefix, me, detection_prc, ts, max_rms);
+    let filename = wav_filename(detection_prc, me, prefix, max_rms, dir_path);
     debug!("Saving {}, rate {}", filename, sample_rate);
     let mut writer = hound::WavWriter::create(&filename, spec).unwrap();
 
@@ -0,0 +1,112 @@
+use color_eyre::eyre::Result;
+
+use crate::audio_resampler::{AudioResampler, Downmix, Downmixer};
+use crate::config::ResamplerQuality;
+use crate::speech_listener::SAMPLE_RATE;
+
+/// A consumer of the front end's audio, as returned by [`AudioFrontEnd::tap`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tap(usize);
+
+struct Chunker {
+    size: usize,
+    pending: Vec<f32>,
+    ready: Vec<Vec<f32>>,
+}
+
+/// Turns input audio into 16 kHz mono once and splits that one stream into chunks for each
+/// consumer, so the wake word and the VAD hear exactly the same samples on the same clock, each
+/// in the chunk size its model wants.
+pub struct AudioFrontEnd {
+    downmixer: Downmixer,
+    resampler: AudioResampler,
+    taps: Vec<Chunker>,
+}
+
+impl AudioFrontEnd {
+    pub fn new(
+        input_rate: u32,
+        channels: u16,
+        downmix: Downmix,
+        quality: ResamplerQuality,
+    ) -> Result<Self> {
+        Ok(Self {
+            downmixer: Downmixer::new(channels, downmix)?,
+            resampler: AudioResampler::new(input_rate, SAMPLE_RATE, quality)?,
+            taps: Vec::new(),
+        })
+    }
+
+    /// Add a consumer that takes the audio in chunks of `chunk_size` samples. It only sees audio
+    /// processed after this call.
+    pub fn tap(&mut self, chunk_size: usize) -> Tap {
+        self.taps.push(Chunker {
+            size: chunk_size,
+            pending: Vec::new(),
+            ready: Vec::new(),
+        });
+        Tap(self.taps.len() - 1)
+    }
+
+    /// Feed interleaved input audio; the resulting chunks wait in each tap until taken.
+    pub fn process(&mut self, interleaved: &[f32]) {
+        let mono = self.downmixer.downmix(interleaved);
+        let samples = self.resampler.resample(&mono);
+        for tap in &mut self.taps {
+            tap.pending.extend_from_slice(&samples);
+            while tap.pending.len() >= tap.size {
+                let rest = tap.pending.split_off(tap.size);
+                tap.ready.push(std::mem::replace(&mut tap.pending, rest));
+            }
+        }
+    }
+
+    /// Take the complete chunks waiting for `tap`, oldest first.
+    pub fn chunks(&mut self, tap: Tap) -> Vec<Vec<f32>> {
+        std::mem::take(&mut self.taps[tap.0].ready)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn taps_get_the_same_audio_in_their_own_chunk_sizes() {
+        // One second of 48 kHz stereo with a different ramp on each channel
+        let input = (0..48000)
+            .flat_map(|i| {
+                let t = i as f32 / 48000.0;
+                [t, -t / 2.0]
+            })
+            .collect::<Vec<_>>();
+
+        let mut front_end =
+            AudioFrontEnd::new(48000, 2, Downmix::Average, ResamplerQuality::Fast).unwrap();
+        let wake_word = front_end.tap(1280);
+        let vad = front_end.tap(512);
+        let (mut wake_word_chunks, mut vad_chunks) = (Vec::new(), Vec::new());
+        for block in input.chunks(2 * 441) {
+            front_end.process(block);
+            wake_word_chunks.extend(front_end.chunks(wake_word));
+            vad_chunks.extend(front_end.chunks(vad));
+        }
+
+        assert!(wake_word_chunks.iter().all(|chunk| chunk.len() == 1280));
+        assert!(vad_chunks.iter().all(|chunk| chunk.len() == 512));
+        assert_eq!(wake_word_chunks.len(), 12);
+        assert_eq!(vad_chunks.len(), 31);
+
+        // Both see one stream: the same samples at the same positions
+        let wake_word_audio = wake_word_chunks.concat();
+        let vad_audio = vad_chunks.concat();
+        assert_eq!(wake_word_audio[..], vad_audio[..wake_word_audio.len()]);
+
+        // And it is the downmixed input resampled once
+        let mut downmixer = Downmixer::new(2, Downmix::Average).unwrap();
+        let mut resampler = AudioResampler::new(48000, 16000, ResamplerQuality::Fast).unwrap();
+        let expected = resampler.resample(&downmixer.downmix(&input));
+        assert_eq!(vad_audio[..], expected[..vad_audio.len()]);
+        assert!(front_end.chunks(vad).is_empty());
+    }
+}
@@ -0,0 +1,535 @@
+use std::str::FromStr;
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{OptionExt, Result};
+use cpal::traits::StreamTrait;
+use cpal::{
+    BufferSize, Device, SampleFormat, StreamConfig, SupportedStreamConfigRange,
+    traits::{DeviceTrait, HostTrait},
+};
+
+use tracing::{debug, info, warn};
+
+use crate::config::AudioConfig;
+use crate::speech_listener::{SpeechEvent, SpeechPipelineConfig, StreamHealth, create_stream};
+
+/// How often the supervisor wakes up to check stream health when no speech events arrive.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A running cpal input stream together with the speech events it produces.
+pub struct InputStream {
+    pub stream: cpal::Stream,
+    pub events: mpsc::Receiver<SpeechEvent>,
+    pub health: Arc<StreamHealth>,
+}
+
+/// What the input supervisor reports to the rest of the app.
+pub enum InputEvent {
+    Speech(SpeechEvent),
+    /// The stream died or stalled and has been torn down; carries the reason
+    Lost(String),
+    /// A new stream is running again after a `Lost`
+    Recovered,
+}
+
+/// Timeouts controlling when a stream is considered dead and how quickly it is reopened.
+#[derive(Debug, Clone)]
+pub struct RecoveryConfig {
+    pub stall_timeout: Duration,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RecoveryConfig {
+    pub fn from_audio_config(config: &AudioConfig) -> Self {
+        Self {
+            stall_timeout: Duration::from_secs_f64(config.stall_timeout_seconds),
+            initial_backoff: Duration::from_secs_f64(config.reconnect_initial_backoff_seconds),
+            max_backoff: Duration::from_secs_f64(config.reconnect_max_backoff_seconds),
+        }
+    }
+}
+
+pub fn get_device(device_id: &str) -> Result<Device> {
+    let device_id = cpal::DeviceId::from_str(device_id)?;
+
+    // Get a completely fresh host each time to ensure clean state
+    // This is critical for ALSA devices that may get into bad states
+    let host = cpal::default_host();
+    let input_devices: Vec<Device> = host.input_devices()?.collect();
+
+    let device = input_devices
+        .into_iter()
+        .find(|d| d.id().ok().map(|id| id == device_id).unwrap_or(false))
+        .ok_or_eyre("No input device found")?;
+
+    // Verify device is accessible before returning
+    device.id()?;
+
+    Ok(device)
+}
+
+/// Every input config range the device reports, queried on a fresh device handle.
+pub fn supported_input_configs(device_id: &str) -> Result<Vec<SupportedStreamConfigRange>> {
+    // Get device fresh just to query supported configs, then drop it immediately
+    let device = get_device(device_id)?;
+    let supported_configs: Vec<SupportedStreamConfigRange> =
+        device.supported_input_configs()?.collect();
+    // Drop device immediately after querying configs to avoid holding onto it
+    drop(device);
+    Ok(supported_configs)
+}
+
+pub fn generate_candidate_configs(device_id: &str) -> Result<Vec<(StreamConfig, SampleFormat)>> {
+    let supported_configs = supported_input_configs(device_id)?;
+
+    for config in &supported_configs {
+        debug!(
+            channels = config.channels(),
+            min_sample_rate = config.min_sample_rate(),
+            max_sample_rate = config.max_sample_rate(),
+            buffer_size = ?config.buffer_size(),
+            sample_format = ?config.sample_format(),
+            "supported input config"
+        );
+    }
+
+    let candidates = candidate_configs(&supported_configs);
+    debug!(
+        count = candidates.len(),
+        "generated candidate configurations"
+    );
+
+    Ok(candidates)
+}
+
+/// Stream configs worth trying, most preferred first: F32 then I16 at common rates the device
+/// claims to support, followed by mono fallbacks for devices that misreport their ranges.
+pub fn candidate_configs(
+    supported_configs: &[SupportedStreamConfigRange],
+) -> Vec<(StreamConfig, SampleFormat)> {
+    let mut candidates = Vec::new();
+
+    // Phase 1: Device-reported supported configs with reasonable sample rates
+    let preferred_rates = [48000, 44100, 32000, 16000, 8000];
+
+    // Try F32 format first (preferred since all downstream systems use it)
+    if let Some(f32_config) = supported_configs
+        .iter()
+        .find(|config| config.sample_format() == SampleFormat::F32)
+    {
+        let min_rate = f32_config.min_sample_rate();
+        let max_rate = f32_config.max_sample_rate();
+        let channels = f32_config.channels();
+
+        // Try each preferred rate that's within range and valid
+        for &rate in &preferred_rates {
+            // Skip invalid rates
+            if rate == u32::MAX || rate == 0 {
+                continue;
+            }
+
+            // Only include rates within device's reported range (if max_rate is valid)
+            if max_rate != u32::MAX && max_rate != 0 {
+                if rate < min_rate || rate > max_rate {
+                    continue;
+                }
+            }
+
+            // Validate rate is in reasonable range for resampling (4000-96000 Hz)
+            if rate < 4000 || rate > 96000 {
+                continue;
+            }
+
+            candidates.push((
+                StreamConfig {
+                    channels,
+                    sample_rate: rate,
+                    buffer_size: BufferSize::Default,
+                },
+                SampleFormat::F32,
+            ));
+        }
+    }
+
+    // Try I16 format as fallback
+    if let Some(i16_config) = supported_configs
+        .iter()
+        .find(|config| config.sample_format() == SampleFormat::I16)
+    {
+        let min_rate = i16_config.min_sample_rate();
+        let max_rate = i16_config.max_sample_rate();
+        let channels = i16_config.channels();
+
+        // Try each preferred rate that's within range and valid
+        for &rate in &preferred_rates {
+            // Skip invalid rates
+            if rate == u32::MAX || rate == 0 {
+                continue;
+            }
+
+            // Only include rates within device's reported range (if max_rate is valid)
+            if max_rate != u32::MAX && max_rate != 0 {
+                if rate < min_rate || rate > max_rate {
+                    continue;
+                }
+            }
+
+            // Validate rate is in reasonable range for resampling (4000-96000 Hz)
+            if rate < 4000 || rate > 96000 {
+                continue;
+            }
+
+            candidates.push((
+                StreamConfig {
+                    channels,
+                    sample_rate: rate,
+                    buffer_size: BufferSize::Default,
+                },
+                SampleFormat::I16,
+            ));
+        }
+    }
+
+    // Phase 2: Hardcoded fallbacks (if we have no candidates yet, or as additional fallbacks)
+    let hardcoded_fallbacks = vec![
+        (16000, SampleFormat::F32),
+        (16000, SampleFormat::I16),
+        (8000, SampleFormat::F32),
+        (8000, SampleFormat::I16),
+        (48000, SampleFormat::F32),
+    ];
+
+    for (rate, format) in hardcoded_fallbacks {
+        // Only add if not already in candidates
+        if !candidates
+            .iter()
+            .any(|(cfg, fmt)| cfg.sample_rate == rate && *fmt == format)
+        {
+            candidates.push((
+                StreamConfig {
+                    channels: 1, // Mono
+                    sample_rate: rate,
+                    buffer_size: BufferSize::Default,
+                },
+                format,
+            ));
+        }
+    }
+
+    candidates
+}
+
+pub fn try_create_stream(
+    device_id: &str,
+    candidates: Vec<(StreamConfig, SampleFormat)>,
+    pipeline_config: &SpeechPipelineConfig,
+) -> Result<InputStream> {
+    let mut errors = Vec::new();
+
+    for (config, sample_format) in candidates {
+        debug!(
+            ?sample_format,
+            sample_rate = config.sample_rate,
+            channels = config.channels,
+            "trying stream config"
+        );
+
+        // Get device fresh for each attempt since create_stream takes ownership
+        // Verify device is still available before attempting to use it
+        let device = match get_device(device_id) {
+            Ok(d) => {
+                // Verify device is still accessible by checking its name
+                if let Err(e) = d.id() {
+                    let error_msg = format!("  Error: Device no longer accessible - {}", e);
+                    warn!("{}", error_msg.trim_start());
+                    errors.push(error_msg);
+                    thread::sleep(Duration::from_millis(200));
+                    continue;
+                }
+                d
+            }
+            Err(e) => {
+                let error_msg = format!("  Error: Failed to get device - {}", e);
+                warn!("{}", error_msg.trim_start());
+                errors.push(error_msg);
+                thread::sleep(Duration::from_millis(200));
+                continue;
+            }
+        };
+
+        // Immediately attempt to create stream - don't query the device further
+        // as additional queries might invalidate it or put it in a bad state
+        // Attempt to create stream in a scope to ensure proper cleanup on failure
+        let result = {
+            // Create stream - if this fails, device will be dropped automatically
+            create_stream(device, config.clone(), sample_format, pipeline_config)
+        };
+
+        match result {
+            Ok((stream, events, health)) => {
+                info!(
+                    ?sample_format,
+                    sample_rate = config.sample_rate,
+                    channels = config.channels,
+                    "audio stream created"
+                );
+                return Ok(InputStream {
+                    stream,
+                    events,
+                    health,
+                });
+            }
+            Err(e) => {
+                let error_msg = format!(
+                    "  Error: {:?} @ {}Hz ({} channel{}) - {}",
+                    sample_format,
+                    config.sample_rate,
+                    config.channels,
+                    if config.channels == 1 { "" } else { "s" },
+                    e
+                );
+                warn!("{}", error_msg.trim_start());
+                errors.push(error_msg);
+
+                // Give the device/host time to recover before trying the next configuration
+                // ALSA devices may need time to release resources after a failed attempt
+                // The device and any partial streams are automatically dropped when we exit this scope
+                thread::sleep(Duration::from_millis(200));
+            }
+        }
+    }
+
+    // All configurations failed
+    Err(color_eyre::eyre::eyre!(
+        "Failed to create audio stream with any configuration. Attempted {} configurations:\n{}",
+        errors.len(),
+        errors.join("\n")
+    ))
+}
+
+/// Query the device, give it a moment to settle and start the first candidate config that works.
+fn open_input_stream(
+    device_id: &str,
+    pipeline_config: &SpeechPipelineConfig,
+) -> Result<InputStream> {
+    // Generate candidate configs (gets device internally and drops it immediately)
+    let candidates = generate_candidate_configs(device_id)?;
+
+    // Give the system time to fully release the device after querying configs
+    // This is especially important for ALSA devices
+    thread::sleep(Duration::from_millis(300));
+
+    // Try each candidate until one works
+    let input = try_create_stream(device_id, candidates, pipeline_config)?;
+    input.stream.play()?;
+    Ok(input)
+}
+
+/// Whether [`InputSupervisor::shutdown`] was called or the supervisor handle was dropped.
+fn stop_requested(stop_rx: &mpsc::Receiver<()>) -> bool {
+    !matches!(stop_rx.try_recv(), Err(mpsc::TryRecvError::Empty))
+}
+
+/// Forward speech events until the stream reports an error or stops delivering callbacks.
+/// Returns the reason the stream should be rebuilt, or `None` once the supervisor should stop.
+fn watch_input_stream(
+    input: &InputStream,
+    stall_timeout: Duration,
+    event_tx: &mpsc::Sender<InputEvent>,
+    stop_rx: &mpsc::Receiver<()>,
+) -> Option<String> {
+    let mut last_callbacks = input.health.callbacks();
+    let mut last_progress = Instant::now();
+
+    loop {
+        if stop_requested(stop_rx) {
+            return None;
+        }
+
+        match input.events.recv_timeout(HEALTH_CHECK_INTERVAL) {
+            Ok(event) => {
+                if event_tx.send(InputEvent::Speech(event)).is_err() {
+                    return None;
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return Some("audio callback was dropped".to_string());
+            }
+        }
+
+        if let Some(error) = input.health.error() {
+            return Some(error);
+        }
+
+        let callbacks = input.health.callbacks();
+        if callbacks != last_callbacks {
+            last_callbacks = callbacks;
+            last_progress = Instant::now();
+        } else if last_progress.elapsed() >= stall_timeout {
+            return Some(format!(
+                "no audio received for {:.1} seconds",
+                last_progress.elapsed().as_secs_f64()
+            ));
+        }
+    }
+}
+
+/// Keep trying to reopen the device, doubling the delay between attempts up to `max_backoff`.
+/// Returns `None` if the supervisor is stopped while waiting.
+fn reopen_input_stream(
+    device_id: &str,
+    pipeline_config: &SpeechPipelineConfig,
+    recovery: &RecoveryConfig,
+    stop_rx: &mpsc::Receiver<()>,
+) -> Option<InputStream> {
+    let mut backoff = recovery.initial_backoff;
+    let mut attempts = 0;
+    loop {
+        if !matches!(
+            stop_rx.recv_timeout(backoff),
+            Err(mpsc::RecvTimeoutError::Timeout)
+        ) {
+            return None;
+        }
+        attempts += 1;
+
+        match open_input_stream(device_id, pipeline_config) {
+            Ok(input) => {
+                info!(attempts, "audio input reopened");
+                return Some(input);
+            }
+            Err(e) => {
+                // Only the first failure is worth a warning, the rest repeat it
+                if attempts == 1 {
+                    warn!(error = %e, "failed to reopen audio input");
+                }
+                backoff = (backoff * 2).min(recovery.max_backoff);
+                debug!(
+                    retry_in_seconds = backoff.as_secs_f64(),
+                    "audio input still unavailable"
+                );
+            }
+        }
+    }
+}
+
+/// Handle to the thread that owns the input stream.
+pub struct InputSupervisor {
+    stop_tx: mpsc::Sender<()>,
+    thread_handle: thread::JoinHandle<()>,
+}
+
+impl InputSupervisor {
+    /// Stop and close the input stream, waiting for the supervisor thread to exit.
+    pub fn shutdown(self) {
+        let _ = self.stop_tx.send(());
+        let _ = self.thread_handle.join();
+    }
+}
+
+/// Open the input device on a dedicated thread and keep it alive: whenever the stream errors out
+/// or stalls it is torn down and reopened with backoff, and `Lost`/`Recovered` are reported on
+/// `event_tx` alongside the speech events. Fails only if the device cannot be opened at startup.
+pub fn spawn_input_supervisor(
+    device_id: String,
+    pipeline_config: SpeechPipelineConfig,
+    recovery: RecoveryConfig,
+    event_tx: mpsc::Sender<InputEvent>,
+) -> Result<InputSupervisor> {
+    let (ready_tx, ready_rx) = mpsc::channel::<Result<()>>();
+    let (stop_tx, stop_rx) = mpsc::channel::<()>();
+
+    // cpal streams are not Send, so the stream is created, watched and dropped on this thread
+    let thread_handle = thread::spawn(move || {
+        let mut input = match open_input_stream(&device_id, &pipeline_config) {
+            Ok(input) => {
+                let _ = ready_tx.send(Ok(()));
+                input
+            }
+            Err(e) => {
+                let _ = ready_tx.send(Err(e));
+                return;
+            }
+        };
+
+        loop {
+            let Some(reason) =
+                watch_input_stream(&input, recovery.stall_timeout, &event_tx, &stop_rx)
+            else {
+                return;
+            };
+            // Drop the dead stream before reopening so the device is released
+            drop(input);
+            if event_tx.send(InputEvent::Lost(reason)).is_err() {
+                return;
+            }
+
+            input = match reopen_input_stream(&device_id, &pipeline_config, &recovery, &stop_rx) {
+                Some(input) => input,
+                None => return,
+            };
+            if event_tx.send(InputEvent::Recovered).is_err() {
+                return;
+            }
+        }
+    });
+
+    ready_rx
+        .recv()
+        .map_err(|_| color_eyre::eyre::eyre!("audio input thread exited during startup"))??;
+
+    Ok(InputSupervisor {
+        stop_tx,
+        thread_handle,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpal::SupportedBufferSize;
+
+    #[test]
+    fn candidate_configs_prefer_supported_f32_rates_then_fallbacks() {
+        let supported = [
+            SupportedStreamConfigRange::new(
+                2,
+                16000,
+                48000,
+                SupportedBufferSize::Unknown,
+                SampleFormat::I16,
+            ),
+            SupportedStreamConfigRange::new(
+                2,
+                44100,
+                48000,
+                SupportedBufferSize::Unknown,
+                SampleFormat::F32,
+            ),
+        ];
+
+        let candidates = candidate_configs(&supported)
+            .into_iter()
+            .map(|(config, format)| (config.sample_rate, config.channels, format))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            candidates,
+            vec![
+                (48000, 2, SampleFormat::F32),
+                (44100, 2, SampleFormat::F32),
+                (48000, 2, SampleFormat::I16),
+                (44100, 2, SampleFormat::I16),
+                (32000, 2, SampleFormat::I16),
+                (16000, 2, SampleFormat::I16),
+                (16000, 1, SampleFormat::F32),
+                (8000, 1, SampleFormat::F32),
+                (8000, 1, SampleFormat::I16),
+            ]
+        );
+    }
+}
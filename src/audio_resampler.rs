@@ -1,52 +1,362 @@
+use color_eyre::eyre::Result;
+use rubato::{
+    Resampler, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction,
+    calculate_cutoff,
+};
+
+use crate::config::ResamplerQuality;
+
+/// How interleaved multichannel audio becomes mono.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Downmix {
+    /// Average all channels
+    Average,
+    /// Keep only this channel, counting from 0
+    Channel(usize),
+    /// Keep whichever channel has the most energy in each block
+    Loudest,
+}
+
+/// Turns interleaved frames into mono samples. Samples of a frame split across two calls are
+/// held back until the rest of the frame arrives.
+pub struct Downmixer {
+    downmix: Downmix,
+    channels: usize,
+    pending: Vec<f32>,
+}
+
+impl Downmixer {
+    pub fn new(channels: u16, downmix: Downmix) -> Result<Self> {
+        let channels = channels.max(1) as usize;
+        if let Downmix::Channel(channel) = downmix
+            && channel >= channels
+        {
+            return Err(color_eyre::eyre::eyre!(
+                "cannot listen to channel {} of a {} channel input (channels count from 0)",
+                channel,
+                channels
+            ));
+        }
+        Ok(Self {
+            downmix,
+            channels,
+            pending: Vec::new(),
+        })
+    }
+
+    pub fn downmix(&mut self, interleaved: &[f32]) -> Vec<f32> {
+        if self.channels == 1 {
+            return interleaved.to_vec();
+        }
+
+        let mut samples = std::mem::take(&mut self.pending);
+        samples.extend_from_slice(interleaved);
+        let whole = samples.len() - samples.len() % self.channels;
+        self.pending = samples.split_off(whole);
+
+        let frames = samples.chunks_exact(self.channels);
+        match self.downmix {
+            Downmix::Average => frames
+                .map(|frame| frame.iter().sum::<f32>() / self.channels as f32)
+                .collect(),
+            Downmix::Channel(channel) => frames.map(|frame| frame[channel]).collect(),
+            Downmix::Loudest => {
+                let mut energy = vec![0.0f32; self.channels];
+                for frame in frames.clone() {
+                    for (total, sample) in energy.iter_mut().zip(frame) {
+                        *total += sample * sample;
+                    }
+                }
+                let loudest = energy
+                    .iter()
+                    .enumerate()
+                    .max_by(|a, b| a.1.total_cmp(b.1))
+                    .map_or(0, |(channel, _)| channel);
+                frames.map(|frame| frame[loudest]).collect()
+            }
+        }
+    }
+}
+
+/// Input frames per resampler pass, 10 ms of audio, which bounds the added latency.
+const PASS_SECONDS: f64 = 0.01;
+
+fn sinc_parameters(quality: ResamplerQuality) -> SincInterpolationParameters {
+    let (sinc_len, interpolation) = match quality {
+        ResamplerQuality::Fast => (64, SincInterpolationType::Linear),
+        ResamplerQuality::Balanced => (128, SincInterpolationType::Linear),
+        ResamplerQuality::Best => (256, SincInterpolationType::Cubic),
+    };
+    let window = WindowFunction::BlackmanHarris2;
+    SincInterpolationParameters {
+        sinc_len,
+        f_cutoff: calculate_cutoff(sinc_len, window),
+        oversampling_factor: 256,
+        interpolation,
+        window,
+    }
+}
+
+/// Resamples mono audio through a band-limited sinc filter. Multichannel input goes through a
+/// [`Downmixer`] first.
+///
+/// Input that does not fill a resampler pass is kept for the next call, so the filter sees one
+/// continuous signal no matter how the audio callbacks split it.
 pub struct AudioResampler {
-    input_rate: u32,
-    output_rate: u32,
-    // TODO: handle multiple channels properly
-    #[allow(dead_code)]
-    channels: u16,
-    buffer: Vec<f32>,
-    target_chunk_size: usize,
+    /// `None` when the rates match and audio passes through untouched
+    resampler: Option<SincFixedIn<f32>>,
+    ratio: f64,
+    input: Vec<f32>,
+    output: Vec<f32>,
+    frames_in: usize,
+    frames_out: usize,
 }
 
 impl AudioResampler {
-    pub fn new(input_rate: u32, output_rate: u32, channels: u16, target_chunk_size: usize) -> Self {
-        Self {
-            input_rate,
-            output_rate,
-            channels,
-            buffer: Vec::new(),
-            target_chunk_size,
+    pub fn new(input_rate: u32, output_rate: u32, quality: ResamplerQuality) -> Result<Self> {
+        if input_rate == 0 || output_rate == 0 {
+            return Err(color_eyre::eyre::eyre!(
+                "cannot resample from {} Hz to {} Hz",
+                input_rate,
+                output_rate
+            ));
+        }
+        let ratio = output_rate as f64 / input_rate as f64;
+        let resampler = if input_rate == output_rate {
+            None
+        } else {
+            let pass_frames = (input_rate as f64 * PASS_SECONDS).ceil() as usize;
+            Some(
+                SincFixedIn::new(ratio, 1.0, sinc_parameters(quality), pass_frames, 1)
+                    .map_err(|e| color_eyre::eyre::eyre!("failed to create resampler: {}", e))?,
+            )
+        };
+        let output = vec![0.0; resampler.as_ref().map_or(0, |r| r.output_frames_max())];
+        Ok(Self {
+            resampler,
+            ratio,
+            input: Vec::new(),
+            output,
+            frames_in: 0,
+            frames_out: 0,
+        })
+    }
+
+    /// Resample the next piece of the stream, returning as much output as the filter can produce
+    /// so far.
+    pub fn resample(&mut self, input: &[f32]) -> Vec<f32> {
+        self.frames_in += input.len();
+        let mut resampled = Vec::new();
+        match &mut self.resampler {
+            None => {
+                self.frames_out += input.len();
+                resampled.extend_from_slice(input);
+            }
+            Some(resampler) => {
+                self.input.extend_from_slice(input);
+                let mut consumed = 0;
+                while self.input.len() - consumed >= resampler.input_frames_next() {
+                    let (used, produced) = resampler
+                        .process_into_buffer(
+                            &[&self.input[consumed..]],
+                            &mut [&mut self.output],
+                            None,
+                        )
+                        .expect("buffers are sized by the resampler");
+                    consumed += used;
+                    self.frames_out += produced;
+                    resampled.extend_from_slice(&self.output[..produced]);
+                }
+                self.input.drain(..consumed);
+            }
+        }
+        resampled
+    }
+
+    /// Push the held back input through the filter and return the rest of the output. The total
+    /// output then matches the input's duration.
+    pub fn finish(&mut self) -> Vec<f32> {
+        let mut resampled = Vec::new();
+        if let Some(resampler) = &mut self.resampler {
+            let expected = (self.frames_in as f64 * self.ratio).round() as usize;
+            while self.frames_out < expected {
+                // Rubato wants `None` rather than an empty slice once only the filter's tail is left
+                let pending: &[&[f32]] = &[&self.input];
+                let (_, produced) = resampler
+                    .process_partial_into_buffer(
+                        (!self.input.is_empty()).then_some(pending),
+                        &mut [&mut self.output],
+                        None,
+                    )
+                    .expect("buffers are sized by the resampler");
+                self.input.clear();
+                let kept = produced.min(expected - self.frames_out);
+                self.frames_out += kept;
+                resampled.extend_from_slice(&self.output[..kept]);
+            }
         }
+        resampled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One second of stereo at 48 kHz: a quiet 100 Hz tone on the left, a loud 300 Hz tone on
+    /// the right.
+    fn stereo_tones() -> Vec<f32> {
+        (0..48000)
+            .flat_map(|i| {
+                let t = i as f32 / 48000.0;
+                [
+                    0.1 * (2.0 * std::f32::consts::PI * 100.0 * t).sin(),
+                    0.8 * (2.0 * std::f32::consts::PI * 300.0 * t).sin(),
+                ]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn downmix_strategies_on_stereo() {
+        let input = stereo_tones();
+        let left = input.iter().step_by(2).copied().collect::<Vec<_>>();
+        let right = input.iter().skip(1).step_by(2).copied().collect::<Vec<_>>();
+
+        let average = Downmixer::new(2, Downmix::Average).unwrap().downmix(&input);
+        assert_eq!(average.len(), 48000);
+        for i in [0, 123, 4567, 47999] {
+            assert!((average[i] - (left[i] + right[i]) / 2.0).abs() < 1e-6);
+        }
+
+        let channel = Downmixer::new(2, Downmix::Channel(0))
+            .unwrap()
+            .downmix(&input);
+        assert_eq!(channel, left);
+
+        let loudest = Downmixer::new(2, Downmix::Loudest).unwrap().downmix(&input);
+        assert_eq!(loudest, right);
+
+        assert!(Downmixer::new(2, Downmix::Channel(2)).is_err());
+        assert!(Downmixer::new(1, Downmix::Channel(0)).is_ok());
     }
 
-    // Simple linear interpolation resampling
-    pub fn resample(&mut self, input: &[f32]) -> Vec<Vec<f32>> {
-        let ratio = self.output_rate as f64 / self.input_rate as f64;
-        let output_len = (input.len() as f64 * ratio) as usize;
+    #[test]
+    fn loudest_channel_is_chosen_per_block() {
+        let mut downmixer = Downmixer::new(2, Downmix::Loudest).unwrap();
+        assert_eq!(downmixer.downmix(&[0.9, 0.1, -0.9, -0.1]), vec![0.9, -0.9]);
+        assert_eq!(downmixer.downmix(&[0.1, 0.9, -0.1, -0.9]), vec![0.9, -0.9]);
+    }
+
+    #[test]
+    fn downmix_keeps_frames_split_across_calls() {
+        let mut downmixer = Downmixer::new(2, Downmix::Channel(1)).unwrap();
+        assert_eq!(downmixer.downmix(&[1.0, 2.0, 3.0]), vec![2.0]);
+        assert_eq!(downmixer.downmix(&[4.0, 5.0, 6.0]), vec![4.0, 6.0]);
+    }
 
-        let mut resampled = Vec::with_capacity(output_len);
+    #[test]
+    fn stereo_resamples_at_the_frame_rate() {
+        let mut downmixer = Downmixer::new(2, Downmix::Average).unwrap();
+        let mut resampler = AudioResampler::new(48000, 16000, ResamplerQuality::Balanced).unwrap();
+        let samples = stereo_tones()
+            .chunks(960)
+            .flat_map(|block| resampler.resample(&downmixer.downmix(block)))
+            .count()
+            + resampler.finish().len();
+        // One second of input is one second of output, not two
+        assert_eq!(samples, 16000);
+    }
 
-        for i in 0..output_len {
-            let src_index = i as f64 / ratio;
-            let src_index_floor = src_index.floor() as usize;
-            let src_index_ceil = (src_index_floor + 1).min(input.len() - 1);
-            let frac = src_index - src_index_floor as f64;
+    fn tone(frequency: f32, rate: u32, seconds: f32) -> Vec<f32> {
+        (0..(rate as f32 * seconds) as usize)
+            .map(|i| 0.5 * (2.0 * std::f32::consts::PI * frequency * i as f32 / rate as f32).sin())
+            .collect()
+    }
+
+    /// Amplitude of the `frequency` component of `samples`, from a single DFT bin.
+    fn amplitude_at(samples: &[f32], frequency: f32, rate: u32) -> f32 {
+        let (re, im) = samples
+            .iter()
+            .enumerate()
+            .fold((0.0, 0.0), |(re, im), (i, &x)| {
+                let phase = 2.0 * std::f64::consts::PI * frequency as f64 * i as f64 / rate as f64;
+                (re + x as f64 * phase.cos(), im - x as f64 * phase.sin())
+            });
+        (2.0 * (re * re + im * im).sqrt() / samples.len() as f64) as f32
+    }
 
-            let sample =
-                input[src_index_floor] * (1.0 - frac) as f32 + input[src_index_ceil] * frac as f32;
-            resampled.push(sample);
+    fn resample_in_blocks(
+        input: &[f32],
+        input_rate: u32,
+        block: usize,
+        quality: ResamplerQuality,
+    ) -> Vec<f32> {
+        let mut resampler = AudioResampler::new(input_rate, 16000, quality).unwrap();
+        let mut output = input
+            .chunks(block)
+            .flat_map(|block| resampler.resample(block))
+            .collect::<Vec<_>>();
+        output.extend(resampler.finish());
+        output
+    }
+
+    #[test]
+    fn tones_above_the_output_nyquist_are_filtered_out() {
+        for quality in [
+            ResamplerQuality::Fast,
+            ResamplerQuality::Balanced,
+            ResamplerQuality::Best,
+        ] {
+            // A 10 kHz tone would fold down to 6 kHz at 16 kHz without the filter
+            let output = resample_in_blocks(&tone(10000.0, 48000, 1.0), 48000, 441, quality);
+            let steady = &output[1000..15000];
+            let alias = amplitude_at(steady, 6000.0, 16000);
+            let level = 20.0 * (alias / 0.5).log10();
+            assert!(level < -60.0, "{:?}: alias at {:.1} dB", quality, level);
+            let peak = steady.iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+            assert!(peak < 0.01, "{:?}: peak {}", quality, peak);
+
+            // Speech frequencies pass untouched
+            let output = resample_in_blocks(&tone(1000.0, 48000, 1.0), 48000, 441, quality);
+            let gain = amplitude_at(&output[1000..15000], 1000.0, 16000) / 0.5;
+            assert!((gain - 1.0).abs() < 0.01, "{:?}: gain {}", quality, gain);
         }
+    }
 
-        // Add to buffer
-        self.buffer.extend_from_slice(&resampled);
+    #[test]
+    fn output_does_not_depend_on_callback_sizes() {
+        let input = tone(440.0, 44100, 0.5);
+        let whole = resample_in_blocks(&input, 44100, input.len(), ResamplerQuality::Balanced);
+        for block in [1, 100, 441, 1023] {
+            let split = resample_in_blocks(&input, 44100, block, ResamplerQuality::Balanced);
+            assert_eq!(split.len(), whole.len(), "blocks of {}", block);
+            assert!(
+                split.iter().zip(&whole).all(|(a, b)| (a - b).abs() < 1e-6),
+                "blocks of {}",
+                block
+            );
+        }
+    }
 
-        // Extract chunks of target size
-        let mut chunks = Vec::new();
-        while self.buffer.len() >= self.target_chunk_size {
-            let chunk: Vec<f32> = self.buffer.drain(..self.target_chunk_size).collect();
-            chunks.push(chunk);
+    #[test]
+    fn finish_returns_the_whole_signal_in_time() {
+        let output =
+            resample_in_blocks(&tone(200.0, 44100, 1.0), 44100, 512, ResamplerQuality::Best);
+        assert_eq!(output.len(), 16000);
+        // No delay is added: the tone lines up with one generated at 16 kHz to within a sample
+        let expected = tone(200.0, 16000, 1.0);
+        for i in (500..15500).step_by(250) {
+            assert!(
+                (output[i] - expected[i]).abs() < 0.04,
+                "sample {}: {} vs {}",
+                i,
+                output[i],
+                expected[i]
+            );
         }
 
-        chunks
+        let passthrough = resample_in_blocks(&[0.25; 1000], 16000, 300, ResamplerQuality::Fast);
+        assert_eq!(passthrough, vec![0.25; 1000]);
     }
 }
@@ -4,6 +4,8 @@ use chrono::Timelike;
 use color_eyre::eyre::Result;
 use pest::Parser;
 use pest::iterators::Pairs;
+use serde::Serialize;
+use tracing::{debug, info, warn};
 
 use crate::command_executor::config::CommandExecutorConfig;
 use crate::command_executor::grammar::CommandParser;
@@ -14,9 +16,12 @@ use crate::command_executor::services::home_assistant::{
 use crate::command_executor::services::timer::TimerManager;
 use crate::command_executor::services::weather;
 use crate::human_format::{int_to_words, words_to_int};
+use crate::metrics::METRICS;
 
-#[derive(Debug, PartialEq)]
-enum Intent {
+/// What a transcribed command asks for, as recognised by the grammar.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Intent {
     TurnOnLight {
         area: Option<String>,
     },
@@ -40,6 +45,24 @@ enum Intent {
     Unknown,
 }
 
+impl Intent {
+    /// Label used for this intent in metrics.
+    fn name(&self) -> &'static str {
+        match self {
+            Intent::TurnOnLight { .. } => "turn_on_light",
+            Intent::TurnOffLight { .. } => "turn_off_light",
+            Intent::GetCurrentTime => "get_current_time",
+            Intent::GetWeather => "get_weather",
+            Intent::SetTimer { .. } => "set_timer",
+            Intent::GetTimers => "get_timers",
+            Intent::CancelTimer { .. } => "cancel_timer",
+            Intent::CancelTimerByDuration { .. } => "cancel_timer_by_duration",
+            Intent::CancelAllTimers => "cancel_all_timers",
+            Intent::Unknown => "unknown",
+        }
+    }
+}
+
 /// Parse a timer_duration rule into total seconds.
 /// timer_duration contains one or more duration_segment children,
 /// each with a number and a time_unit.
@@ -89,12 +112,12 @@ fn parse_timer_duration(pairs: Pairs<Rule>) -> Option<u64> {
     }
 }
 
-fn parse_intent(command: &str) -> Intent {
+pub fn parse_intent(command: &str) -> Intent {
     let command_lower = command.to_lowercase();
     let pairs = match CommandParser::parse(Rule::command, &command_lower) {
         Ok(mut pairs) => pairs.next().unwrap(),
         Err(e) => {
-            eprintln!("Parse error: {:?}", e);
+            debug!(error = ?e, "command did not match the grammar");
             return Intent::Unknown;
         }
     };
@@ -226,14 +249,25 @@ pub fn execute_command(
     timer_manager: &TimerManager,
     command: &str,
 ) -> Result<String> {
-    let intent = parse_intent(command);
+    execute_intent(config, timer_manager, parse_intent(command), command)
+}
+
+/// Run an intent already parsed from `command` and return the text to speak.
+pub fn execute_intent(
+    config: &CommandExecutorConfig,
+    timer_manager: &TimerManager,
+    intent: Intent,
+    command: &str,
+) -> Result<String> {
+    info!(?intent, "parsed intent");
+    let _timer = METRICS
+        .intent_execution_seconds
+        .with_label_values(&[intent.name()])
+        .start_timer();
 
     match intent {
         Intent::TurnOnLight { area } => {
             let area_clone = area.clone();
-
-            println!("Turning on lights in area: {:?}", area_clone);
-
             turn_on_light(config, area)?;
             let area_msg = area_clone
                 .map(|a| format!(" in {}", a.replace('_', " ")))
@@ -264,7 +298,8 @@ pub fn execute_command(
         }
         Intent::CancelAllTimers => Ok(timer_manager.cancel_all_timers()),
         Intent::Unknown => {
-            println!("Unknown command: '{}'", command);
+            warn!(command, "unknown command");
+            METRICS.unknown_intents.inc();
             Ok("Unknown command".to_string())
         }
     }
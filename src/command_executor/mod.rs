@@ -4,5 +4,5 @@ mod grammar;
 mod services;
 
 pub use config::CommandExecutorConfig;
-pub use executor::execute_command;
+pub use executor::{Intent, execute_command, execute_intent, parse_intent};
 pub use services::timer::{TimerEvent, TimerManager};
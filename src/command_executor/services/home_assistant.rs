@@ -2,8 +2,10 @@ use std::time::Duration;
 
 use color_eyre::eyre::{Context, Result};
 use serde_json::json;
+use tracing::instrument;
 
 use crate::command_executor::{CommandExecutorConfig, grammar::Rule};
+use crate::metrics::METRICS;
 
 pub fn extract_area(pairs: &mut pest::iterators::Pairs<'_, Rule>) -> Option<String> {
     let pair = pairs.next()?;
@@ -48,6 +50,7 @@ pub fn normalize_area_name(area: &str) -> String {
     }
 }
 
+#[instrument(name = "home_assistant", skip(config))]
 pub fn turn_on_light(config: &CommandExecutorConfig, area: Option<String>) -> Result<()> {
     let client = reqwest::blocking::Client::new();
     let url = config
@@ -65,7 +68,7 @@ pub fn turn_on_light(config: &CommandExecutorConfig, area: Option<String>) -> Re
     };
 
     // TODO: use async version
-    let _ = client
+    let result = client
         .post(url)
         .header(
             "Authorization",
@@ -74,12 +77,18 @@ pub fn turn_on_light(config: &CommandExecutorConfig, area: Option<String>) -> Re
         .header("Content-Type", "application/json")
         .body(serde_json::to_string(&body).wrap_err("failed to serialize request body")?)
         .timeout(Duration::from_secs(5))
-        .send()?
-        .error_for_status();
+        .send()
+        .and_then(|response| response.error_for_status());
+
+    if let Err(e) = result {
+        METRICS.home_assistant_errors.inc();
+        return Err(e).wrap_err("Home Assistant request failed");
+    }
 
     Ok(())
 }
 
+#[instrument(name = "home_assistant", skip(config))]
 pub fn turn_off_light(config: &CommandExecutorConfig, area: Option<String>) -> Result<()> {
     let client = reqwest::blocking::Client::new();
     let url = config
@@ -97,7 +106,7 @@ pub fn turn_off_light(config: &CommandExecutorConfig, area: Option<String>) -> R
     };
 
     // TODO: use async version
-    let _ = client
+    let result = client
         .post(url)
         .header(
             "Authorization",
@@ -106,8 +115,13 @@ pub fn turn_off_light(config: &CommandExecutorConfig, area: Option<String>) -> R
         .header("Content-Type", "application/json")
         .body(serde_json::to_string(&body).wrap_err("failed to serialize request body")?)
         .timeout(Duration::from_secs(5))
-        .send()?
-        .error_for_status();
+        .send()
+        .and_then(|response| response.error_for_status());
+
+    if let Err(e) = result {
+        METRICS.home_assistant_errors.inc();
+        return Err(e).wrap_err("Home Assistant request failed");
+    }
 
     Ok(())
 }
@@ -1,8 +1,14 @@
 use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use color_eyre::eyre::{Context, Result};
+use serde::{Deserialize, Serialize};
 
 use crate::human_format::int_to_words;
 
@@ -10,6 +16,33 @@ pub struct TimerEvent {
     pub name: Option<String>,
 }
 
+impl TimerEvent {
+    /// Text spoken when the timer goes off
+    pub fn announcement(&self) -> String {
+        match &self.name {
+            Some(name) => format!("Timer {} is done.", name),
+            None => "Your timer is done.".to_string(),
+        }
+    }
+}
+
+/// A timer as stored on disk between runs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PersistedTimer {
+    pub name: Option<String>,
+    pub duration_secs: u64,
+    pub ends_at_unix_secs: u64,
+}
+
+/// An active timer as reported by the control API.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TimerStatus {
+    pub id: u64,
+    pub name: Option<String>,
+    pub duration_secs: u64,
+    pub remaining_secs: u64,
+}
+
 struct TimerInfo {
     pub id: u64,
     pub name: Option<String>,
@@ -34,6 +67,18 @@ impl TimerManager {
     }
 
     pub fn set_timer(&self, duration_secs: u64, name: Option<String>) -> String {
+        self.start_timer(duration_secs, name.clone(), Duration::from_secs(duration_secs));
+
+        let duration_str = format_duration_human(duration_secs);
+        match name {
+            Some(n) => format!("Timer {} set for {}", n, duration_str),
+            None => format!("Timer set for {}", duration_str),
+        }
+    }
+
+    /// Start a timer of `duration_secs` that fires after `remaining`, which is shorter than the
+    /// full duration for timers restored from a previous run.
+    fn start_timer(&self, duration_secs: u64, name: Option<String>, remaining: Duration) {
         let id = {
             let mut next = self.next_id.lock().unwrap();
             let id = *next;
@@ -43,11 +88,14 @@ impl TimerManager {
 
         let cancelled = Arc::new(AtomicBool::new(false));
 
+        let already_elapsed = Duration::from_secs(duration_secs).saturating_sub(remaining);
         let info = TimerInfo {
             id,
             name: name.clone(),
             original_duration_secs: duration_secs,
-            started_at: Instant::now(),
+            started_at: Instant::now()
+                .checked_sub(already_elapsed)
+                .unwrap_or_else(Instant::now),
             cancelled: cancelled.clone(),
         };
 
@@ -55,13 +103,12 @@ impl TimerManager {
 
         let timers = self.timers.clone();
         let sender = self.event_sender.clone();
-        let timer_name = name.clone();
+        let timer_name = name;
 
         thread::spawn(move || {
             // Sleep in small increments so we can check for cancellation
-            let total = Duration::from_secs(duration_secs);
             let start = Instant::now();
-            while start.elapsed() < total {
+            while start.elapsed() < remaining {
                 if cancelled.load(Ordering::Relaxed) {
                     return;
                 }
@@ -80,12 +127,69 @@ impl TimerManager {
                 name: timer_name,
             });
         });
+    }
 
-        let duration_str = format_duration_human(duration_secs);
-        match name {
-            Some(n) => format!("Timer {} set for {}", n, duration_str),
-            None => format!("Timer set for {}", duration_str),
+    /// Active timers with their wall clock end time, for writing to disk on shutdown.
+    pub fn persisted_timers(&self) -> Vec<PersistedTimer> {
+        let now = SystemTime::now();
+        self.timers
+            .lock()
+            .unwrap()
+            .values()
+            .map(|timer| {
+                let remaining = Duration::from_secs(timer.original_duration_secs)
+                    .saturating_sub(timer.started_at.elapsed());
+                PersistedTimer {
+                    name: timer.name.clone(),
+                    duration_secs: timer.original_duration_secs,
+                    ends_at_unix_secs: (now + remaining)
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                }
+            })
+            .collect()
+    }
+
+    /// Restart a timer saved by [`Self::persisted_timers`]. Timers that ended while the assistant
+    /// was not running fire immediately.
+    pub fn restore_timer(&self, timer: PersistedTimer) {
+        let ends_at = UNIX_EPOCH + Duration::from_secs(timer.ends_at_unix_secs);
+        let remaining = ends_at
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        self.start_timer(timer.duration_secs, timer.name, remaining);
+    }
+
+    /// Write all active timers to `path` as JSON.
+    pub fn save(&self, path: &Path) -> Result<usize> {
+        let timers = self.persisted_timers();
+        let json = serde_json::to_string_pretty(&timers)?;
+        fs::write(path, json)
+            .wrap_err_with(|| format!("failed to write timers to {}", path.display()))?;
+        Ok(timers.len())
+    }
+
+    /// Restore the timers saved to `path` and remove the file, so a crash before the next save
+    /// cannot fire them a second time. A missing file means there is nothing to restore.
+    pub fn restore(&self, path: &Path) -> Result<usize> {
+        let json = match fs::read_to_string(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => {
+                return Err(e)
+                    .wrap_err_with(|| format!("failed to read timers from {}", path.display()));
+            }
+        };
+        let timers: Vec<PersistedTimer> = serde_json::from_str(&json)
+            .wrap_err_with(|| format!("invalid timers file {}", path.display()))?;
+        let count = timers.len();
+        for timer in timers {
+            self.restore_timer(timer);
         }
+        fs::remove_file(path)
+            .wrap_err_with(|| format!("failed to remove timers file {}", path.display()))?;
+        Ok(count)
     }
 
     pub fn get_timers(&self) -> String {
@@ -110,6 +214,36 @@ impl TimerManager {
         lines.join(". ")
     }
 
+    /// Active timers, oldest first.
+    pub fn list_timers(&self) -> Vec<TimerStatus> {
+        let mut timers = self
+            .timers
+            .lock()
+            .unwrap()
+            .values()
+            .map(|timer| TimerStatus {
+                id: timer.id,
+                name: timer.name.clone(),
+                duration_secs: timer.original_duration_secs,
+                remaining_secs: timer
+                    .original_duration_secs
+                    .saturating_sub(timer.started_at.elapsed().as_secs()),
+            })
+            .collect::<Vec<_>>();
+        timers.sort_by_key(|timer| timer.id);
+        timers
+    }
+
+    /// Cancel the timer with `id` as listed by [`Self::list_timers`]; `None` if there is none.
+    pub fn cancel_timer(&self, id: u64) -> Option<String> {
+        let timer = self.timers.lock().unwrap().remove(&id)?;
+        timer.cancelled.store(true, Ordering::Relaxed);
+        let label = timer
+            .name
+            .unwrap_or_else(|| format_duration_human(timer.original_duration_secs));
+        Some(format!("Cancelled timer {}", label))
+    }
+
     pub fn cancel_timer_by_name(&self, name: &str) -> String {
         let mut timers = self.timers.lock().unwrap();
         let name_lower = name.to_lowercase();
@@ -237,3 +371,69 @@ fn format_duration_human(total_secs: u64) -> String {
 
     parts.join(" and ")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn persisted_timers_round_trip_through_restore() {
+        let (tx, _rx) = mpsc::channel();
+        let manager = TimerManager::new(tx);
+        manager.set_timer(600, Some("pasta".to_string()));
+
+        let persisted = manager.persisted_timers();
+        assert_eq!(persisted.len(), 1);
+        assert_eq!(persisted[0].name.as_deref(), Some("pasta"));
+        assert_eq!(persisted[0].duration_secs, 600);
+
+        let (tx, _rx) = mpsc::channel();
+        let restored = TimerManager::new(tx);
+        restored.restore_timer(persisted[0].clone());
+        let restored_timers = restored.persisted_timers();
+        assert_eq!(restored_timers[0].name, persisted[0].name);
+        assert_eq!(restored_timers[0].duration_secs, 600);
+        // End times are whole seconds, so the restored one may round down by one
+        assert!(persisted[0].ends_at_unix_secs - restored_timers[0].ends_at_unix_secs <= 1);
+        assert_eq!(restored.cancel_timer_by_duration(600), "Cancelled timer pasta");
+        manager.cancel_all_timers();
+    }
+
+    #[test]
+    fn timers_are_listed_and_cancelled_by_id() {
+        let (tx, _rx) = mpsc::channel();
+        let manager = TimerManager::new(tx);
+        manager.set_timer(300, Some("eggs".to_string()));
+        manager.set_timer(60, None);
+
+        let timers = manager.list_timers();
+        assert_eq!(timers.len(), 2);
+        assert_eq!(timers[0].name.as_deref(), Some("eggs"));
+        assert_eq!(timers[0].duration_secs, 300);
+        assert!(timers[0].remaining_secs >= 299);
+        assert_eq!(timers[1].name, None);
+
+        assert_eq!(
+            manager.cancel_timer(timers[1].id).as_deref(),
+            Some("Cancelled timer one minute")
+        );
+        assert_eq!(manager.cancel_timer(timers[1].id), None);
+        assert_eq!(manager.list_timers().len(), 1);
+        manager.cancel_all_timers();
+    }
+
+    #[test]
+    fn timers_that_ended_while_stopped_fire_on_restore() {
+        let (tx, rx) = mpsc::channel();
+        let manager = TimerManager::new(tx);
+        manager.restore_timer(PersistedTimer {
+            name: Some("tea".to_string()),
+            duration_secs: 180,
+            ends_at_unix_secs: 1,
+        });
+
+        let event = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(event.name.as_deref(), Some("tea"));
+        assert_eq!(manager.get_timers(), "No timers are set");
+    }
+}
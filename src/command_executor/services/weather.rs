@@ -2,6 +2,7 @@ use std::time::Duration;
 
 use color_eyre::eyre::Result;
 use serde::{Deserialize, Serialize};
+use tracing::instrument;
 
 use url::Url;
 
@@ -144,6 +145,7 @@ fn weather_code_to_string(weather_code: i32) -> String {
     }
 }
 
+#[instrument(name = "weather")]
 pub fn get_weather(latitude: f64, longitude: f64) -> Result<String> {
     let url_params = [
         ("latitude", latitude.to_string()),
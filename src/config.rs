@@ -0,0 +1,1263 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use clap::{Args, ValueEnum};
+use color_eyre::eyre::{Context, Result};
+use serde::Deserialize;
+use url::Url;
+
+/// Settings for the voice assistant, loaded from a TOML file (see `assistant.example.toml`).
+/// Every section is optional; missing values fall back to the defaults below.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AssistantConfig {
+    pub audio: AudioConfig,
+    pub wake_word: WakeWordConfig,
+    pub stt: SttConfig,
+    pub tts: TtsConfig,
+    pub home_assistant: HomeAssistantConfig,
+    pub weather: WeatherConfig,
+    pub timers: TimersConfig,
+    pub metrics: MetricsConfig,
+    pub history: HistoryConfig,
+    pub recordings: RecordingsConfig,
+    pub follow_up: FollowUpConfig,
+    pub earcons: EarconsConfig,
+    pub wyoming: WyomingConfig,
+    pub api: ApiConfig,
+    pub satellite: SatelliteConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    /// cpal device id of the microphone, as printed by `get-input-devices`
+    pub input_device_id: Option<String>,
+    /// Seconds of consecutive non-speech that end an utterance
+    pub silence_seconds: f64,
+    /// Seconds after the wake word within which speech has to start, or listening is cancelled
+    pub no_speech_timeout_seconds: f64,
+    /// Longest utterance in seconds; longer ones are cut off and transcribed as they are
+    pub max_utterance_seconds: f64,
+    /// Seconds of audio kept from before the wake word was detected
+    pub rolling_buffer_duration_seconds: f64,
+    /// Silero VAD probability above which a chunk counts as speech
+    pub vad_threshold: f32,
+    /// Seconds without any audio callback before the stream is treated as dead and reopened
+    pub stall_timeout_seconds: f64,
+    /// Delay before the first attempt to reopen a lost input device; doubles on every failure
+    pub reconnect_initial_backoff_seconds: f64,
+    /// Upper bound for the delay between attempts to reopen a lost input device
+    pub reconnect_max_backoff_seconds: f64,
+    /// How a multichannel microphone is turned into the mono audio the models listen to
+    pub channel_mix: ChannelMix,
+    /// Channel to listen to with `channel_mix = "channel"`, counting from 0
+    pub channel: usize,
+    /// Trade between CPU use and filter quality when resampling the mic to 16 kHz
+    pub resampler_quality: ResamplerQuality,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        Self {
+            input_device_id: None,
+            silence_seconds: 1.0,
+            no_speech_timeout_seconds: 5.0,
+            max_utterance_seconds: 15.0,
+            rolling_buffer_duration_seconds: 2.0,
+            vad_threshold: 0.75,
+            stall_timeout_seconds: 5.0,
+            reconnect_initial_backoff_seconds: 1.0,
+            reconnect_max_backoff_seconds: 30.0,
+            channel_mix: ChannelMix::Average,
+            channel: 0,
+            resampler_quality: ResamplerQuality::Balanced,
+        }
+    }
+}
+
+/// How the channels of a multichannel microphone are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelMix {
+    /// Average all channels
+    Average,
+    /// Use only `audio.channel`
+    Channel,
+    /// Use whichever channel is loudest in each block of audio
+    Loudest,
+}
+
+/// Length of the band-limiting filter used to resample the mic; every level keeps aliasing well
+/// below what the VAD and whisper can hear
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ResamplerQuality {
+    /// Short filter for slow CPUs, with a wider transition band below 8 kHz
+    Fast,
+    Balanced,
+    /// Long filter that keeps nearly everything up to 8 kHz
+    Best,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WakeWordConfig {
+    /// openWakeWord ONNX model to use instead of the bundled "alexa" model
+    pub model_path: Option<PathBuf>,
+    /// openWakeWord detection threshold
+    pub threshold: f32,
+    /// Spoken wake word, stripped from the start of transcripts. Should match the model.
+    pub activation_text: String,
+    /// How the wake word is treated while the assistant is talking
+    pub while_speaking: WhileSpeakingPolicy,
+    /// Probability the wake word needs while the assistant is talking, with `raise_threshold`
+    pub speaking_threshold: f32,
+    /// Seconds after the assistant stops talking during which `while_speaking` still applies,
+    /// to cover echo and the output device's buffer
+    pub speaking_hangover_seconds: f64,
+}
+
+impl Default for WakeWordConfig {
+    fn default() -> Self {
+        Self {
+            model_path: None,
+            threshold: 0.2,
+            activation_text: "alexa".to_string(),
+            while_speaking: WhileSpeakingPolicy::RaiseThreshold,
+            speaking_threshold: 0.6,
+            speaking_hangover_seconds: 0.5,
+        }
+    }
+}
+
+/// What the wake word detector does while the assistant's own voice may be reaching the mic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum WhileSpeakingPolicy {
+    /// Detect the wake word as usual
+    Normal,
+    /// Only accept detections at or above `speaking_threshold`
+    RaiseThreshold,
+    /// Never detect the wake word, which also turns off barge-in
+    Ignore,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SttConfig {
+    /// Path to the ggml whisper model
+    pub model_path: PathBuf,
+    /// Spoken language code (e.g. "en", "de"), or "auto" to let whisper detect it
+    pub language: String,
+    pub strategy: SttStrategy,
+    /// Beam width when `strategy` is beam search
+    pub beam_size: i32,
+    /// Candidates sampled when `strategy` is greedy
+    pub best_of: i32,
+    /// Threads used for decoding; whisper picks `min(4, cores)` when unset
+    pub threads: Option<i32>,
+    /// Temperature step for retrying failed decodes; 0.0 disables the fallback
+    pub temperature_inc: f32,
+    /// Probability above which a segment is treated as silence
+    pub no_speech_threshold: f32,
+}
+
+impl Default for SttConfig {
+    fn default() -> Self {
+        Self {
+            model_path: PathBuf::from("./whisper_model/ggml-tiny.bin"),
+            language: "en".to_string(),
+            strategy: SttStrategy::BeamSearch,
+            beam_size: 5,
+            best_of: 5,
+            threads: None,
+            temperature_inc: 0.2,
+            no_speech_threshold: 0.6,
+        }
+    }
+}
+
+/// Whisper decoding strategy: greedy is faster, beam search is more accurate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum SttStrategy {
+    Greedy,
+    BeamSearch,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TtsConfig {
+    /// Path to the pocket-tts safetensors model
+    pub model_path: PathBuf,
+    /// Path to the WAV file used to clone the voice
+    pub voice_path: PathBuf,
+    /// Stop speaking as soon as the wake word is heard, so a new command can cut off a long answer
+    pub barge_in: bool,
+}
+
+impl Default for TtsConfig {
+    fn default() -> Self {
+        Self {
+            model_path: PathBuf::from("model/tts_b6369a24.safetensors"),
+            voice_path: PathBuf::from("model/p303_023.wav"),
+            barge_in: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HomeAssistantConfig {
+    pub base_url: Option<Url>,
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WeatherConfig {
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimersConfig {
+    /// Playback volume used when announcing a finished timer (0.0 to 1.0)
+    pub alarm_volume: f32,
+    /// Where active timers are saved on shutdown and restored from on startup
+    pub state_path: PathBuf,
+}
+
+impl Default for TimersConfig {
+    fn default() -> Self {
+        Self {
+            alarm_volume: 0.5,
+            state_path: PathBuf::from("timers.json"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// Append a JSON record for every interaction to `path`. Off by default, since the records
+    /// hold everything that was said to the assistant
+    pub enabled: bool,
+    pub path: PathBuf,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: PathBuf::from("history.jsonl"),
+        }
+    }
+}
+
+/// Short sounds played as feedback between the wake word and the spoken answer. Any format rodio
+/// can decode works; relative paths are resolved against the cwd.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EarconsConfig {
+    pub enabled: bool,
+    /// Played when the wake word is heard
+    pub wake: PathBuf,
+    /// Played when the end of the command is detected, or a follow-up window passes
+    pub end_of_listening: PathBuf,
+    /// Played when the command did not match any intent
+    pub not_understood: PathBuf,
+    /// Played when executing the command failed
+    pub error: PathBuf,
+}
+
+impl Default for EarconsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            wake: PathBuf::from("sounds/wake.wav"),
+            end_of_listening: PathBuf::from("sounds/end_of_listening.wav"),
+            not_understood: PathBuf::from("sounds/not_understood.wav"),
+            error: PathBuf::from("sounds/error.wav"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FollowUpConfig {
+    /// Keep listening after a response so a follow-up needs no wake word
+    pub enabled: bool,
+    /// Seconds to wait for the follow-up to start before going back to waiting for the wake word
+    pub window_seconds: f64,
+}
+
+impl Default for FollowUpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window_seconds: 5.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordingsConfig {
+    /// Save the audio of every utterance as a WAV file in `dir`, next to a `.txt` transcript
+    pub enabled: bool,
+    pub dir: PathBuf,
+    /// Oldest recordings are deleted once there are more than this many
+    pub max_files: usize,
+    /// Recordings older than this are deleted
+    pub max_age_days: f64,
+}
+
+impl Default for RecordingsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: PathBuf::from("recordings"),
+            max_files: 500,
+            max_age_days: 30.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Address to serve Prometheus metrics on at `/metrics`, e.g. "0.0.0.0:9100"; off when unset
+    pub listen_address: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    /// Address to serve the HTTP control API on, e.g. "0.0.0.0:8080"; off when unset
+    pub listen_address: Option<SocketAddr>,
+    /// Bearer token every request must carry; unset accepts any request
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WyomingConfig {
+    /// Address `wyoming-server` accepts Home Assistant connections on
+    pub listen_address: SocketAddr,
+}
+
+impl Default for WyomingConfig {
+    fn default() -> Self {
+        Self {
+            listen_address: SocketAddr::from(([0, 0, 0, 0], 10700)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SatelliteConfig {
+    /// `host:port` of the `satellite-server` a `satellite` sends its utterances to
+    pub server_address: Option<String>,
+    /// Name the satellite introduces itself with, shown in the server's logs
+    pub name: String,
+    /// Address `satellite-server` accepts satellite connections on
+    pub listen_address: SocketAddr,
+}
+
+impl Default for SatelliteConfig {
+    fn default() -> Self {
+        Self {
+            server_address: None,
+            name: "satellite".to_string(),
+            listen_address: SocketAddr::from(([0, 0, 0, 0], 10800)),
+        }
+    }
+}
+
+/// Settings a subcommand cannot run without, checked by [`AssistantConfig::validate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Requirement {
+    /// A microphone to listen on
+    InputDevice,
+    /// Home Assistant URL and token
+    HomeAssistant,
+    /// The server a satellite connects to
+    SatelliteServer,
+}
+
+/// Command line / environment overrides. Any value set here wins over the config file.
+#[derive(Debug, Default, Args)]
+pub struct ConfigOverrides {
+    #[arg(long, env = "HOME_ASSISTANT_BASE_URL")]
+    pub home_assistant_base_url: Option<Url>,
+
+    #[arg(long, env = "HOME_ASSISTANT_TOKEN")]
+    pub home_assistant_token: Option<String>,
+
+    #[arg(short, long, env = "INPUT_DEVICE_ID")]
+    pub input_device_id: Option<String>,
+
+    #[arg(short, long, env = "SILENCE_SECONDS")]
+    pub silence_seconds: Option<f64>,
+
+    #[arg(long, env = "NO_SPEECH_TIMEOUT_SECONDS")]
+    pub no_speech_timeout_seconds: Option<f64>,
+
+    #[arg(long, env = "MAX_UTTERANCE_SECONDS")]
+    pub max_utterance_seconds: Option<f64>,
+
+    #[arg(short, long, env = "ROLLING_BUFFER_DURATION_SECONDS")]
+    pub rolling_buffer_duration_seconds: Option<f64>,
+
+    #[arg(long, env = "VAD_THRESHOLD")]
+    pub vad_threshold: Option<f32>,
+
+    #[arg(long, env = "CHANNEL_MIX")]
+    pub channel_mix: Option<ChannelMix>,
+
+    #[arg(long, env = "INPUT_CHANNEL")]
+    pub input_channel: Option<usize>,
+
+    #[arg(long, env = "RESAMPLER_QUALITY")]
+    pub resampler_quality: Option<ResamplerQuality>,
+
+    #[arg(long, env = "STALL_TIMEOUT_SECONDS")]
+    pub stall_timeout_seconds: Option<f64>,
+
+    #[arg(long, env = "RECONNECT_INITIAL_BACKOFF_SECONDS")]
+    pub reconnect_initial_backoff_seconds: Option<f64>,
+
+    #[arg(long, env = "RECONNECT_MAX_BACKOFF_SECONDS")]
+    pub reconnect_max_backoff_seconds: Option<f64>,
+
+    #[arg(long, env = "WAKE_WORD_MODEL_PATH")]
+    pub wake_word_model_path: Option<PathBuf>,
+
+    #[arg(long, env = "WAKE_WORD_THRESHOLD")]
+    pub wake_word_threshold: Option<f32>,
+
+    #[arg(long, env = "WAKE_WORD_ACTIVATION_TEXT")]
+    pub wake_word_activation_text: Option<String>,
+
+    #[arg(long, env = "WAKE_WORD_WHILE_SPEAKING")]
+    pub wake_word_while_speaking: Option<WhileSpeakingPolicy>,
+
+    #[arg(long, env = "WAKE_WORD_SPEAKING_THRESHOLD")]
+    pub wake_word_speaking_threshold: Option<f32>,
+
+    #[arg(long, env = "WAKE_WORD_SPEAKING_HANGOVER_SECONDS")]
+    pub wake_word_speaking_hangover_seconds: Option<f64>,
+
+    #[arg(long, env = "WHISPER_MODEL_PATH")]
+    pub whisper_model_path: Option<PathBuf>,
+
+    #[arg(long, env = "WHISPER_LANGUAGE")]
+    pub whisper_language: Option<String>,
+
+    #[arg(long, env = "WHISPER_STRATEGY")]
+    pub whisper_strategy: Option<SttStrategy>,
+
+    #[arg(long, env = "WHISPER_BEAM_SIZE")]
+    pub whisper_beam_size: Option<i32>,
+
+    #[arg(long, env = "WHISPER_BEST_OF")]
+    pub whisper_best_of: Option<i32>,
+
+    #[arg(long, env = "WHISPER_THREADS")]
+    pub whisper_threads: Option<i32>,
+
+    #[arg(long, env = "WHISPER_TEMPERATURE_INC")]
+    pub whisper_temperature_inc: Option<f32>,
+
+    #[arg(long, env = "WHISPER_NO_SPEECH_THRESHOLD")]
+    pub whisper_no_speech_threshold: Option<f32>,
+
+    #[arg(long, env = "TTS_MODEL_PATH")]
+    pub tts_model_path: Option<PathBuf>,
+
+    #[arg(long, env = "TTS_VOICE_PATH")]
+    pub tts_voice_path: Option<PathBuf>,
+
+    #[arg(long, env = "TTS_BARGE_IN")]
+    pub tts_barge_in: Option<bool>,
+
+    #[arg(long, env = "WEATHER_LATITUDE")]
+    pub weather_latitude: Option<f64>,
+
+    #[arg(long, env = "WEATHER_LONGITUDE")]
+    pub weather_longitude: Option<f64>,
+
+    #[arg(long, env = "ALARM_VOLUME")]
+    pub alarm_volume: Option<f32>,
+
+    #[arg(long, env = "TIMERS_STATE_PATH")]
+    pub timers_state_path: Option<PathBuf>,
+
+    #[arg(long, env = "METRICS_LISTEN_ADDRESS")]
+    pub metrics_listen_address: Option<SocketAddr>,
+
+    #[arg(long, env = "API_LISTEN_ADDRESS")]
+    pub api_listen_address: Option<SocketAddr>,
+
+    #[arg(long, env = "API_TOKEN")]
+    pub api_token: Option<String>,
+
+    #[arg(long, env = "WYOMING_LISTEN_ADDRESS")]
+    pub wyoming_listen_address: Option<SocketAddr>,
+
+    #[arg(long, env = "SATELLITE_SERVER_ADDRESS")]
+    pub satellite_server_address: Option<String>,
+
+    #[arg(long, env = "SATELLITE_NAME")]
+    pub satellite_name: Option<String>,
+
+    #[arg(long, env = "SATELLITE_LISTEN_ADDRESS")]
+    pub satellite_listen_address: Option<SocketAddr>,
+
+    /// Append the interaction history to this file; turns the history on
+    #[arg(long, env = "HISTORY_PATH")]
+    pub history_path: Option<PathBuf>,
+
+    #[arg(long, env = "EARCONS")]
+    pub earcons: Option<bool>,
+
+    #[arg(long, env = "FOLLOW_UP")]
+    pub follow_up: Option<bool>,
+
+    #[arg(long, env = "FOLLOW_UP_WINDOW_SECONDS")]
+    pub follow_up_window_seconds: Option<f64>,
+
+    /// Save utterance recordings to this directory; turns recording on
+    #[arg(long, env = "RECORDINGS_DIR")]
+    pub recordings_dir: Option<PathBuf>,
+}
+
+impl AssistantConfig {
+    /// Read and parse a TOML config file. Values are not validated here, see [`Self::validate`].
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("failed to read config file {}", path.display()))?;
+        toml::from_str(&contents)
+            .wrap_err_with(|| format!("failed to parse config file {}", path.display()))
+    }
+
+    /// Load the config file (if any), apply the overrides on top and validate the result.
+    pub fn resolve(
+        path: Option<&Path>,
+        overrides: ConfigOverrides,
+        requirements: &[Requirement],
+    ) -> Result<Self> {
+        let mut config = match path {
+            Some(path) => Self::load(path)?,
+            None => Self::default(),
+        };
+        config.apply_overrides(overrides);
+        config.validate(requirements)?;
+        Ok(config)
+    }
+
+    pub fn apply_overrides(&mut self, overrides: ConfigOverrides) {
+        if let Some(v) = overrides.home_assistant_base_url {
+            self.home_assistant.base_url = Some(v);
+        }
+        if let Some(v) = overrides.home_assistant_token {
+            self.home_assistant.token = Some(v);
+        }
+        if let Some(v) = overrides.input_device_id {
+            self.audio.input_device_id = Some(v);
+        }
+        if let Some(v) = overrides.silence_seconds {
+            self.audio.silence_seconds = v;
+        }
+        if let Some(v) = overrides.no_speech_timeout_seconds {
+            self.audio.no_speech_timeout_seconds = v;
+        }
+        if let Some(v) = overrides.max_utterance_seconds {
+            self.audio.max_utterance_seconds = v;
+        }
+        if let Some(v) = overrides.rolling_buffer_duration_seconds {
+            self.audio.rolling_buffer_duration_seconds = v;
+        }
+        if let Some(v) = overrides.vad_threshold {
+            self.audio.vad_threshold = v;
+        }
+        if let Some(v) = overrides.channel_mix {
+            self.audio.channel_mix = v;
+        }
+        if let Some(v) = overrides.input_channel {
+            self.audio.channel = v;
+        }
+        if let Some(v) = overrides.resampler_quality {
+            self.audio.resampler_quality = v;
+        }
+        if let Some(v) = overrides.stall_timeout_seconds {
+            self.audio.stall_timeout_seconds = v;
+        }
+        if let Some(v) = overrides.reconnect_initial_backoff_seconds {
+            self.audio.reconnect_initial_backoff_seconds = v;
+        }
+        if let Some(v) = overrides.reconnect_max_backoff_seconds {
+            self.audio.reconnect_max_backoff_seconds = v;
+        }
+        if let Some(v) = overrides.wake_word_model_path {
+            self.wake_word.model_path = Some(v);
+        }
+        if let Some(v) = overrides.wake_word_threshold {
+            self.wake_word.threshold = v;
+        }
+        if let Some(v) = overrides.wake_word_activation_text {
+            self.wake_word.activation_text = v;
+        }
+        if let Some(v) = overrides.wake_word_while_speaking {
+            self.wake_word.while_speaking = v;
+        }
+        if let Some(v) = overrides.wake_word_speaking_threshold {
+            self.wake_word.speaking_threshold = v;
+        }
+        if let Some(v) = overrides.wake_word_speaking_hangover_seconds {
+            self.wake_word.speaking_hangover_seconds = v;
+        }
+        if let Some(v) = overrides.whisper_model_path {
+            self.stt.model_path = v;
+        }
+        if let Some(v) = overrides.whisper_language {
+            self.stt.language = v;
+        }
+        if let Some(v) = overrides.whisper_strategy {
+            self.stt.strategy = v;
+        }
+        if let Some(v) = overrides.whisper_beam_size {
+            self.stt.beam_size = v;
+        }
+        if let Some(v) = overrides.whisper_best_of {
+            self.stt.best_of = v;
+        }
+        if let Some(v) = overrides.whisper_threads {
+            self.stt.threads = Some(v);
+        }
+        if let Some(v) = overrides.whisper_temperature_inc {
+            self.stt.temperature_inc = v;
+        }
+        if let Some(v) = overrides.whisper_no_speech_threshold {
+            self.stt.no_speech_threshold = v;
+        }
+        if let Some(v) = overrides.tts_model_path {
+            self.tts.model_path = v;
+        }
+        if let Some(v) = overrides.tts_voice_path {
+            self.tts.voice_path = v;
+        }
+        if let Some(v) = overrides.tts_barge_in {
+            self.tts.barge_in = v;
+        }
+        if let Some(v) = overrides.weather_latitude {
+            self.weather.latitude = Some(v);
+        }
+        if let Some(v) = overrides.weather_longitude {
+            self.weather.longitude = Some(v);
+        }
+        if let Some(v) = overrides.alarm_volume {
+            self.timers.alarm_volume = v;
+        }
+        if let Some(v) = overrides.timers_state_path {
+            self.timers.state_path = v;
+        }
+        if let Some(v) = overrides.metrics_listen_address {
+            self.metrics.listen_address = Some(v);
+        }
+        if let Some(v) = overrides.api_listen_address {
+            self.api.listen_address = Some(v);
+        }
+        if let Some(v) = overrides.api_token {
+            self.api.token = Some(v);
+        }
+        if let Some(v) = overrides.wyoming_listen_address {
+            self.wyoming.listen_address = v;
+        }
+        if let Some(v) = overrides.satellite_server_address {
+            self.satellite.server_address = Some(v);
+        }
+        if let Some(v) = overrides.satellite_name {
+            self.satellite.name = v;
+        }
+        if let Some(v) = overrides.satellite_listen_address {
+            self.satellite.listen_address = v;
+        }
+        if let Some(v) = overrides.history_path {
+            self.history.enabled = true;
+            self.history.path = v;
+        }
+        if let Some(v) = overrides.earcons {
+            self.earcons.enabled = v;
+        }
+        if let Some(v) = overrides.follow_up {
+            self.follow_up.enabled = v;
+        }
+        if let Some(v) = overrides.follow_up_window_seconds {
+            self.follow_up.window_seconds = v;
+        }
+        if let Some(v) = overrides.recordings_dir {
+            self.recordings.enabled = true;
+            self.recordings.dir = v;
+        }
+    }
+
+    /// Check every field and report all problems at once rather than stopping at the first.
+    /// Optional settings only become mandatory when listed in `requirements`.
+    pub fn validate(&self, requirements: &[Requirement]) -> Result<()> {
+        let mut errors = Vec::new();
+
+        if requirements.contains(&Requirement::InputDevice)
+            && self
+                .audio
+                .input_device_id
+                .as_deref()
+                .is_none_or(|id| id.trim().is_empty())
+        {
+            errors.push(
+                "audio.input_device_id is required (or set --input-device-id / INPUT_DEVICE_ID)"
+                    .to_string(),
+            );
+        }
+        check_seconds(
+            &mut errors,
+            "audio.silence_seconds",
+            self.audio.silence_seconds,
+            false,
+        );
+        check_seconds(
+            &mut errors,
+            "audio.no_speech_timeout_seconds",
+            self.audio.no_speech_timeout_seconds,
+            false,
+        );
+        check_seconds(
+            &mut errors,
+            "audio.max_utterance_seconds",
+            self.audio.max_utterance_seconds,
+            false,
+        );
+        if self.audio.max_utterance_seconds <= self.audio.silence_seconds {
+            errors.push(format!(
+                "audio.max_utterance_seconds must be greater than audio.silence_seconds (got {})",
+                self.audio.max_utterance_seconds
+            ));
+        }
+        check_seconds(
+            &mut errors,
+            "audio.rolling_buffer_duration_seconds",
+            self.audio.rolling_buffer_duration_seconds,
+            true,
+        );
+        check_unit_range(&mut errors, "audio.vad_threshold", self.audio.vad_threshold);
+        check_seconds(
+            &mut errors,
+            "audio.stall_timeout_seconds",
+            self.audio.stall_timeout_seconds,
+            false,
+        );
+        check_seconds(
+            &mut errors,
+            "audio.reconnect_initial_backoff_seconds",
+            self.audio.reconnect_initial_backoff_seconds,
+            false,
+        );
+        check_seconds(
+            &mut errors,
+            "audio.reconnect_max_backoff_seconds",
+            self.audio.reconnect_max_backoff_seconds,
+            false,
+        );
+        if self.audio.reconnect_max_backoff_seconds < self.audio.reconnect_initial_backoff_seconds {
+            errors.push(format!(
+                "audio.reconnect_max_backoff_seconds must be at least reconnect_initial_backoff_seconds (got {} < {})",
+                self.audio.reconnect_max_backoff_seconds,
+                self.audio.reconnect_initial_backoff_seconds
+            ));
+        }
+        check_unit_range(&mut errors, "wake_word.threshold", self.wake_word.threshold);
+        check_unit_range(
+            &mut errors,
+            "wake_word.speaking_threshold",
+            self.wake_word.speaking_threshold,
+        );
+        if self.wake_word.while_speaking == WhileSpeakingPolicy::RaiseThreshold
+            && self.wake_word.speaking_threshold < self.wake_word.threshold
+        {
+            errors.push(format!(
+                "wake_word.speaking_threshold must be at least wake_word.threshold (got {} < {})",
+                self.wake_word.speaking_threshold, self.wake_word.threshold
+            ));
+        }
+        check_seconds(
+            &mut errors,
+            "wake_word.speaking_hangover_seconds",
+            self.wake_word.speaking_hangover_seconds,
+            true,
+        );
+        if let Some(model_path) = &self.wake_word.model_path
+            && !model_path.is_file()
+        {
+            errors.push(format!(
+                "wake_word.model_path {} does not exist",
+                model_path.display()
+            ));
+        }
+        if self.wake_word.activation_text.trim().is_empty() {
+            errors.push("wake_word.activation_text must not be empty".to_string());
+        }
+        if self.stt.model_path.as_os_str().is_empty() {
+            errors.push("stt.model_path must not be empty".to_string());
+        }
+        if self.stt.language.trim().is_empty() {
+            errors.push("stt.language must not be empty (use \"auto\" to detect it)".to_string());
+        }
+        if self.stt.beam_size < 1 {
+            errors.push(format!(
+                "stt.beam_size must be at least 1 (got {})",
+                self.stt.beam_size
+            ));
+        }
+        if self.stt.best_of < 1 {
+            errors.push(format!(
+                "stt.best_of must be at least 1 (got {})",
+                self.stt.best_of
+            ));
+        }
+        if let Some(threads) = self.stt.threads
+            && threads < 1
+        {
+            errors.push(format!("stt.threads must be at least 1 (got {})", threads));
+        }
+        check_unit_range(&mut errors, "stt.temperature_inc", self.stt.temperature_inc);
+        check_unit_range(
+            &mut errors,
+            "stt.no_speech_threshold",
+            self.stt.no_speech_threshold,
+        );
+        if self.tts.model_path.as_os_str().is_empty() {
+            errors.push("tts.model_path must not be empty".to_string());
+        }
+        if self.tts.voice_path.as_os_str().is_empty() {
+            errors.push("tts.voice_path must not be empty".to_string());
+        }
+        let require_home_assistant = requirements.contains(&Requirement::HomeAssistant);
+        if require_home_assistant && self.home_assistant.base_url.is_none() {
+            errors.push(
+                "home_assistant.base_url is required (or set --home-assistant-base-url / HOME_ASSISTANT_BASE_URL)"
+                    .to_string(),
+            );
+        }
+        if require_home_assistant
+            && self
+                .home_assistant
+                .token
+                .as_deref()
+                .is_none_or(|token| token.trim().is_empty())
+        {
+            errors.push(
+                "home_assistant.token is required (or set --home-assistant-token / HOME_ASSISTANT_TOKEN)"
+                    .to_string(),
+            );
+        }
+        if let Some(latitude) = self.weather.latitude
+            && !(-90.0..=90.0).contains(&latitude)
+        {
+            errors.push(format!(
+                "weather.latitude must be between -90 and 90 (got {})",
+                latitude
+            ));
+        }
+        if let Some(longitude) = self.weather.longitude
+            && !(-180.0..=180.0).contains(&longitude)
+        {
+            errors.push(format!(
+                "weather.longitude must be between -180 and 180 (got {})",
+                longitude
+            ));
+        }
+        check_unit_range(&mut errors, "timers.alarm_volume", self.timers.alarm_volume);
+        if self.timers.state_path.as_os_str().is_empty() {
+            errors.push("timers.state_path must not be empty".to_string());
+        }
+        if self.history.path.as_os_str().is_empty() {
+            errors.push("history.path must not be empty".to_string());
+        }
+        if self
+            .api
+            .token
+            .as_deref()
+            .is_some_and(|token| token.trim().is_empty())
+        {
+            errors.push("api.token must not be empty; leave it unset to turn auth off".to_string());
+        }
+        if requirements.contains(&Requirement::SatelliteServer)
+            && self
+                .satellite
+                .server_address
+                .as_deref()
+                .is_none_or(|address| address.trim().is_empty())
+        {
+            errors.push(
+                "satellite.server_address is required (or set --satellite-server-address / SATELLITE_SERVER_ADDRESS)"
+                    .to_string(),
+            );
+        }
+        if self.satellite.name.trim().is_empty() {
+            errors.push("satellite.name must not be empty".to_string());
+        }
+        if self.follow_up.enabled {
+            check_seconds(
+                &mut errors,
+                "follow_up.window_seconds",
+                self.follow_up.window_seconds,
+                false,
+            );
+        }
+        if self.recordings.enabled {
+            if self.recordings.dir.as_os_str().is_empty() {
+                errors.push("recordings.dir must not be empty".to_string());
+            }
+            if self.recordings.max_files == 0 {
+                errors.push("recordings.max_files must be at least 1".to_string());
+            }
+            let max_age_days = self.recordings.max_age_days;
+            if max_age_days.is_nan()
+                || max_age_days <= 0.0
+                || max_age_days * SECONDS_PER_DAY > MAX_SECONDS
+            {
+                errors.push(format!(
+                    "recordings.max_age_days must be greater than 0 and at most {} (got {})",
+                    MAX_SECONDS / SECONDS_PER_DAY,
+                    self.recordings.max_age_days
+                ));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(color_eyre::eyre::eyre!(
+                "Invalid configuration ({} problem{}):\n  - {}",
+                errors.len(),
+                if errors.len() == 1 { "" } else { "s" },
+                errors.join("\n  - ")
+            ))
+        }
+    }
+}
+
+/// Longest duration accepted for any setting, about 30 years. Anything longer is a typo, and
+/// could overflow when added to the current time.
+const MAX_SECONDS: f64 = 1e9;
+
+pub const SECONDS_PER_DAY: f64 = 24.0 * 60.0 * 60.0;
+
+/// Make sure `value` converts to a `Duration`: a number, greater than 0 (or not negative with
+/// `allow_zero`) and at most [`MAX_SECONDS`].
+fn check_seconds(errors: &mut Vec<String>, field: &str, value: f64, allow_zero: bool) {
+    if allow_zero && value < 0.0 {
+        errors.push(format!("{} must not be negative (got {})", field, value));
+    } else if !allow_zero && value <= 0.0 {
+        errors.push(format!("{} must be greater than 0 (got {})", field, value));
+    } else if value.is_nan() || value > MAX_SECONDS {
+        errors.push(format!(
+            "{} must be a number of seconds no larger than {} (got {})",
+            field, MAX_SECONDS, value
+        ));
+    }
+}
+
+fn check_unit_range(errors: &mut Vec<String>, field: &str, value: f32) {
+    if !(0.0..=1.0).contains(&value) {
+        errors.push(format!(
+            "{} must be between 0.0 and 1.0 (got {})",
+            field, value
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_REQUIREMENTS: &[Requirement] =
+        &[Requirement::InputDevice, Requirement::HomeAssistant];
+
+    fn valid_config() -> AssistantConfig {
+        toml::from_str(
+            r#"
+            [audio]
+            input_device_id = "alsa:default"
+
+            [home_assistant]
+            base_url = "http://homeassistant.local:8123"
+            token = "secret"
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn parse_empty_file_uses_defaults() {
+        let config: AssistantConfig = toml::from_str("").unwrap();
+        assert_eq!(config.audio.silence_seconds, 1.0);
+        assert_eq!(config.audio.vad_threshold, 0.75);
+        assert_eq!(config.wake_word.activation_text, "alexa");
+        assert_eq!(config.timers.alarm_volume, 0.5);
+    }
+
+    #[test]
+    fn parse_all_sections() {
+        let config: AssistantConfig = toml::from_str(
+            r#"
+            [audio]
+            input_device_id = "alsa:hw:1"
+            silence_seconds = 1.5
+            rolling_buffer_duration_seconds = 3.0
+            vad_threshold = 0.6
+            channel_mix = "loudest"
+
+            [wake_word]
+            model_path = "models/hey_jarvis.onnx"
+            threshold = 0.4
+            activation_text = "jarvis"
+
+            [stt]
+            model_path = "whisper_model/ggml-base.bin"
+            language = "auto"
+            strategy = "greedy"
+            best_of = 3
+            threads = 2
+            temperature_inc = 0.0
+            no_speech_threshold = 0.5
+
+            [tts]
+            model_path = "model/other.safetensors"
+            voice_path = "model/other.wav"
+
+            [home_assistant]
+            base_url = "http://homeassistant.local:8123"
+            token = "secret"
+
+            [weather]
+            latitude = 39.7
+            longitude = -104.9
+
+            [timers]
+            alarm_volume = 0.8
+
+            [metrics]
+            listen_address = "127.0.0.1:9100"
+
+            [wyoming]
+            listen_address = "127.0.0.1:10300"
+
+            [satellite]
+            server_address = "assistant.local:10800"
+            name = "kitchen"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.audio.input_device_id.as_deref(), Some("alsa:hw:1"));
+        assert_eq!(config.audio.vad_threshold, 0.6);
+        assert_eq!(config.audio.channel_mix, ChannelMix::Loudest);
+        assert_eq!(
+            config.wake_word.model_path,
+            Some(PathBuf::from("models/hey_jarvis.onnx"))
+        );
+        assert_eq!(config.wake_word.activation_text, "jarvis");
+        assert_eq!(
+            config.stt.model_path,
+            PathBuf::from("whisper_model/ggml-base.bin")
+        );
+        assert_eq!(config.stt.language, "auto");
+        assert_eq!(config.stt.strategy, SttStrategy::Greedy);
+        assert_eq!(config.stt.beam_size, 5);
+        assert_eq!(config.stt.best_of, 3);
+        assert_eq!(config.stt.threads, Some(2));
+        assert_eq!(config.weather.longitude, Some(-104.9));
+        assert_eq!(config.timers.alarm_volume, 0.8);
+        assert_eq!(
+            config.metrics.listen_address,
+            Some("127.0.0.1:9100".parse().unwrap())
+        );
+        assert_eq!(
+            config.wyoming.listen_address,
+            "127.0.0.1:10300".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            config.satellite.server_address.as_deref(),
+            Some("assistant.local:10800")
+        );
+        assert_eq!(config.satellite.name, "kitchen");
+
+        // The model file does not exist in the test environment, everything else is valid
+        let message = config.validate(ALL_REQUIREMENTS).unwrap_err().to_string();
+        assert!(message.contains("(1 problem)"), "{}", message);
+        assert!(message.contains("wake_word.model_path"), "{}", message);
+    }
+
+    #[test]
+    fn parse_rejects_unknown_fields() {
+        let result = toml::from_str::<AssistantConfig>("[audio]\nsilence_second = 1.0\n");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn overrides_win_over_file() {
+        let mut config = valid_config();
+        config.apply_overrides(ConfigOverrides {
+            silence_seconds: Some(2.5),
+            home_assistant_token: Some("from-env".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(config.audio.silence_seconds, 2.5);
+        assert_eq!(config.home_assistant.token.as_deref(), Some("from-env"));
+        assert_eq!(
+            config.audio.input_device_id.as_deref(),
+            Some("alsa:default")
+        );
+    }
+
+    #[test]
+    fn validate_rejects_durations_that_cannot_be_represented() {
+        let mut config = valid_config();
+        config.audio.silence_seconds = f64::NAN;
+        config.audio.stall_timeout_seconds = f64::INFINITY;
+        config.wake_word.speaking_hangover_seconds = 1e300;
+        config.recordings.enabled = true;
+        config.recordings.max_age_days = f64::NAN;
+
+        let message = config.validate(ALL_REQUIREMENTS).unwrap_err().to_string();
+        assert!(message.contains("audio.silence_seconds"), "{}", message);
+        assert!(
+            message.contains("audio.stall_timeout_seconds"),
+            "{}",
+            message
+        );
+        assert!(
+            message.contains("wake_word.speaking_hangover_seconds"),
+            "{}",
+            message
+        );
+        assert!(message.contains("recordings.max_age_days"), "{}", message);
+    }
+
+    #[test]
+    fn validate_listening_timeouts() {
+        let mut config = valid_config();
+        config.apply_overrides(ConfigOverrides {
+            no_speech_timeout_seconds: Some(0.0),
+            max_utterance_seconds: Some(0.5),
+            ..Default::default()
+        });
+        let message = config.validate(ALL_REQUIREMENTS).unwrap_err().to_string();
+        assert!(message.contains("2 problems"), "{}", message);
+        assert!(
+            message.contains("audio.no_speech_timeout_seconds"),
+            "{}",
+            message
+        );
+        assert!(
+            message.contains("audio.max_utterance_seconds"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn history_is_opt_in() {
+        let mut config = valid_config();
+        assert!(!config.history.enabled);
+
+        config.apply_overrides(ConfigOverrides {
+            history_path: Some(PathBuf::from("/var/lib/assistant/history.jsonl")),
+            ..Default::default()
+        });
+        assert!(config.history.enabled);
+        assert_eq!(
+            config.history.path,
+            PathBuf::from("/var/lib/assistant/history.jsonl")
+        );
+    }
+
+    #[test]
+    fn recordings_dir_override_turns_recording_on() {
+        let mut config = valid_config();
+        config.recordings.max_files = 0;
+        assert!(config.validate(ALL_REQUIREMENTS).is_ok());
+
+        config.apply_overrides(ConfigOverrides {
+            recordings_dir: Some(PathBuf::from("/tmp/utterances")),
+            ..Default::default()
+        });
+        assert!(config.recordings.enabled);
+        assert_eq!(config.recordings.dir, PathBuf::from("/tmp/utterances"));
+        let message = config.validate(ALL_REQUIREMENTS).unwrap_err().to_string();
+        assert!(message.contains("recordings.max_files"), "{}", message);
+    }
+
+    #[test]
+    fn validate_lists_every_bad_field() {
+        let mut config = valid_config();
+        config.audio.silence_seconds = 0.0;
+        config.audio.vad_threshold = 1.5;
+        config.home_assistant.token = None;
+        config.weather.latitude = Some(120.0);
+        config.wake_word.model_path = Some(PathBuf::from("does/not/exist.onnx"));
+
+        let message = config.validate(ALL_REQUIREMENTS).unwrap_err().to_string();
+        assert!(message.contains("5 problems"), "{}", message);
+        assert!(message.contains("wake_word.model_path"), "{}", message);
+        assert!(message.contains("audio.silence_seconds"), "{}", message);
+        assert!(message.contains("audio.vad_threshold"), "{}", message);
+        assert!(message.contains("home_assistant.token"), "{}", message);
+        assert!(message.contains("weather.latitude"), "{}", message);
+    }
+
+    #[test]
+    fn validate_requires_device_and_home_assistant() {
+        let message = AssistantConfig::default()
+            .validate(ALL_REQUIREMENTS)
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains("audio.input_device_id"), "{}", message);
+        assert!(message.contains("home_assistant.base_url"), "{}", message);
+        assert!(message.contains("home_assistant.token"), "{}", message);
+    }
+
+    #[test]
+    fn validate_requires_satellite_server_address() {
+        let config = valid_config();
+        let message = config
+            .validate(&[Requirement::SatelliteServer])
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains("satellite.server_address"), "{}", message);
+        assert!(config.validate(ALL_REQUIREMENTS).is_ok());
+    }
+
+    #[test]
+    fn validate_skips_requirements_not_asked_for() {
+        assert!(AssistantConfig::default().validate(&[]).is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_max_backoff_below_initial() {
+        let mut config = valid_config();
+        config.audio.reconnect_initial_backoff_seconds = 10.0;
+        config.audio.reconnect_max_backoff_seconds = 5.0;
+
+        let message = config.validate(ALL_REQUIREMENTS).unwrap_err().to_string();
+        assert!(
+            message.contains("audio.reconnect_max_backoff_seconds"),
+            "{}",
+            message
+        );
+    }
+}
@@ -0,0 +1,343 @@
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use color_eyre::eyre::Result;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tiny_http::Method;
+use tracing::{debug, info, info_span, warn};
+
+use crate::speech_output::{Priority, Utterance};
+
+/// How long a request waits for the event loop, which may be busy transcribing an utterance or
+/// running a slow command.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Something the event loop is asked to do on behalf of an HTTP client.
+pub enum ApiCall {
+    /// Run a command as if it had been spoken; `speak` also says the response
+    Command {
+        text: String,
+        speak: bool,
+    },
+    Say(Utterance),
+    ListTimers,
+    CancelTimer(u64),
+    Status,
+}
+
+/// A call waiting for the event loop to answer it.
+pub struct ApiRequest {
+    pub call: ApiCall,
+    pub responder: Responder,
+}
+
+/// Sends the answer back to the HTTP thread.
+pub struct Responder(oneshot::Sender<ApiResult>);
+
+impl Responder {
+    pub fn respond(self, result: ApiResult) {
+        // The client may have timed out already
+        let _ = self.0.send(result);
+    }
+}
+
+/// The JSON body of a successful response, or why the call failed.
+pub type ApiResult = std::result::Result<Value, ApiError>;
+
+#[derive(Debug, PartialEq)]
+pub struct ApiError {
+    status: u16,
+    message: String,
+}
+
+impl ApiError {
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self {
+            status: 400,
+            message: message.into(),
+        }
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self {
+            status: 404,
+            message: message.into(),
+        }
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self {
+            status: 500,
+            message: message.into(),
+        }
+    }
+
+    fn unavailable(message: impl Into<String>) -> Self {
+        Self {
+            status: 503,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CommandBody {
+    text: String,
+    #[serde(default)]
+    speak: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SayBody {
+    text: String,
+    #[serde(default = "full_volume")]
+    volume: f32,
+    #[serde(default = "announcement")]
+    priority: Priority,
+}
+
+fn full_volume() -> f32 {
+    1.0
+}
+
+fn announcement() -> Priority {
+    Priority::Announcement
+}
+
+fn parse_body<T: for<'de> Deserialize<'de>>(body: &str) -> std::result::Result<T, ApiError> {
+    serde_json::from_str(body).map_err(|e| ApiError::bad_request(format!("invalid body: {}", e)))
+}
+
+/// Map a request to the call it stands for, checking the body on the way.
+fn route(method: &Method, path: &str, body: &str) -> std::result::Result<ApiCall, ApiError> {
+    let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+    match (method, segments.as_slice()) {
+        (Method::Post, ["command"]) => {
+            let body: CommandBody = parse_body(body)?;
+            if body.text.trim().is_empty() {
+                return Err(ApiError::bad_request("text must not be empty"));
+            }
+            Ok(ApiCall::Command {
+                text: body.text,
+                speak: body.speak,
+            })
+        }
+        (Method::Post, ["say"]) => {
+            let body: SayBody = parse_body(body)?;
+            if body.text.trim().is_empty() {
+                return Err(ApiError::bad_request("text must not be empty"));
+            }
+            if !(0.0..=1.0).contains(&body.volume) {
+                return Err(ApiError::bad_request(format!(
+                    "volume must be between 0 and 1 (got {})",
+                    body.volume
+                )));
+            }
+            Ok(ApiCall::Say(Utterance {
+                volume: body.volume,
+                ..Utterance::new(body.text, body.priority)
+            }))
+        }
+        (Method::Get, ["timers"]) => Ok(ApiCall::ListTimers),
+        (Method::Delete, ["timers", id]) => id
+            .parse()
+            .map(ApiCall::CancelTimer)
+            .map_err(|_| ApiError::bad_request(format!("invalid timer id: {}", id))),
+        (Method::Get, ["status"]) => Ok(ApiCall::Status),
+        _ => Err(ApiError::not_found(format!(
+            "no route for {} {}",
+            method, path
+        ))),
+    }
+}
+
+/// Whether the request carries `Authorization: Bearer <token>`, when a token is configured.
+fn authorized(request: &tiny_http::Request, token: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+    request.headers().iter().any(|header| {
+        header.field.equiv("Authorization")
+            && header.value.as_str().strip_prefix("Bearer ") == Some(token)
+    })
+}
+
+fn handle(
+    request: &mut tiny_http::Request,
+    token: Option<&str>,
+    requests: &mpsc::Sender<ApiRequest>,
+) -> ApiResult {
+    if !authorized(request, token) {
+        return Err(ApiError {
+            status: 401,
+            message: "missing or wrong bearer token".to_string(),
+        });
+    }
+    let mut body = String::new();
+    request
+        .as_reader()
+        .read_to_string(&mut body)
+        .map_err(|e| ApiError::bad_request(format!("failed to read body: {}", e)))?;
+    let path = request
+        .url()
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let call = info_span!("api", method = %request.method(), path)
+        .in_scope(|| route(request.method(), &path, &body))?;
+
+    let (reply, response) = oneshot::channel();
+    requests
+        .send(ApiRequest {
+            call,
+            responder: Responder(reply),
+        })
+        .map_err(|_| ApiError::unavailable("the assistant is shutting down"))?;
+    response
+        .recv_timeout(REPLY_TIMEOUT)
+        .map_err(|_| ApiError::unavailable("the assistant did not answer in time"))?
+}
+
+/// Serve the control API on `address` from a background thread, handing each call to the event
+/// loop through `requests`. With a `token`, every request must carry it as a bearer token.
+pub fn spawn_server(
+    address: SocketAddr,
+    token: Option<String>,
+    requests: mpsc::Sender<ApiRequest>,
+) -> Result<()> {
+    let server = tiny_http::Server::http(address)
+        .map_err(|e| color_eyre::eyre::eyre!("failed to listen on {}: {}", address, e))?;
+    info!(%address, "serving control API");
+
+    thread::spawn(move || {
+        for mut request in server.incoming_requests() {
+            let (status, body) = match handle(&mut request, token.as_deref(), &requests) {
+                Ok(body) => (200, body),
+                Err(e) => {
+                    debug!(status = e.status, error = %e.message, "control API request failed");
+                    (e.status, json!({ "error": e.message }))
+                }
+            };
+            let response = tiny_http::Response::from_string(body.to_string())
+                .with_status_code(status)
+                .with_header(
+                    tiny_http::Header::from_bytes("Content-Type", "application/json").unwrap(),
+                );
+            if let Err(e) = request.respond(response) {
+                warn!(error = %e, "failed to send control API response");
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    #[test]
+    fn route_parses_bodies_and_paths() {
+        let call = route(
+            &Method::Post,
+            "/say",
+            r#"{"text": "Dinner is ready", "volume": 0.5}"#,
+        );
+        let Ok(ApiCall::Say(utterance)) = call else {
+            panic!("expected a say call");
+        };
+        assert_eq!(utterance.text, "Dinner is ready");
+        assert_eq!(utterance.volume, 0.5);
+        assert_eq!(utterance.priority, Priority::Announcement);
+
+        let call = route(
+            &Method::Post,
+            "/say",
+            r#"{"text": "Wake up", "priority": "alarm"}"#,
+        );
+        assert!(matches!(call, Ok(ApiCall::Say(u)) if u.priority == Priority::Alarm));
+
+        let call = route(&Method::Post, "/command", r#"{"text": "what time is it"}"#);
+        assert!(matches!(call, Ok(ApiCall::Command { speak: false, .. })));
+
+        assert!(matches!(
+            route(&Method::Delete, "/timers/3", ""),
+            Ok(ApiCall::CancelTimer(3))
+        ));
+        assert!(matches!(
+            route(&Method::Get, "/timers/", ""),
+            Ok(ApiCall::ListTimers)
+        ));
+
+        for (method, path, body, status) in [
+            (
+                Method::Post,
+                "/say",
+                r#"{"text": "Hi", "volume": 2.0}"#,
+                400,
+            ),
+            (Method::Post, "/say", r#"{"text": " "}"#, 400),
+            (Method::Post, "/command", "not json", 400),
+            (Method::Delete, "/timers/tea", "", 400),
+            (Method::Get, "/say", "", 404),
+        ] {
+            let Err(e) = route(&method, path, body) else {
+                panic!("{} {} should fail", method, path);
+            };
+            assert_eq!(e.status, status, "{} {}: {}", method, path, e.message);
+        }
+    }
+
+    #[test]
+    fn server_forwards_calls_and_checks_the_token() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let (tx, rx) = mpsc::channel();
+        spawn_server(address, Some("secret".to_string()), tx).unwrap();
+
+        // Stands in for the event loop
+        thread::spawn(move || {
+            for request in rx {
+                let result = match &request.call {
+                    ApiCall::ListTimers => Ok(json!([{ "id": 1, "name": "tea" }])),
+                    ApiCall::CancelTimer(id) => {
+                        Err(ApiError::not_found(format!("no timer {}", id)))
+                    }
+                    _ => Ok(json!({})),
+                };
+                request.responder.respond(result);
+            }
+        });
+
+        let send = |method: &str, path: &str, token: &str| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            write!(
+                stream,
+                "{} {} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\n\
+                 Content-Length: 0\r\nConnection: close\r\n\r\n",
+                method, path, token
+            )
+            .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let timers = send("GET", "/timers", "secret");
+        assert!(timers.starts_with("HTTP/1.1 200"), "{}", timers);
+        assert!(timers.contains(r#"[{"id":1,"name":"tea"}]"#), "{}", timers);
+        let missing = send("DELETE", "/timers/7", "secret");
+        assert!(missing.starts_with("HTTP/1.1 404"), "{}", missing);
+        assert!(missing.contains(r#"{"error":"no timer 7"}"#), "{}", missing);
+        assert!(send("GET", "/timers", "wrong").starts_with("HTTP/1.1 401"));
+    }
+}
@@ -0,0 +1,377 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::config::HistoryConfig;
+use crate::speech::SpeechSegment;
+
+/// How often `history --follow` checks the file for new records.
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A whisper segment with its timestamps converted to seconds from the start of the utterance.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SegmentRecord {
+    pub start_seconds: f64,
+    pub end_seconds: f64,
+    pub text: String,
+}
+
+impl From<&SpeechSegment> for SegmentRecord {
+    fn from(segment: &SpeechSegment) -> Self {
+        // whisper timestamps are in centiseconds
+        Self {
+            start_seconds: segment.start_timestamp as f64 / 100.0,
+            end_seconds: segment.end_timestamp as f64 / 100.0,
+            text: segment.text.clone(),
+        }
+    }
+}
+
+/// Milliseconds spent in each stage; a stage that did not run is left out.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StageLatency {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transcription_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub intent_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tts_ms: Option<u64>,
+}
+
+/// Everything known about one interaction, from wake word to spoken response.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InteractionRecord {
+    /// RFC 3339 local time at which the utterance ended
+    pub timestamp: String,
+    /// `None` when no wake word preceded the utterance, as for follow-ups
+    pub wake_word_probability: Option<f32>,
+    pub utterance_seconds: f64,
+    pub segments: Vec<SegmentRecord>,
+    pub cleaned_text: String,
+    /// The parsed intent, tagged with its `type`
+    pub intent: Option<serde_json::Value>,
+    pub response: Option<String>,
+    pub errors: Vec<String>,
+    pub latency: StageLatency,
+    /// WAV file the utterance was saved to, when recordings are enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recording: Option<PathBuf>,
+}
+
+impl InteractionRecord {
+    pub fn new(wake_word_probability: Option<f32>, utterance_seconds: f64) -> Self {
+        Self {
+            timestamp: chrono::Local::now().to_rfc3339(),
+            wake_word_probability,
+            utterance_seconds,
+            segments: Vec::new(),
+            cleaned_text: String::new(),
+            intent: None,
+            response: None,
+            errors: Vec::new(),
+            latency: StageLatency::default(),
+            recording: None,
+        }
+    }
+
+    /// The `type` tag of the recorded intent, e.g. "set_timer".
+    pub fn intent_type(&self) -> Option<&str> {
+        self.intent.as_ref()?.get("type")?.as_str()
+    }
+
+    /// The transcript as whisper produced it, before cleaning.
+    pub fn transcript(&self) -> String {
+        self.segments
+            .iter()
+            .map(|segment| segment.text.as_str())
+            .collect::<String>()
+            .trim()
+            .to_string()
+    }
+}
+
+/// Milliseconds elapsed since `started`, for [`StageLatency`].
+pub fn elapsed_ms(started: Instant) -> u64 {
+    started.elapsed().as_millis() as u64
+}
+
+/// Appends interaction records to the history file, one JSON object per line. Does nothing when
+/// history is disabled in the config.
+pub struct HistoryLog {
+    file: Option<File>,
+}
+
+impl HistoryLog {
+    pub fn open(config: &HistoryConfig) -> Result<Self> {
+        if !config.enabled {
+            return Ok(Self { file: None });
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)
+            .wrap_err_with(|| format!("failed to open history file {}", config.path.display()))?;
+        Ok(Self { file: Some(file) })
+    }
+
+    pub fn append(&mut self, record: &InteractionRecord) -> Result<()> {
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        // One write per record so concurrent readers never see half a line
+        file.write_all(line.as_bytes())
+            .wrap_err("failed to write history record")?;
+        Ok(())
+    }
+}
+
+/// Which records the `history` subcommand prints.
+#[derive(Debug, Default)]
+pub struct HistoryFilter {
+    /// Case-insensitive text to look for in the transcript, cleaned text or response
+    pub contains: Option<String>,
+    /// Only records whose intent has this type
+    pub intent: Option<String>,
+    /// Only records with at least one error
+    pub errors_only: bool,
+}
+
+impl HistoryFilter {
+    fn matches(&self, record: &InteractionRecord) -> bool {
+        if self.errors_only && record.errors.is_empty() {
+            return false;
+        }
+        if let Some(intent) = &self.intent
+            && record.intent_type() != Some(intent.as_str())
+        {
+            return false;
+        }
+        if let Some(contains) = &self.contains {
+            let contains = contains.to_lowercase();
+            let haystacks = [
+                record.transcript(),
+                record.cleaned_text.clone(),
+                record.response.clone().unwrap_or_default(),
+            ];
+            if !haystacks
+                .iter()
+                .any(|text| text.to_lowercase().contains(&contains))
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+fn parse_line(line: &str, line_number: usize) -> Option<InteractionRecord> {
+    if line.trim().is_empty() {
+        return None;
+    }
+    match serde_json::from_str(line) {
+        Ok(record) => Some(record),
+        Err(e) => {
+            eprintln!("Skipping history line {}: {}", line_number, e);
+            None
+        }
+    }
+}
+
+fn print_record(record: &InteractionRecord, json: bool) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string(record)?);
+        return Ok(());
+    }
+
+    println!("{}", record.timestamp);
+    match record.wake_word_probability {
+        Some(probability) => println!(
+            "  wake word:  probability {:.2}, {:.2}s of speech",
+            probability, record.utterance_seconds
+        ),
+        None => println!(
+            "  wake word:  -, {:.2}s of speech",
+            record.utterance_seconds
+        ),
+    }
+    println!("  heard:      {:?}", record.transcript());
+    println!("  cleaned:    {:?}", record.cleaned_text);
+    if let Some(intent) = &record.intent {
+        println!("  intent:     {}", intent);
+    }
+    if let Some(response) = &record.response {
+        println!("  response:   {:?}", response);
+    }
+    for error in &record.errors {
+        println!("  error:      {}", error);
+    }
+    if let Some(recording) = &record.recording {
+        println!("  recording:  {}", recording.display());
+    }
+    let latency = &record.latency;
+    let stages = [
+        ("transcription", latency.transcription_ms),
+        ("intent", latency.intent_ms),
+        ("tts", latency.tts_ms),
+    ]
+    .iter()
+    .filter_map(|(name, ms)| ms.map(|ms| format!("{} {}ms", name, ms)))
+    .collect::<Vec<_>>();
+    if !stages.is_empty() {
+        println!("  latency:    {}", stages.join(", "));
+    }
+    Ok(())
+}
+
+/// Print the last `last` records of the history file matching `filter`, then keep printing new
+/// ones as they are appended if `follow` is set.
+pub fn show_history(
+    path: &Path,
+    filter: &HistoryFilter,
+    last: usize,
+    follow: bool,
+    json: bool,
+) -> Result<()> {
+    let file = File::open(path)
+        .wrap_err_with(|| format!("failed to open history file {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+
+    // Only the last `last` matches are kept, however long the file has grown
+    let mut matching = VecDeque::with_capacity(last);
+    let mut line_number = 0;
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 {
+        line_number += 1;
+        if let Some(record) = parse_line(&line, line_number)
+            && filter.matches(&record)
+            && last > 0
+        {
+            if matching.len() == last {
+                matching.pop_front();
+            }
+            matching.push_back(record);
+        }
+        line.clear();
+    }
+
+    for record in &matching {
+        print_record(record, json)?;
+    }
+
+    if !follow {
+        return Ok(());
+    }
+
+    // Poll for appended lines, only consuming complete ones so a record being written is read
+    // whole on the next pass
+    loop {
+        let position = reader.stream_position()?;
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || !line.ends_with('\n') {
+            reader.seek(SeekFrom::Start(position))?;
+            thread::sleep(FOLLOW_POLL_INTERVAL);
+            continue;
+        }
+        line_number += 1;
+        if let Some(record) = parse_line(&line, line_number)
+            && filter.matches(&record)
+        {
+            print_record(&record, json)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(cleaned_text: &str, intent_type: &str, errors: &[&str]) -> InteractionRecord {
+        let mut record = InteractionRecord::new(Some(0.9), 1.5);
+        record.segments = vec![SegmentRecord {
+            start_seconds: 0.0,
+            end_seconds: 1.5,
+            text: format!(" Alexa, {}.", cleaned_text),
+        }];
+        record.cleaned_text = cleaned_text.to_string();
+        record.intent = Some(serde_json::json!({ "type": intent_type }));
+        record.errors = errors.iter().map(|e| e.to_string()).collect();
+        record
+    }
+
+    #[test]
+    fn records_round_trip_through_the_log() {
+        let path = std::env::temp_dir().join(format!(
+            "voice-assistant-history-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let config = HistoryConfig {
+            enabled: true,
+            path: path.clone(),
+        };
+
+        let mut written = record("what time is it", "get_current_time", &[]);
+        written.latency.transcription_ms = Some(420);
+        let mut log = HistoryLog::open(&config).unwrap();
+        log.append(&written).unwrap();
+        log.append(&written).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines = contents.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(parse_line(lines[0], 1).unwrap(), written);
+        assert!(!lines[0].contains("tts_ms"));
+    }
+
+    #[test]
+    fn segment_timestamps_are_converted_to_seconds() {
+        let segment = SpeechSegment {
+            start_timestamp: 150,
+            end_timestamp: 325,
+            text: " Hello".to_string(),
+        };
+        let record = SegmentRecord::from(&segment);
+        assert_eq!(record.start_seconds, 1.5);
+        assert_eq!(record.end_seconds, 3.25);
+    }
+
+    #[test]
+    fn filter_matches_text_intent_and_errors() {
+        let ok = record("turn on the lights", "turn_on_light", &[]);
+        let failed = record(
+            "turn off the lights",
+            "turn_off_light",
+            &["command: timeout"],
+        );
+
+        let contains = HistoryFilter {
+            contains: Some("ALEXA, TURN ON".to_string()),
+            ..Default::default()
+        };
+        assert!(contains.matches(&ok));
+        assert!(!contains.matches(&failed));
+
+        let intent = HistoryFilter {
+            intent: Some("turn_off_light".to_string()),
+            ..Default::default()
+        };
+        assert!(!intent.matches(&ok));
+        assert!(intent.matches(&failed));
+
+        let errors = HistoryFilter {
+            errors_only: true,
+            ..Default::default()
+        };
+        assert!(!errors.matches(&ok));
+        assert!(errors.matches(&failed));
+    }
+}
@@ -0,0 +1,46 @@
+use clap::ValueEnum;
+use color_eyre::eyre::Result;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{EnvFilter, fmt};
+
+/// Filter used when `RUST_LOG` is not set.
+const DEFAULT_FILTER: &str = "info";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line, including the fields of every enclosing span
+    Json,
+}
+
+/// Install the global tracing subscriber. Levels come from `RUST_LOG` (e.g.
+/// `RUST_LOG=voice_assistant=debug,oww_rs=warn`). Records from crates using `log`, such as oww_rs,
+/// are forwarded too. Logs go to stderr so stdout stays free for command output.
+pub fn init(format: LogFormat) -> Result<()> {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+
+    // Closing spans log how long each stage of an interaction took
+    let fmt_layer = match format {
+        LogFormat::Text => fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_span_events(FmtSpan::CLOSE)
+            .boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_writer(std::io::stderr)
+            .with_span_events(FmtSpan::CLOSE)
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt_layer)
+        .try_init()?;
+
+    Ok(())
+}
@@ -1,331 +1,178 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::command_executor::{TimerEvent, TimerManager};
+use crate::audio_input::{InputEvent, RecoveryConfig, spawn_input_supervisor};
+use crate::audio_resampler::Downmix;
+use crate::command_executor::{CommandExecutorConfig, Intent, TimerEvent, TimerManager};
+use crate::config::{
+    AssistantConfig, ChannelMix, ConfigOverrides, EarconsConfig, Requirement, SttConfig,
+};
+use crate::control_api::{ApiCall, ApiError, ApiRequest, ApiResult};
+use crate::history::{HistoryFilter, HistoryLog, InteractionRecord, SegmentRecord, elapsed_ms};
+use crate::logging::LogFormat;
+use crate::metrics::METRICS;
+use crate::recordings::UtteranceRecorder;
 use crate::speech::{SpeechSegment, SpeechToTextClient};
-use crate::speech_listener::SpeechEvent;
-use crate::{speech_listener::create_stream, tts_client::TtsClient};
+use crate::speech_listener::{
+    PipelineControl, PlaybackState, SAMPLE_RATE, SpeechEvent, SpeechPipelineConfig,
+};
+use crate::speech_output::{OutputEvent, Priority, SpeechOutput, Utterance};
+use crate::tts_client::{Playback, TtsClient};
 use clap::Parser;
 use color_eyre::eyre::{OptionExt, Result};
-use cpal::traits::StreamTrait;
-use cpal::{
-    BufferSize, Device, SampleFormat, StreamConfig, SupportedStreamConfigRange,
-    traits::{DeviceTrait, HostTrait},
-};
-use std::sync::mpsc;
-use whisper_rs::{SamplingStrategy, WhisperContext, WhisperContextParameters};
-
+use cpal::traits::{DeviceTrait, HostTrait};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, mpsc};
+use tracing::{Span, debug, error, info, info_span, warn};
+use whisper_rs::{WhisperContext, WhisperContextParameters};
+
+mod audio_frontend;
+mod audio_input;
 mod audio_resampler;
 mod command_executor;
+mod config;
+mod control_api;
+mod history;
 pub(crate) mod human_format;
+mod logging;
+mod metrics;
+mod pcm;
+mod probe_device;
+mod recordings;
+mod satellite;
+mod simulate;
 mod speech;
 mod speech_listener;
+mod speech_output;
+mod speech_services;
+mod transcribe_file;
 mod tts_client;
+mod wyoming;
 use clap::Subcommand;
-use url::Url;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Log output format; levels are set with RUST_LOG
+    #[arg(long, global = true, env = "LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     RunVoiceAssistant {
-        #[arg(short, long, env = "HOME_ASSISTANT_BASE_URL")]
-        home_assistant_base_url: Url,
-
-        #[arg(short, long, env = "HOME_ASSISTANT_TOKEN")]
-        home_assistant_token: String,
-
-        #[arg(short, long, env = "INPUT_DEVICE_ID")]
-        input_device_id: String,
-
-        #[arg(short, long, env = "SILENCE_SECONDS", default_value = "1.0")]
-        silence_seconds: f64,
-
-        #[arg(
-            short,
-            long,
-            env = "ROLLING_BUFFER_DURATION_SECONDS",
-            default_value = "2.0"
-        )]
-        rolling_buffer_duration_seconds: f64,
-
-        #[arg(long, env = "WAKE_WORD_THRESHOLD", default_value = "0.2")]
-        wake_word_threshold: f32,
+        /// Path to a TOML config file; command line flags and env vars override its values
+        #[arg(short, long, env = "ASSISTANT_CONFIG")]
+        config: Option<PathBuf>,
 
-        #[arg(short, long, env = "WEATHER_LATITUDE")]
-        weather_latitude: Option<f64>,
-
-        #[arg(short, long, env = "WEATHER_LONGITUDE")]
-        weather_longitude: Option<f64>,
-
-        #[arg(long, env = "ALARM_VOLUME", default_value = "0.5")]
-        alarm_volume: f32,
+        #[command(flatten)]
+        overrides: Box<ConfigOverrides>,
     },
     GetInputDevices,
-}
-
-fn get_device(device_id: &str) -> Result<Device> {
-    let device_id = cpal::DeviceId::from_str(device_id)?;
-
-    // Get a completely fresh host each time to ensure clean state
-    // This is critical for ALSA devices that may get into bad states
-    let host = cpal::default_host();
-    let input_devices: Vec<Device> = host.input_devices()?.collect();
-
-    let device = input_devices
-        .into_iter()
-        .find(|d| d.id().ok().map(|id| id == device_id).unwrap_or(false))
-        .ok_or_eyre("No input device found")?;
-
-    // Verify device is accessible before returning
-    device.id()?;
-
-    Ok(device)
-}
-
-fn generate_candidate_configs(device_id: &str) -> Result<Vec<(StreamConfig, SampleFormat)>> {
-    // Get device fresh just to query supported configs, then drop it immediately
-    let device = get_device(device_id)?;
-    let supported_configs: Vec<SupportedStreamConfigRange> =
-        device.supported_input_configs()?.collect();
-    // Drop device immediately after querying configs to avoid holding onto it
-    drop(device);
-
-    for config in &supported_configs {
-        println!(
-            "config channels: {}, min sample rate: {}, max sample rate: {}, buffer size: {:?}, sample format: {:?}",
-            config.channels(),
-            config.min_sample_rate(),
-            config.max_sample_rate(),
-            config.buffer_size(),
-            config.sample_format(),
-        );
-    }
-
-    let mut candidates = Vec::new();
-
-    // Phase 1: Device-reported supported configs with reasonable sample rates
-    let preferred_rates = [48000, 44100, 32000, 16000, 8000];
+    /// Try every candidate stream config on a device and report which ones deliver audio
+    ProbeDevice {
+        /// Device id as printed by get-input-devices
+        device_id: String,
+
+        /// Seconds to capture with each candidate config
+        #[arg(long, default_value = "2.0")]
+        seconds: f64,
+
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Type commands on stdin and run them through the command executor, without a mic or whisper
+    Simulate {
+        /// Also speak responses and timer announcements through the TTS processor
+        #[arg(long)]
+        speak: bool,
 
-    // Try F32 format first (preferred since all downstream systems use it)
-    if let Some(f32_config) = supported_configs
-        .iter()
-        .find(|config| config.sample_format() == SampleFormat::F32)
-    {
-        let min_rate = f32_config.min_sample_rate();
-        let max_rate = f32_config.max_sample_rate();
-        let channels = f32_config.channels();
-
-        println!("F32 config found - min: {}, max: {}", min_rate, max_rate);
-
-        // Try each preferred rate that's within range and valid
-        for &rate in &preferred_rates {
-            // Skip invalid rates
-            if rate == u32::MAX || rate == 0 {
-                continue;
-            }
+        #[arg(short, long, env = "ASSISTANT_CONFIG")]
+        config: Option<PathBuf>,
 
-            // Only include rates within device's reported range (if max_rate is valid)
-            if max_rate != u32::MAX && max_rate != 0 {
-                if rate < min_rate || rate > max_rate {
-                    continue;
-                }
-            }
+        #[command(flatten)]
+        overrides: Box<ConfigOverrides>,
+    },
+    /// Run a WAV file through the wake word, VAD and whisper pipeline and print each utterance
+    TranscribeFile {
+        /// WAV file to process, any sample rate and channel count
+        file: PathBuf,
 
-            // Validate rate is in reasonable range for resampling (4000-96000 Hz)
-            if rate < 4000 || rate > 96000 {
-                continue;
-            }
+        /// Frames handed to the pipeline per simulated audio callback
+        #[arg(long, default_value = "1024")]
+        block_frames: usize,
 
-            candidates.push((
-                StreamConfig {
-                    channels,
-                    sample_rate: rate,
-                    buffer_size: BufferSize::Default,
-                },
-                SampleFormat::F32,
-            ));
-        }
-    }
+        #[arg(short, long, env = "ASSISTANT_CONFIG")]
+        config: Option<PathBuf>,
 
-    // Try I16 format as fallback
-    if let Some(i16_config) = supported_configs
-        .iter()
-        .find(|config| config.sample_format() == SampleFormat::I16)
-    {
-        let min_rate = i16_config.min_sample_rate();
-        let max_rate = i16_config.max_sample_rate();
-        let channels = i16_config.channels();
-
-        println!("I16 config found - min: {}, max: {}", min_rate, max_rate);
-
-        // Try each preferred rate that's within range and valid
-        for &rate in &preferred_rates {
-            // Skip invalid rates
-            if rate == u32::MAX || rate == 0 {
-                continue;
-            }
+        #[command(flatten)]
+        overrides: Box<ConfigOverrides>,
+    },
+    /// Print recent interactions from the history log, newest last
+    History {
+        /// Number of matching interactions to print
+        #[arg(long, default_value = "20")]
+        last: usize,
 
-            // Only include rates within device's reported range (if max_rate is valid)
-            if max_rate != u32::MAX && max_rate != 0 {
-                if rate < min_rate || rate > max_rate {
-                    continue;
-                }
-            }
+        /// Only interactions whose transcript, cleaned text or response contains this text
+        #[arg(long)]
+        contains: Option<String>,
 
-            // Validate rate is in reasonable range for resampling (4000-96000 Hz)
-            if rate < 4000 || rate > 96000 {
-                continue;
-            }
+        /// Only interactions whose parsed intent has this type, e.g. set_timer
+        #[arg(long)]
+        intent: Option<String>,
 
-            candidates.push((
-                StreamConfig {
-                    channels,
-                    sample_rate: rate,
-                    buffer_size: BufferSize::Default,
-                },
-                SampleFormat::I16,
-            ));
-        }
-    }
+        /// Only interactions that recorded an error
+        #[arg(long)]
+        errors: bool,
 
-    // Phase 2: Hardcoded fallbacks (if we have no candidates yet, or as additional fallbacks)
-    let hardcoded_fallbacks = vec![
-        (16000, SampleFormat::F32),
-        (16000, SampleFormat::I16),
-        (8000, SampleFormat::F32),
-        (8000, SampleFormat::I16),
-        (48000, SampleFormat::F32),
-    ];
-
-    for (rate, format) in hardcoded_fallbacks {
-        // Only add if not already in candidates
-        if !candidates
-            .iter()
-            .any(|(cfg, fmt)| cfg.sample_rate == rate && *fmt == format)
-        {
-            candidates.push((
-                StreamConfig {
-                    channels: 1, // Mono
-                    sample_rate: rate,
-                    buffer_size: BufferSize::Default,
-                },
-                format,
-            ));
-        }
-    }
+        /// Keep printing interactions as they are appended
+        #[arg(short, long)]
+        follow: bool,
 
-    println!("Generated {} candidate configurations", candidates.len());
+        /// Print the raw JSON records
+        #[arg(long)]
+        json: bool,
 
-    Ok(candidates)
-}
+        #[arg(short, long, env = "ASSISTANT_CONFIG")]
+        config: Option<PathBuf>,
 
-fn try_create_stream(
-    device_id: &str,
-    candidates: Vec<(StreamConfig, SampleFormat)>,
-    wake_word_threshold: f32,
-    vad_threshold: f32,
-    silence_seconds: f64,
-    rolling_buffer_duration_seconds: f64,
-) -> Result<(
-    cpal::Stream,
-    mpsc::Receiver<SpeechEvent>,
-    StreamConfig,
-    SampleFormat,
-)> {
-    let mut errors = Vec::new();
-
-    for (config, sample_format) in candidates {
-        println!(
-            "Trying {:?} @ {}Hz ({} channel{})...",
-            sample_format,
-            config.sample_rate,
-            config.channels,
-            if config.channels == 1 { "" } else { "s" }
-        );
-
-        // Get device fresh for each attempt since create_stream takes ownership
-        // Verify device is still available before attempting to use it
-        let device = match get_device(device_id) {
-            Ok(d) => {
-                // Verify device is still accessible by checking its name
-                if let Err(e) = d.id() {
-                    let error_msg = format!("  Error: Device no longer accessible - {}", e);
-                    println!("{}", error_msg);
-                    errors.push(error_msg);
-                    thread::sleep(Duration::from_millis(200));
-                    continue;
-                }
-                d
-            }
-            Err(e) => {
-                let error_msg = format!("  Error: Failed to get device - {}", e);
-                println!("{}", error_msg);
-                errors.push(error_msg);
-                thread::sleep(Duration::from_millis(200));
-                continue;
-            }
-        };
-
-        // Immediately attempt to create stream - don't query the device further
-        // as additional queries might invalidate it or put it in a bad state
-        // Attempt to create stream in a scope to ensure proper cleanup on failure
-        let result = {
-            // Create stream - if this fails, device will be dropped automatically
-            create_stream(
-                device,
-                config.clone(),
-                sample_format,
-                wake_word_threshold,
-                vad_threshold,
-                silence_seconds,
-                rolling_buffer_duration_seconds,
-            )
-        };
-
-        match result {
-            Ok((stream, channel_rx)) => {
-                println!(
-                    "  Success! Using {:?} @ {}Hz ({} channel{})",
-                    sample_format,
-                    config.sample_rate,
-                    config.channels,
-                    if config.channels == 1 { "" } else { "s" }
-                );
-                return Ok((stream, channel_rx, config, sample_format));
-            }
-            Err(e) => {
-                let error_msg = format!(
-                    "  Error: {:?} @ {}Hz ({} channel{}) - {}",
-                    sample_format,
-                    config.sample_rate,
-                    config.channels,
-                    if config.channels == 1 { "" } else { "s" },
-                    e
-                );
-                println!("{}", error_msg);
-                errors.push(error_msg);
-
-                // Give the device/host time to recover before trying the next configuration
-                // ALSA devices may need time to release resources after a failed attempt
-                // The device and any partial streams are automatically dropped when we exit this scope
-                thread::sleep(Duration::from_millis(200));
-            }
-        }
-    }
+        #[command(flatten)]
+        overrides: Box<ConfigOverrides>,
+    },
+    /// Serve the wake word, speech to text and text to speech to Home Assistant over the Wyoming
+    /// protocol
+    WyomingServer {
+        #[arg(short, long, env = "ASSISTANT_CONFIG")]
+        config: Option<PathBuf>,
+
+        #[command(flatten)]
+        overrides: Box<ConfigOverrides>,
+    },
+    /// Listen for the wake word and speech locally and send each utterance to a satellite server,
+    /// playing back its spoken response
+    Satellite {
+        #[arg(short, long, env = "ASSISTANT_CONFIG")]
+        config: Option<PathBuf>,
+
+        #[command(flatten)]
+        overrides: Box<ConfigOverrides>,
+    },
+    /// Transcribe, run and answer utterances streamed from satellites
+    SatelliteServer {
+        #[arg(short, long, env = "ASSISTANT_CONFIG")]
+        config: Option<PathBuf>,
 
-    // All configurations failed
-    Err(color_eyre::eyre::eyre!(
-        "Failed to create audio stream with any configuration. Attempted {} configurations:\n{}",
-        errors.len(),
-        errors.join("\n")
-    ))
+        #[command(flatten)]
+        overrides: Box<ConfigOverrides>,
+    },
 }
 
 fn clean_text_segments(segments: Vec<SpeechSegment>, voice_activation_text: &str) -> String {
@@ -336,7 +183,13 @@ fn clean_text_segments(segments: Vec<SpeechSegment>, voice_activation_text: &str
         }
         text
     };
-    let full_text = full_text.trim().to_lowercase();
+    clean_text(&full_text, voice_activation_text)
+}
+
+/// Lowercase `text`, drop punctuation and everything up to the wake word, as the command grammar
+/// expects.
+fn clean_text(text: &str, voice_activation_text: &str) -> String {
+    let full_text = text.trim().to_lowercase();
     // Only keep alphanumeric characters and spaces; collapse multiple spaces
     let cleaned: String = full_text
         .chars()
@@ -356,38 +209,116 @@ fn clean_text_segments(segments: Vec<SpeechSegment>, voice_activation_text: &str
     }
 }
 
+/// `playback` tells the pipeline when the assistant is talking and `control` lets the event loop
+/// steer it; pass defaults when nothing is played back.
+fn speech_pipeline_config(
+    config: &AssistantConfig,
+    playback: Arc<PlaybackState>,
+    control: Arc<PipelineControl>,
+) -> SpeechPipelineConfig {
+    SpeechPipelineConfig {
+        wake_word_model_path: config.wake_word.model_path.clone(),
+        wake_word_threshold: config.wake_word.threshold,
+        vad_threshold: config.audio.vad_threshold,
+        silence_seconds: config.audio.silence_seconds,
+        no_speech_timeout: Duration::from_secs_f64(config.audio.no_speech_timeout_seconds),
+        max_utterance: Duration::from_secs_f64(config.audio.max_utterance_seconds),
+        rolling_buffer_duration_seconds: config.audio.rolling_buffer_duration_seconds,
+        playback,
+        while_speaking: config.wake_word.while_speaking,
+        speaking_threshold: config.wake_word.speaking_threshold,
+        speaking_hangover: Duration::from_secs_f64(config.wake_word.speaking_hangover_seconds),
+        control,
+        downmix: match config.audio.channel_mix {
+            ChannelMix::Average => Downmix::Average,
+            ChannelMix::Channel => Downmix::Channel(config.audio.channel),
+            ChannelMix::Loudest => Downmix::Loudest,
+        },
+        resampler_quality: config.audio.resampler_quality,
+    }
+}
+
+fn create_speech_to_text_client(config: &SttConfig) -> Result<SpeechToTextClient> {
+    let model_path = config
+        .model_path
+        .to_str()
+        .ok_or_eyre("Failed to convert whisper model path to string")?;
+    let ctx = WhisperContext::new_with_params(model_path, WhisperContextParameters::default())
+        .map_err(|e| color_eyre::eyre::eyre!("failed to load model: {}", e))?;
+    SpeechToTextClient::new(ctx, config)
+}
+
+fn create_command_executor_config(config: &AssistantConfig) -> Result<CommandExecutorConfig> {
+    let home_assistant_base_url = config
+        .home_assistant
+        .base_url
+        .clone()
+        .ok_or_eyre("No Home Assistant base URL configured")?;
+    let home_assistant_token = config
+        .home_assistant
+        .token
+        .clone()
+        .ok_or_eyre("No Home Assistant token configured")?;
+    Ok(CommandExecutorConfig::new(
+        home_assistant_base_url,
+        home_assistant_token,
+        config.weather.latitude,
+        config.weather.longitude,
+    ))
+}
+
+/// Exit status when a second signal cuts shutdown short, following the 128 + SIGINT convention.
+const EXIT_INTERRUPTED: i32 = 130;
+
 enum AppEvent {
     Speech(SpeechEvent),
     TimerFired(TimerEvent),
+    AudioInputLost(String),
+    AudioInputRecovered,
+    Output(OutputEvent),
+    /// A call to the HTTP control API
+    Api(ApiRequest),
+    /// SIGINT or SIGTERM received
+    Shutdown,
 }
 
-struct VoiceAssistantConfig {
-    pub home_assistant_base_url: Url,
-    pub home_assistant_token: String,
-    pub input_device_id: String,
-    pub silence_seconds: f64,
-    pub rolling_buffer_duration_seconds: f64,
-    pub wake_word_threshold: f32,
-    pub weather_latitude: Option<f64>,
-    pub weather_longitude: Option<f64>,
-    pub alarm_volume: f32,
+/// History is a diagnostic aid, so failing to write it is logged rather than stopping the
+/// assistant.
+fn append_history(history: &mut HistoryLog, record: &InteractionRecord) {
+    if let Err(e) = history.append(record) {
+        warn!(error = %e, "failed to write interaction history");
+    }
 }
 
-fn run_voice_assistant(voice_assistant_config: VoiceAssistantConfig) -> Result<()> {
-    let mut tts_client = TtsClient::new()?;
-    let ctx = WhisperContext::new_with_params(
-        "./whisper_model/ggml-tiny.bin",
-        WhisperContextParameters::default(),
-    )
-    .map_err(|e| color_eyre::eyre::eyre!("failed to load model: {}", e))?;
-    let sampling_strategy = SamplingStrategy::BeamSearch {
-        beam_size: 5,
-        patience: -1.0,
-    };
-    let speech_to_text_client = SpeechToTextClient::new(ctx, sampling_strategy)?;
+fn play_earcon(speech_output: &SpeechOutput, sound: Option<&Path>) {
+    if let Some(sound) = sound {
+        speech_output.play_sound(sound);
+    }
+}
+
+fn run_voice_assistant(config: AssistantConfig) -> Result<()> {
+    let input_device_id = config
+        .audio
+        .input_device_id
+        .clone()
+        .ok_or_eyre("No input device configured")?;
+
+    let history = HistoryLog::open(&config.history)?;
+    let recorder = UtteranceRecorder::new(&config.recordings)?;
+
+    let started = Instant::now();
+    let mut tts_client = TtsClient::new(&config.tts.model_path, &config.tts.voice_path)?;
+    let playback = Arc::new(PlaybackState::default());
+    tts_client.report_playback(playback.clone());
+    let pipeline_control = Arc::new(PipelineControl::default());
+    let follow_up_window = config
+        .follow_up
+        .enabled
+        .then(|| Duration::from_secs_f64(config.follow_up.window_seconds));
+    let speech_to_text_client = create_speech_to_text_client(&config.stt)?;
 
     // Print device list once
-    let device_id = cpal::DeviceId::from_str(&voice_assistant_config.input_device_id)?;
+    let device_id = cpal::DeviceId::from_str(&input_device_id)?;
     let host = cpal::default_host();
     let input_devices = host.input_devices()?.collect::<Vec<_>>();
     let input_devices_names = input_devices
@@ -396,7 +327,7 @@ fn run_voice_assistant(voice_assistant_config: VoiceAssistantConfig) -> Result<(
             Ok((d.id()?.to_string(), d.description()?.to_string()))
         })
         .collect::<Result<Vec<_>>>()?;
-    println!("Input devices: {:#?}", input_devices_names);
+    info!(devices = ?input_devices_names, "input devices");
 
     // Verify device exists
     let device_name = input_devices
@@ -404,42 +335,69 @@ fn run_voice_assistant(voice_assistant_config: VoiceAssistantConfig) -> Result<(
         .find(|d| d.id().ok().map(|id| id == device_id).unwrap_or(false))
         .and_then(|d| d.id().ok().map(|id| id.1))
         .ok_or_eyre("No input device found")?;
-    println!("Using input device: {}", device_name);
-
-    // Generate candidate configs (gets device internally and drops it immediately)
-    let candidates = generate_candidate_configs(&voice_assistant_config.input_device_id)?;
-
-    // Give the system time to fully release the device after querying configs
-    // This is especially important for ALSA devices
-    thread::sleep(Duration::from_millis(300));
-
-    // Try each candidate until one works
-    let (stream, channel_rx, _config, _sample_format) = try_create_stream(
-        &voice_assistant_config.input_device_id,
-        candidates,
-        voice_assistant_config.wake_word_threshold,
-        0.75,
-        voice_assistant_config.silence_seconds,
-        voice_assistant_config.rolling_buffer_duration_seconds,
-    )?;
+    info!(device = %device_name, "using input device");
 
-    stream.play()?;
+    let command_executor_config = create_command_executor_config(&config)?;
 
-    let command_executor_config = command_executor::CommandExecutorConfig::new(
-        voice_assistant_config.home_assistant_base_url,
-        voice_assistant_config.home_assistant_token,
-        voice_assistant_config.weather_latitude,
-        voice_assistant_config.weather_longitude,
-    );
+    if let Some(address) = config.metrics.listen_address {
+        metrics::spawn_server(address)?;
+    }
 
     // Create unified event channel
     let (app_tx, app_rx) = mpsc::channel::<AppEvent>();
 
-    // Forward speech events to unified channel
-    let speech_tx = app_tx.clone();
+    // The first signal asks the event loop to wind down once the current interaction is over,
+    // a second one exits straight away
+    let shutdown_requested = Arc::new(AtomicBool::new(false));
+    let signal_app_tx = app_tx.clone();
+    let signal_shutdown_requested = shutdown_requested.clone();
+    ctrlc::set_handler(move || {
+        if signal_shutdown_requested.swap(true, Ordering::SeqCst) {
+            warn!("second signal received, exiting immediately");
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+        info!("signal received, shutting down");
+        let _ = signal_app_tx.send(AppEvent::Shutdown);
+    })?;
+
+    // Speech is generated and played on its own thread, so the loop below never waits for it
+    let (output_tx, output_rx) = mpsc::channel::<OutputEvent>();
+    let speech_output = SpeechOutput::spawn(tts_client, output_tx);
+    let output_app_tx = app_tx.clone();
     thread::spawn(move || {
-        for event in channel_rx {
-            if speech_tx.send(AppEvent::Speech(event)).is_err() {
+        for event in output_rx {
+            if output_app_tx.send(AppEvent::Output(event)).is_err() {
+                break;
+            }
+        }
+    });
+
+    // The supervisor owns the stream and reopens it whenever the device disappears or stalls
+    let (input_tx, input_rx) = mpsc::channel::<InputEvent>();
+    let input_supervisor = spawn_input_supervisor(
+        input_device_id,
+        speech_pipeline_config(&config, playback.clone(), pipeline_control.clone()),
+        RecoveryConfig::from_audio_config(&config.audio),
+        input_tx,
+    )?;
+
+    // Forward audio input events to unified channel. The wake word cuts off the assistant here,
+    // before the event reaches the back of the queue
+    let input_app_tx = app_tx.clone();
+    let barge_in = config.tts.barge_in.then(|| speech_output.barge_in());
+    thread::spawn(move || {
+        for event in input_rx {
+            if let (Some(barge_in), InputEvent::Speech(SpeechEvent::WakeWordDetected { .. })) =
+                (&barge_in, &event)
+            {
+                barge_in.barge_in();
+            }
+            let event = match event {
+                InputEvent::Speech(event) => AppEvent::Speech(event),
+                InputEvent::Lost(reason) => AppEvent::AudioInputLost(reason),
+                InputEvent::Recovered => AppEvent::AudioInputRecovered,
+            };
+            if input_app_tx.send(event).is_err() {
                 break;
             }
         }
@@ -448,6 +406,11 @@ fn run_voice_assistant(voice_assistant_config: VoiceAssistantConfig) -> Result<(
     // Create timer manager with sender for timer events
     let (timer_tx, timer_rx) = mpsc::channel::<TimerEvent>();
     let timer_manager = TimerManager::new(timer_tx);
+    match timer_manager.restore(&config.timers.state_path) {
+        Ok(0) => {}
+        Ok(count) => info!(count, "restored timers from the previous run"),
+        Err(e) => warn!(error = %e, "failed to restore timers"),
+    }
 
     // Forward timer events to unified channel
     let timer_app_tx = app_tx.clone();
@@ -459,50 +422,379 @@ fn run_voice_assistant(voice_assistant_config: VoiceAssistantConfig) -> Result<(
         }
     });
 
-    let alarm_volume = voice_assistant_config.alarm_volume;
+    if let Some(address) = config.api.listen_address {
+        let (api_tx, api_rx) = mpsc::channel::<ApiRequest>();
+        control_api::spawn_server(address, config.api.token.clone(), api_tx)?;
+        let api_app_tx = app_tx.clone();
+        thread::spawn(move || {
+            for request in api_rx {
+                if api_app_tx.send(AppEvent::Api(request)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    let mut assistant = Assistant {
+        started,
+        alarm_volume: config.timers.alarm_volume,
+        follow_up_window,
+        earcons: config.earcons.enabled.then_some(&config.earcons),
+        voice_activation_text: config.wake_word.activation_text.to_lowercase(),
+        speech_output,
+        speech_to_text_client,
+        command_executor_config,
+        timer_manager,
+        pipeline_control,
+        playback,
+        recorder,
+        history,
+        unspoken: HashMap::new(),
+        interactions: 0,
+        interaction: None,
+        audio_input_lost: false,
+    };
+
+    info!(
+        activation_text = %assistant.voice_activation_text,
+        "listening for speech"
+    );
+    assistant.speech_output.say(Utterance::new(
+        "Listening for speech...",
+        Priority::Announcement,
+    ));
+
+    // Whichever way the loop ends, the cleanup below runs, so timers survive an error exit too
+    let result = run_event_loop(&mut assistant, app_rx, &shutdown_requested);
+
+    // Stop listening first so nothing new starts while the rest shuts down
+    input_supervisor.shutdown();
+    let Assistant {
+        speech_output,
+        speech_to_text_client,
+        timer_manager,
+        mut history,
+        unspoken,
+        ..
+    } = assistant;
+    if let Err(e) = speech_to_text_client.stop() {
+        error!(error = %e, "failed to stop speech to text");
+    }
+
+    match timer_manager.save(&config.timers.state_path) {
+        Ok(0) => {}
+        Ok(count) => info!(count, path = %config.timers.state_path.display(), "saved timers"),
+        Err(e) => error!(error = %e, "failed to save timers"),
+    }
+
+    // Let the sentence being spoken finish, then close the TTS processor
+    speech_output.shutdown();
+    for record in unspoken.into_values() {
+        append_history(&mut history, &record);
+    }
+    info!("shutdown complete");
+
+    result
+}
 
-    println!("Listening for speech... say alexa to start");
-    tts_client.generate_audio("Listening for speech...".to_string())?;
-    let voice_activation_text = "alexa";
+/// Everything the event loop works with, set up by [`run_voice_assistant`] and taken apart again
+/// for shutdown.
+struct Assistant<'a> {
+    started: Instant,
+    alarm_volume: f32,
+    follow_up_window: Option<Duration>,
+    earcons: Option<&'a EarconsConfig>,
+    voice_activation_text: String,
+    speech_output: SpeechOutput,
+    speech_to_text_client: SpeechToTextClient,
+    command_executor_config: CommandExecutorConfig,
+    timer_manager: TimerManager,
+    pipeline_control: Arc<PipelineControl>,
+    playback: Arc<PlaybackState>,
+    recorder: Option<UtteranceRecorder>,
+    history: HistoryLog,
+    // Every wake word opens an interaction span that stays open until the response is spoken,
+    // so all stages of one utterance share an id in the logs. History records wait here until
+    // their response has been spoken.
+    unspoken: HashMap<u64, InteractionRecord>,
+    interactions: u64,
+    interaction: Option<(u64, Span, f32)>,
+    audio_input_lost: bool,
+}
+
+/// Handle events until shutdown is requested or something fails that the assistant cannot carry
+/// on without.
+fn run_event_loop(
+    assistant: &mut Assistant,
+    app_rx: mpsc::Receiver<AppEvent>,
+    shutdown_requested: &AtomicBool,
+) -> Result<()> {
+    let &mut Assistant {
+        started,
+        alarm_volume,
+        follow_up_window,
+        earcons,
+        ref voice_activation_text,
+        ref speech_output,
+        ref speech_to_text_client,
+        ref command_executor_config,
+        ref timer_manager,
+        ref pipeline_control,
+        ref playback,
+        ref recorder,
+        ref mut history,
+        ref mut unspoken,
+        ref mut interactions,
+        ref mut interaction,
+        ref mut audio_input_lost,
+    } = assistant;
     for event in app_rx {
+        // Events queued behind the signal are dropped rather than handled during shutdown
+        if shutdown_requested.load(Ordering::SeqCst) {
+            break;
+        }
         match event {
-            AppEvent::Speech(SpeechEvent::SpeechDetected(audio)) => {
-                let segments = speech_to_text_client.process(audio)?;
+            AppEvent::Speech(SpeechEvent::WakeWordDetected { probability }) => {
+                *interactions += 1;
+                METRICS.wake_word_detections.inc();
+                let span = info_span!("interaction", id = *interactions);
+                span.in_scope(|| info!(probability, "wake word detected"));
+                play_earcon(speech_output, earcons.map(|e| e.wake.as_path()));
+                *interaction = Some((*interactions, span, probability));
+            }
+            AppEvent::Speech(
+                SpeechEvent::SpeechDetected(audio) | SpeechEvent::MaxUtteranceReached(audio),
+            ) => {
+                let (id, span, wake_word_probability) = match interaction.take() {
+                    Some((id, span, probability)) => (id, span, Some(probability)),
+                    None => {
+                        *interactions += 1;
+                        let span = info_span!("interaction", id = *interactions);
+                        (*interactions, span, None)
+                    }
+                };
+                let _interaction = span.enter();
+                play_earcon(speech_output, earcons.map(|e| e.end_of_listening.as_path()));
+                // Speech held back by barge-in can play now that the user is done
+                speech_output.resume();
+                let utterance_seconds = audio.len() as f64 / SAMPLE_RATE as f64;
+                info!(duration_seconds = utterance_seconds, "speech ended");
+                let mut record = InteractionRecord::new(wake_word_probability, utterance_seconds);
+                let recording = recorder.as_ref().and_then(|recorder| {
+                    recorder
+                        .save(&audio, wake_word_probability)
+                        .inspect_err(|e| warn!(error = %e, "failed to save recording"))
+                        .ok()
+                });
+                record.recording = recording.as_ref().map(|r| r.wav_path.clone());
+
+                let started = Instant::now();
+                let segments = info_span!("transcription").in_scope(|| {
+                    let _timer = METRICS.whisper_transcription_seconds.start_timer();
+                    speech_to_text_client.process(audio)
+                });
+                record.latency.transcription_ms = Some(elapsed_ms(started));
+                let segments = match segments {
+                    Ok(segments) => segments,
+                    Err(e) => {
+                        error!(error = %e, "transcription failed");
+                        record.errors.push(format!("transcription: {}", e));
+                        play_earcon(speech_output, earcons.map(|e| e.error.as_path()));
+                        let response_text = "Something went wrong. Please try again.";
+                        record.response = Some(response_text.to_string());
+                        unspoken.insert(id, record);
+                        speech_output.say(Utterance {
+                            interaction: Some(id),
+                            ..Utterance::new(response_text, Priority::Response)
+                        });
+                        continue;
+                    }
+                };
+                record.segments = segments.iter().map(SegmentRecord::from).collect();
+                if let Some(recording) = &recording
+                    && let Err(e) = recording.write_transcript(&record.transcript())
+                {
+                    warn!(error = %e, "failed to save transcript");
+                }
                 let cleaned_text = clean_text_segments(segments, voice_activation_text);
-                match command_executor::execute_command(
-                    &command_executor_config,
-                    &timer_manager,
+                info!(text = %cleaned_text, "transcribed");
+                record.cleaned_text = cleaned_text.clone();
+
+                let started = Instant::now();
+                let intent = command_executor::parse_intent(&cleaned_text);
+                record.intent = serde_json::to_value(&intent).ok();
+                if intent == Intent::Unknown {
+                    play_earcon(speech_output, earcons.map(|e| e.not_understood.as_path()));
+                }
+                let response_text = match command_executor::execute_intent(
+                    command_executor_config,
+                    timer_manager,
+                    intent,
                     &cleaned_text,
                 ) {
-                    Ok(response_text) => {
-                        tts_client.generate_audio(response_text)?;
-                    }
+                    Ok(response_text) => response_text,
                     Err(e) => {
-                        println!("Error executing command: {}", e);
-                        tts_client.generate_audio(
-                            "Something went wrong. Please try again.".to_string(),
-                        )?;
+                        error!(error = %e, "error executing command");
+                        record.errors.push(format!("command: {}", e));
+                        play_earcon(speech_output, earcons.map(|e| e.error.as_path()));
+                        "Something went wrong. Please try again.".to_string()
                     }
+                };
+                record.latency.intent_ms = Some(elapsed_ms(started));
+                info!(response = %response_text, "responding");
+                record.response = Some(response_text.clone());
+
+                unspoken.insert(id, record);
+                speech_output.say(Utterance {
+                    interaction: Some(id),
+                    ..Utterance::new(response_text, Priority::Response)
+                });
+            }
+            AppEvent::Speech(SpeechEvent::NoSpeech) => {
+                // Nothing to answer, so the interaction just ends without a response
+                match interaction.take() {
+                    Some((_, span, _)) => span.in_scope(|| info!("no speech, cancelled")),
+                    None => info!("no speech, cancelled"),
                 }
+                speech_output.resume();
+            }
+            AppEvent::Speech(SpeechEvent::FollowUpExpired) => {
+                debug!("no follow-up, waiting for the wake word");
+                play_earcon(speech_output, earcons.map(|e| e.end_of_listening.as_path()));
             }
             AppEvent::TimerFired(timer_event) => {
-                let message = match timer_event.name {
-                    Some(name) => format!("Timer {} is done.", name),
-                    None => "Your timer is done.".to_string(),
+                let message = timer_event.announcement();
+                let _span = info_span!("timer_announcement").entered();
+                info!(message = %message, "timer fired");
+                speech_output.say(Utterance {
+                    volume: alarm_volume,
+                    ..Utterance::new(message, Priority::Alarm)
+                });
+            }
+            AppEvent::AudioInputLost(reason) => {
+                warn!(%reason, "lost audio input, waiting for it to come back");
+                *audio_input_lost = true;
+                // The utterance being listened to will never end now
+                speech_output.resume();
+            }
+            AppEvent::AudioInputRecovered => {
+                info!("audio input recovered");
+                *audio_input_lost = false;
+                speech_output.say(Utterance::new(
+                    "Microphone reconnected.",
+                    Priority::Announcement,
+                ));
+            }
+            AppEvent::Output(OutputEvent::Spoken {
+                interaction,
+                playback,
+                duration,
+            }) => {
+                let Some(id) = interaction else {
+                    continue;
                 };
-                println!("Timer fired: {}", message);
-                tts_client.set_volume(alarm_volume)?;
-                tts_client.generate_audio(message)?;
-                tts_client.set_volume(1.0)?;
+                if let Some(mut record) = unspoken.remove(&id) {
+                    record.latency.tts_ms = Some(duration.as_millis() as u64);
+                    append_history(history, &record);
+                }
+                match playback {
+                    Playback::Interrupted => info!(id, "response cut off by the wake word"),
+                    Playback::Finished => {
+                        if let Some(window) = follow_up_window {
+                            pipeline_control.request_follow_up(window);
+                        }
+                    }
+                }
             }
+            AppEvent::Output(OutputEvent::Failed {
+                interaction,
+                error,
+                processor_exited,
+            }) => {
+                error!(%error, interaction, "speech output failed");
+                if let Some(mut record) = interaction.and_then(|id| unspoken.remove(&id)) {
+                    record.errors.push(format!("tts: {}", error));
+                    append_history(history, &record);
+                }
+                // Without the processor nothing can be said any more, so shut down
+                if processor_exited {
+                    return Err(color_eyre::eyre::eyre!("TTS processor exited: {}", error));
+                }
+            }
+            AppEvent::Api(ApiRequest { call, responder }) => {
+                let status = || {
+                    serde_json::json!({
+                        "uptime_seconds": started.elapsed().as_secs(),
+                        "audio_input": if *audio_input_lost { "lost" } else { "ok" },
+                        "speaking": playback.is_playing(),
+                        "interactions": *interactions,
+                        "timers": timer_manager.list_timers().len(),
+                    })
+                };
+                responder.respond(handle_api_call(
+                    call,
+                    command_executor_config,
+                    timer_manager,
+                    speech_output,
+                    voice_activation_text,
+                    status,
+                ));
+            }
+            AppEvent::Shutdown => break,
         }
     }
-
-    tts_client.wait_until_finished()?;
-
     Ok(())
 }
 
+/// Answer a control API call from the event loop. Commands run like spoken ones but are left out
+/// of the history, which records what the microphone heard.
+fn handle_api_call(
+    call: ApiCall,
+    command_executor_config: &CommandExecutorConfig,
+    timer_manager: &TimerManager,
+    speech_output: &SpeechOutput,
+    voice_activation_text: &str,
+    status: impl FnOnce() -> serde_json::Value,
+) -> ApiResult {
+    match call {
+        ApiCall::Command { text, speak } => {
+            let _span = info_span!("api_command").entered();
+            let cleaned_text = clean_text(&text, voice_activation_text);
+            let intent = command_executor::parse_intent(&cleaned_text);
+            let intent_json = serde_json::to_value(&intent).ok();
+            let response = command_executor::execute_intent(
+                command_executor_config,
+                timer_manager,
+                intent,
+                &cleaned_text,
+            )
+            .map_err(|e| {
+                error!(error = %e, "error executing command");
+                ApiError::internal(e.to_string())
+            })?;
+            info!(text = %cleaned_text, response = %response, "ran command from the API");
+            if speak {
+                speech_output.say(Utterance::new(response.clone(), Priority::Response));
+            }
+            Ok(serde_json::json!({
+                "text": cleaned_text,
+                "intent": intent_json,
+                "response": response,
+            }))
+        }
+        ApiCall::Say(utterance) => {
+            info!(text = %utterance.text, priority = ?utterance.priority, "announcing");
+            speech_output.say(utterance);
+            Ok(serde_json::json!({ "queued": true }))
+        }
+        ApiCall::ListTimers => Ok(serde_json::json!(timer_manager.list_timers())),
+        ApiCall::CancelTimer(id) => timer_manager
+            .cancel_timer(id)
+            .map(|response| serde_json::json!({ "response": response }))
+            .ok_or_else(|| ApiError::not_found(format!("no timer with id {}", id))),
+        ApiCall::Status => Ok(status()),
+    }
+}
+
 fn get_input_devices() -> Result<()> {
     let host = cpal::default_host();
     let input_devices = host.input_devices()?.collect::<Vec<_>>();
@@ -515,28 +807,91 @@ fn get_input_devices() -> Result<()> {
 
 fn main() -> Result<()> {
     let args = Cli::parse();
+    logging::init(args.log_format)?;
     match args.command {
-        Commands::RunVoiceAssistant {
-            home_assistant_base_url,
-            home_assistant_token,
-            input_device_id,
-            silence_seconds,
-            rolling_buffer_duration_seconds,
-            wake_word_threshold,
-            weather_latitude,
-            weather_longitude,
-            alarm_volume,
-        } => run_voice_assistant(VoiceAssistantConfig {
-            home_assistant_base_url,
-            home_assistant_token,
-            input_device_id,
-            silence_seconds,
-            rolling_buffer_duration_seconds,
-            wake_word_threshold,
-            weather_latitude,
-            weather_longitude,
-            alarm_volume,
-        }),
+        Commands::RunVoiceAssistant { config, overrides } => {
+            let config = AssistantConfig::resolve(
+                config.as_deref(),
+                *overrides,
+                &[Requirement::InputDevice, Requirement::HomeAssistant],
+            )?;
+            run_voice_assistant(config)
+        }
         Commands::GetInputDevices => get_input_devices(),
+        Commands::ProbeDevice {
+            device_id,
+            seconds,
+            json,
+        } => probe_device::probe_device(&device_id, seconds, json),
+        Commands::TranscribeFile {
+            file,
+            block_frames,
+            config,
+            overrides,
+        } => {
+            let config = AssistantConfig::resolve(config.as_deref(), *overrides, &[])?;
+            transcribe_file::transcribe_file(&file, block_frames, &config)
+        }
+        Commands::Simulate {
+            speak,
+            config,
+            overrides,
+        } => {
+            let config = AssistantConfig::resolve(
+                config.as_deref(),
+                *overrides,
+                &[Requirement::HomeAssistant],
+            )?;
+            simulate::simulate(&config, speak)
+        }
+        Commands::History {
+            last,
+            contains,
+            intent,
+            errors,
+            follow,
+            json,
+            config,
+            overrides,
+        } => {
+            let config = AssistantConfig::resolve(config.as_deref(), *overrides, &[])?;
+            let filter = HistoryFilter {
+                contains,
+                intent,
+                errors_only: errors,
+            };
+            history::show_history(&config.history.path, &filter, last, follow, json)
+        }
+        Commands::WyomingServer { config, overrides } => {
+            let config = AssistantConfig::resolve(config.as_deref(), *overrides, &[])?;
+            wyoming::run_wyoming_server(&config)
+        }
+        Commands::Satellite { config, overrides } => {
+            let config = AssistantConfig::resolve(
+                config.as_deref(),
+                *overrides,
+                &[Requirement::InputDevice, Requirement::SatelliteServer],
+            )?;
+            satellite::run_satellite(&config)
+        }
+        Commands::SatelliteServer { config, overrides } => {
+            let config = AssistantConfig::resolve(
+                config.as_deref(),
+                *overrides,
+                &[Requirement::HomeAssistant],
+            )?;
+            satellite::run_satellite_server(&config)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn verify_cli() {
+        Cli::command().debug_assert();
     }
 }
@@ -0,0 +1,213 @@
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+use std::thread;
+
+use color_eyre::eyre::Result;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, Registry, TextEncoder,
+};
+use tracing::{info, warn};
+
+/// Buckets for stages that take from a few milliseconds up to several seconds.
+const LATENCY_BUCKETS: &[f64] = &[0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Buckets for the per-chunk wake word inference, which runs every 80 ms of audio.
+const INFERENCE_BUCKETS: &[f64] = &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.08, 0.1, 0.25];
+
+/// Every metric the assistant records. They are registered on a private registry so only these
+/// show up on `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    pub whisper_transcription_seconds: Histogram,
+    pub intent_execution_seconds: HistogramVec,
+    pub tts_time_to_first_audio_seconds: Histogram,
+    pub wake_word_inference_seconds: Histogram,
+    pub wake_word_detections: IntCounter,
+    pub unknown_intents: IntCounter,
+    pub home_assistant_errors: IntCounter,
+    pub audio_callback_overruns: IntCounter,
+    pub audio_frames_dropped: IntCounter,
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("voice_assistant".to_string()), None)?;
+
+        let whisper_transcription_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "whisper_transcription_seconds",
+                "Time whisper took to transcribe one utterance",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+        )?;
+        let intent_execution_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "intent_execution_seconds",
+                "Time taken to execute a command, by intent",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+            &["intent"],
+        )?;
+        let tts_time_to_first_audio_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "tts_time_to_first_audio_seconds",
+                "Time from requesting speech until the first audio chunk was generated",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+        )?;
+        let wake_word_inference_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "wake_word_inference_seconds",
+                "Time the wake word model took for one 80 ms chunk",
+            )
+            .buckets(INFERENCE_BUCKETS.to_vec()),
+        )?;
+        let wake_word_detections =
+            IntCounter::new("wake_word_detections_total", "Wake words detected")?;
+        let unknown_intents = IntCounter::new(
+            "unknown_intents_total",
+            "Transcripts that did not match any command",
+        )?;
+        let home_assistant_errors = IntCounter::new(
+            "home_assistant_errors_total",
+            "Failed Home Assistant API calls",
+        )?;
+        let audio_callback_overruns = IntCounter::new(
+            "audio_callback_overruns_total",
+            "Buffer overruns reported by the input stream",
+        )?;
+        let audio_frames_dropped = IntCounter::new(
+            "audio_frames_dropped_total",
+            "Input frames dropped because audio processing fell behind the microphone",
+        )?;
+
+        registry.register(Box::new(whisper_transcription_seconds.clone()))?;
+        registry.register(Box::new(intent_execution_seconds.clone()))?;
+        registry.register(Box::new(tts_time_to_first_audio_seconds.clone()))?;
+        registry.register(Box::new(wake_word_inference_seconds.clone()))?;
+        registry.register(Box::new(wake_word_detections.clone()))?;
+        registry.register(Box::new(unknown_intents.clone()))?;
+        registry.register(Box::new(home_assistant_errors.clone()))?;
+        registry.register(Box::new(audio_callback_overruns.clone()))?;
+        registry.register(Box::new(audio_frames_dropped.clone()))?;
+
+        Ok(Self {
+            registry,
+            whisper_transcription_seconds,
+            intent_execution_seconds,
+            tts_time_to_first_audio_seconds,
+            wake_word_inference_seconds,
+            wake_word_detections,
+            unknown_intents,
+            home_assistant_errors,
+            audio_callback_overruns,
+            audio_frames_dropped,
+        })
+    }
+
+    /// All metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+/// Metrics are recorded from the audio callbacks, the command executor and the TTS client, so
+/// they live in one global rather than being threaded through all of them.
+pub static METRICS: LazyLock<Metrics> =
+    LazyLock::new(|| Metrics::new().expect("metric definitions are valid"));
+
+/// Serve `GET /metrics` on `address` from a background thread.
+pub fn spawn_server(address: SocketAddr) -> Result<()> {
+    let server = tiny_http::Server::http(address)
+        .map_err(|e| color_eyre::eyre::eyre!("failed to listen on {}: {}", address, e))?;
+    info!(%address, "serving metrics");
+
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let response = if request.url() != "/metrics" {
+                tiny_http::Response::from_string("Not found").with_status_code(404)
+            } else {
+                match METRICS.render() {
+                    Ok(body) => tiny_http::Response::from_string(body).with_header(
+                        tiny_http::Header::from_bytes(
+                            "Content-Type",
+                            TextEncoder::new().format_type(),
+                        )
+                        .unwrap(),
+                    ),
+                    Err(e) => {
+                        warn!(error = %e, "failed to render metrics");
+                        tiny_http::Response::from_string(e.to_string()).with_status_code(500)
+                    }
+                }
+            };
+            if let Err(e) = request.respond(response) {
+                warn!(error = %e, "failed to send metrics response");
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_includes_every_metric() {
+        let metrics = Metrics::new().unwrap();
+        metrics.whisper_transcription_seconds.observe(0.3);
+        metrics
+            .intent_execution_seconds
+            .with_label_values(&["get_weather"])
+            .observe(0.2);
+        metrics.unknown_intents.inc();
+
+        let rendered = metrics.render().unwrap();
+        for name in [
+            "voice_assistant_whisper_transcription_seconds_count 1",
+            "voice_assistant_intent_execution_seconds_count{intent=\"get_weather\"} 1",
+            "voice_assistant_tts_time_to_first_audio_seconds",
+            "voice_assistant_wake_word_inference_seconds",
+            "voice_assistant_wake_word_detections_total 0",
+            "voice_assistant_unknown_intents_total 1",
+            "voice_assistant_home_assistant_errors_total 0",
+            "voice_assistant_audio_callback_overruns_total 0",
+            "voice_assistant_audio_frames_dropped_total 0",
+        ] {
+            assert!(rendered.contains(name), "missing {}:\n{}", name, rendered);
+        }
+    }
+
+    #[test]
+    fn server_serves_metrics_and_404s_other_paths() {
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        spawn_server(address).unwrap();
+
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            write!(
+                stream,
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                path
+            )
+            .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let metrics = get("/metrics");
+        assert!(metrics.starts_with("HTTP/1.1 200"), "{}", metrics);
+        assert!(metrics.contains("voice_assistant_wake_word_detections_total"));
+        assert!(get("/").starts_with("HTTP/1.1 404"));
+    }
+}
@@ -0,0 +1,55 @@
+use color_eyre::eyre::Result;
+use oww_rs::mic::converters::f32_to_i16;
+
+use crate::audio_resampler::{AudioResampler, Downmix, Downmixer};
+use crate::config::ResamplerQuality;
+use crate::speech_listener::SAMPLE_RATE;
+
+/// Interleaved 16-bit little-endian PCM, the format audio travels in over the network, to f32
+/// samples in the -1.0 to 1.0 range.
+pub fn pcm_to_f32(pcm: &[u8]) -> Vec<f32> {
+    pcm.chunks_exact(2)
+        .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0)
+        .collect()
+}
+
+pub fn f32_to_pcm(samples: &[f32]) -> Vec<u8> {
+    samples
+        .iter()
+        .flat_map(|sample| f32_to_i16(sample).to_le_bytes())
+        .collect()
+}
+
+/// Average the channels and resample to the 16 kHz mono whisper expects.
+pub fn to_whisper_input(samples: &[f32], rate: u32, channels: u16) -> Result<Vec<f32>> {
+    let mono = Downmixer::new(channels, Downmix::Average)?.downmix(samples);
+    if rate == SAMPLE_RATE || mono.is_empty() {
+        return Ok(mono);
+    }
+    // Whole utterances are resampled at once, so there is no latency to save on the filter
+    let mut resampler = AudioResampler::new(rate, SAMPLE_RATE, ResamplerQuality::Best)?;
+    let mut resampled = resampler.resample(&mono);
+    resampled.extend(resampler.finish());
+    Ok(resampled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pcm_conversion_and_downmix_to_whisper_input() {
+        let pcm = f32_to_pcm(&[0.5, -0.5, 0.25, 0.75]);
+        let samples = pcm_to_f32(&pcm);
+        assert!((samples[0] - 0.5).abs() < 0.001);
+        assert!((samples[1] + 0.5).abs() < 0.001);
+
+        let mono = to_whisper_input(&samples, SAMPLE_RATE, 2).unwrap();
+        assert_eq!(mono.len(), 2);
+        assert!(mono[0].abs() < 0.001);
+        assert!((mono[1] - 0.5).abs() < 0.001);
+
+        let resampled = to_whisper_input(&vec![0.0; 48000], 48000, 1).unwrap();
+        assert_eq!(resampled.len(), 16000);
+    }
+}
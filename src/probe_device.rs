@@ -0,0 +1,368 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::Result;
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{SampleFormat, StreamConfig};
+use serde::Serialize;
+
+use crate::audio_input::{candidate_configs, get_device, supported_input_configs};
+
+/// Samples at or beyond this magnitude are counted as clipped.
+const CLIP_LEVEL: f32 = 0.999;
+
+/// Measured sample rates within this fraction of the requested rate count as correct.
+const SAMPLE_RATE_TOLERANCE: f64 = 0.05;
+
+/// Running totals collected from the audio callbacks of one candidate stream.
+#[derive(Debug, Default)]
+struct LevelStats {
+    callbacks: u64,
+    frames: u64,
+    first_callback_frames: u64,
+    first_callback: Option<Instant>,
+    last_callback: Option<Instant>,
+    samples: u64,
+    sum_squares: f64,
+    peak: f32,
+    clipped: u64,
+    error: Option<String>,
+}
+
+impl LevelStats {
+    fn record(&mut self, data: &[f32], channels: usize, now: Instant) {
+        let frames = (data.len() / channels.max(1)) as u64;
+        if self.first_callback.is_none() {
+            self.first_callback = Some(now);
+            self.first_callback_frames = frames;
+        }
+        self.last_callback = Some(now);
+        self.callbacks += 1;
+        self.frames += frames;
+
+        for &sample in data {
+            let magnitude = sample.abs();
+            self.peak = self.peak.max(magnitude);
+            self.sum_squares += (sample as f64) * (sample as f64);
+            if magnitude >= CLIP_LEVEL {
+                self.clipped += 1;
+            }
+        }
+        self.samples += data.len() as u64;
+    }
+
+    fn rms(&self) -> f32 {
+        if self.samples == 0 {
+            return 0.0;
+        }
+        (self.sum_squares / self.samples as f64).sqrt() as f32
+    }
+
+    /// Frames per second between the first and last callback. The first callback's frames are
+    /// left out since they were captured before the first timestamp.
+    fn measured_sample_rate(&self) -> Option<f64> {
+        let elapsed = self
+            .last_callback?
+            .duration_since(self.first_callback?)
+            .as_secs_f64();
+        if elapsed <= 0.0 {
+            return None;
+        }
+        Some((self.frames - self.first_callback_frames) as f64 / elapsed)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct SupportedConfigReport {
+    channels: u16,
+    min_sample_rate: u32,
+    max_sample_rate: u32,
+    buffer_size: String,
+    sample_format: String,
+}
+
+#[derive(Debug, Serialize)]
+struct CandidateReport {
+    sample_format: String,
+    channels: u16,
+    requested_sample_rate: u32,
+    /// Set when the stream could not be built or started, or reported an error while running
+    error: Option<String>,
+    callbacks: u64,
+    measured_sample_rate: Option<f64>,
+    peak: f32,
+    rms: f32,
+    clipped_samples: u64,
+}
+
+impl CandidateReport {
+    fn works(&self) -> bool {
+        self.error.is_none()
+            && self.callbacks > 0
+            && self.measured_sample_rate.is_some_and(|rate| {
+                let requested = self.requested_sample_rate as f64;
+                (rate - requested).abs() <= requested * SAMPLE_RATE_TOLERANCE
+            })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ProbeReport {
+    device_id: String,
+    supported_configs: Vec<SupportedConfigReport>,
+    candidates: Vec<CandidateReport>,
+}
+
+fn dbfs(level: f32) -> String {
+    if level > 0.0 {
+        format!("{:.1} dBFS", 20.0 * level.log10())
+    } else {
+        "-inf dBFS".to_string()
+    }
+}
+
+/// Open `device_id` with one candidate config, capture for `duration` and summarise the audio.
+fn probe_candidate(
+    device_id: &str,
+    config: &StreamConfig,
+    sample_format: SampleFormat,
+    duration: Duration,
+) -> CandidateReport {
+    let stats = Arc::new(Mutex::new(LevelStats::default()));
+    let result = run_candidate(device_id, config, sample_format, duration, &stats);
+
+    let stats = stats.lock().unwrap();
+    CandidateReport {
+        sample_format: format!("{:?}", sample_format),
+        channels: config.channels,
+        requested_sample_rate: config.sample_rate,
+        error: result.err().map(|e| e.to_string()).or(stats.error.clone()),
+        callbacks: stats.callbacks,
+        measured_sample_rate: stats.measured_sample_rate(),
+        peak: stats.peak,
+        rms: stats.rms(),
+        clipped_samples: stats.clipped,
+    }
+}
+
+fn run_candidate(
+    device_id: &str,
+    config: &StreamConfig,
+    sample_format: SampleFormat,
+    duration: Duration,
+    stats: &Arc<Mutex<LevelStats>>,
+) -> Result<()> {
+    // Get device fresh for each attempt, just like try_create_stream does
+    let device = get_device(device_id)?;
+    let channels = config.channels as usize;
+
+    let error_stats = stats.clone();
+    let error_callback = move |err: cpal::StreamError| {
+        let mut stats = error_stats.lock().unwrap();
+        if stats.error.is_none() {
+            stats.error = Some(err.to_string());
+        }
+    };
+
+    let stream = match sample_format {
+        SampleFormat::F32 => {
+            let stats = stats.clone();
+            device.build_input_stream(
+                config,
+                move |data: &[f32], _: &cpal::InputCallbackInfo| {
+                    stats.lock().unwrap().record(data, channels, Instant::now());
+                },
+                error_callback,
+                None,
+            )?
+        }
+        SampleFormat::I16 => {
+            let stats = stats.clone();
+            device.build_input_stream(
+                config,
+                move |data: &[i16], _: &cpal::InputCallbackInfo| {
+                    let data_f32: Vec<f32> =
+                        data.iter().map(|&sample| sample as f32 / 32768.0).collect();
+                    stats
+                        .lock()
+                        .unwrap()
+                        .record(&data_f32, channels, Instant::now());
+                },
+                error_callback,
+                None,
+            )?
+        }
+        _ => {
+            return Err(color_eyre::eyre::eyre!(
+                "Unsupported sample format: {:?}",
+                sample_format
+            ));
+        }
+    };
+
+    stream.play()?;
+    thread::sleep(duration);
+    drop(stream);
+
+    Ok(())
+}
+
+fn print_report(report: &ProbeReport) {
+    println!("Device: {}", report.device_id);
+    println!("Supported input configs:");
+    for config in &report.supported_configs {
+        println!(
+            "  {} @ {}-{} Hz, {} channel{}, buffer size {}",
+            config.sample_format,
+            config.min_sample_rate,
+            config.max_sample_rate,
+            config.channels,
+            if config.channels == 1 { "" } else { "s" },
+            config.buffer_size
+        );
+    }
+
+    println!("Candidates:");
+    for candidate in &report.candidates {
+        print!(
+            "  {} @ {} Hz, {} channel{}: ",
+            candidate.sample_format,
+            candidate.requested_sample_rate,
+            candidate.channels,
+            if candidate.channels == 1 { "" } else { "s" }
+        );
+        if let Some(error) = &candidate.error {
+            println!("FAILED - {}", error);
+        } else if candidate.callbacks == 0 {
+            println!("FAILED - no audio callbacks");
+        } else {
+            println!(
+                "{} - {} callbacks, measured {}, peak {}, RMS {}, {} clipped sample{}",
+                if candidate.works() {
+                    "OK"
+                } else {
+                    "WRONG RATE"
+                },
+                candidate.callbacks,
+                candidate
+                    .measured_sample_rate
+                    .map(|rate| format!("{:.0} Hz", rate))
+                    .unwrap_or_else(|| "-".to_string()),
+                dbfs(candidate.peak),
+                dbfs(candidate.rms),
+                candidate.clipped_samples,
+                if candidate.clipped_samples == 1 {
+                    ""
+                } else {
+                    "s"
+                }
+            );
+        }
+    }
+
+    match report.candidates.iter().find(|candidate| candidate.works()) {
+        Some(candidate) => println!(
+            "First working config: {} @ {} Hz, {} channel{}",
+            candidate.sample_format,
+            candidate.requested_sample_rate,
+            candidate.channels,
+            if candidate.channels == 1 { "" } else { "s" }
+        ),
+        None => println!("No candidate config delivered audio at the requested rate"),
+    }
+}
+
+/// Run every candidate stream config for `device_id` for `seconds` each and report which ones
+/// actually deliver audio, at what rate and level.
+pub fn probe_device(device_id: &str, seconds: f64, json: bool) -> Result<()> {
+    let duration = Duration::try_from_secs_f64(seconds)
+        .ok()
+        .filter(|duration| !duration.is_zero())
+        .ok_or_else(|| {
+            color_eyre::eyre::eyre!("--seconds must be greater than 0 (got {})", seconds)
+        })?;
+
+    let supported_configs = supported_input_configs(device_id)?;
+    let candidates = candidate_configs(&supported_configs);
+
+    let mut candidate_reports = Vec::new();
+    for (config, sample_format) in &candidates {
+        if !json {
+            println!(
+                "Probing {:?} @ {} Hz ({} channel{}) for {:.1}s...",
+                sample_format,
+                config.sample_rate,
+                config.channels,
+                if config.channels == 1 { "" } else { "s" },
+                seconds
+            );
+        }
+        candidate_reports.push(probe_candidate(device_id, config, *sample_format, duration));
+
+        // Give the device time to release resources before the next configuration
+        thread::sleep(Duration::from_millis(200));
+    }
+
+    let report = ProbeReport {
+        device_id: device_id.to_string(),
+        supported_configs: supported_configs
+            .iter()
+            .map(|config| SupportedConfigReport {
+                channels: config.channels(),
+                min_sample_rate: config.min_sample_rate(),
+                max_sample_rate: config.max_sample_rate(),
+                buffer_size: format!("{:?}", config.buffer_size()),
+                sample_format: format!("{:?}", config.sample_format()),
+            })
+            .collect(),
+        candidates: candidate_reports,
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_report(&report);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn level_stats_measure_peak_rms_clipping_and_rate() {
+        let mut stats = LevelStats::default();
+        let start = Instant::now();
+        // Stereo, 4 frames per callback, 100 ms apart: 40 frames per second
+        stats.record(&[0.5, -0.5, 0.5, -0.5, 0.5, -0.5, 0.5, -0.5], 2, start);
+        stats.record(
+            &[1.0, -1.0, 0.5, -0.5, 0.5, -0.5, 0.5, -0.5],
+            2,
+            start + Duration::from_millis(100),
+        );
+        stats.record(
+            &[0.5, -0.5, 0.5, -0.5, 0.5, -0.5, 0.5, -0.5],
+            2,
+            start + Duration::from_millis(200),
+        );
+
+        assert_eq!(stats.callbacks, 3);
+        assert_eq!(stats.frames, 12);
+        assert_eq!(stats.peak, 1.0);
+        assert_eq!(stats.clipped, 2);
+        let expected_rms = ((22.0 * 0.25 + 2.0) / 24.0_f64).sqrt() as f32;
+        assert!((stats.rms() - expected_rms).abs() < 1e-6);
+        let rate = stats.measured_sample_rate().unwrap();
+        assert!((rate - 40.0).abs() < 1e-6, "{}", rate);
+    }
+
+    #[test]
+    fn level_stats_without_callbacks_have_no_rate() {
+        let stats = LevelStats::default();
+        assert_eq!(stats.rms(), 0.0);
+        assert_eq!(stats.measured_sample_rate(), None);
+    }
+}
@@ -0,0 +1,222 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use color_eyre::eyre::{Context, Result};
+use oww_rs::mic::converters::f32_to_i16;
+use oww_rs::oww::OWW_MODEL_CHUNK_SIZE;
+use oww_rs::rms::calculate_rms;
+use oww_rs::save::try_save_full_wav;
+use tracing::{debug, warn};
+
+use crate::config::{RecordingsConfig, SECONDS_PER_DAY};
+
+/// Marker in every recording's file name, so retention never touches other files in the
+/// directory.
+const RECORDING_MARKER: &str = "utterance";
+
+/// Saves utterance audio as WAV files for debugging and for building a regression corpus, and
+/// keeps the directory within the configured limits.
+pub struct UtteranceRecorder {
+    dir: PathBuf,
+    max_files: usize,
+    max_age: Duration,
+}
+
+/// A saved utterance. Its transcript goes next to it with a `.txt` extension.
+pub struct Recording {
+    pub wav_path: PathBuf,
+}
+
+impl Recording {
+    pub fn write_transcript(&self, transcript: &str) -> Result<()> {
+        let path = self.wav_path.with_extension("txt");
+        fs::write(&path, format!("{}\n", transcript))
+            .wrap_err_with(|| format!("failed to write transcript {}", path.display()))
+    }
+}
+
+impl UtteranceRecorder {
+    /// `None` when recording is turned off in the config.
+    pub fn new(config: &RecordingsConfig) -> Result<Option<Self>> {
+        if !config.enabled {
+            return Ok(None);
+        }
+        fs::create_dir_all(&config.dir).wrap_err_with(|| {
+            format!(
+                "failed to create recordings directory {}",
+                config.dir.display()
+            )
+        })?;
+        Ok(Some(Self {
+            dir: config.dir.clone(),
+            max_files: config.max_files,
+            max_age: Duration::from_secs_f64(config.max_age_days * SECONDS_PER_DAY),
+        }))
+    }
+
+    /// Write `audio` (16 kHz mono, as handed out by the speech pipeline) to a new WAV file, then
+    /// delete recordings beyond the retention limits.
+    pub fn save(&self, audio: &[f32], wake_word_probability: Option<f32>) -> Result<Recording> {
+        let samples = audio.iter().map(f32_to_i16).collect::<Vec<_>>();
+        // Loudest wake-word-sized chunk, the same level oww_rs puts in its own recordings
+        let max_rms = samples
+            .chunks(OWW_MODEL_CHUNK_SIZE)
+            .map(calculate_rms)
+            .max()
+            .unwrap_or(0);
+        let detection_percent = (wake_word_probability.unwrap_or(0.0) * 100.0).round() as u8;
+        // Local time first so the files sort chronologically and never collide within a second
+        let prefix = chrono::Local::now().format("%Y%m%d-%H%M%S-%3f").to_string();
+
+        let dir = self.dir.to_string_lossy();
+        let wav_path = try_save_full_wav(
+            &samples,
+            detection_percent,
+            RECORDING_MARKER,
+            &prefix,
+            max_rms,
+            &dir,
+        )
+        .wrap_err_with(|| format!("failed to save recording in {}", dir))?;
+
+        if let Err(e) = self.prune() {
+            warn!(error = %e, "failed to delete old recordings");
+        }
+
+        Ok(Recording {
+            wav_path: PathBuf::from(wav_path),
+        })
+    }
+
+    fn prune(&self) -> Result<()> {
+        let mut recordings = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if !is_recording(&path) {
+                continue;
+            }
+            let modified = fs::metadata(&path)?.modified()?;
+            recordings.push((path, modified));
+        }
+
+        for path in expired(recordings, SystemTime::now(), self.max_files, self.max_age) {
+            debug!(path = %path.display(), "deleting old recording");
+            fs::remove_file(&path)?;
+            let transcript = path.with_extension("txt");
+            if transcript.exists() {
+                fs::remove_file(transcript)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_recording(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "wav")
+        && path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.contains(&format!("_{}_", RECORDING_MARKER)))
+}
+
+/// Recordings older than `max_age`, plus the oldest ones beyond `max_files`. File names start
+/// with the time they were saved, so sorting by name sorts by age.
+fn expired(
+    mut recordings: Vec<(PathBuf, SystemTime)>,
+    now: SystemTime,
+    max_files: usize,
+    max_age: Duration,
+) -> Vec<PathBuf> {
+    recordings.sort_by(|(a, _), (b, _)| b.cmp(a));
+    recordings
+        .into_iter()
+        .enumerate()
+        .filter(|(index, (_, modified))| {
+            *index >= max_files || now.duration_since(*modified).is_ok_and(|age| age > max_age)
+        })
+        .map(|(_, (path, _))| path)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expired_keeps_newest_files_within_age() {
+        let now = SystemTime::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let recordings = vec![
+            (
+                PathBuf::from("r/20260101-000000-000_utterance_90.wav"),
+                now - day * 40,
+            ),
+            (
+                PathBuf::from("r/20260301-000000-000_utterance_90.wav"),
+                now - day * 3,
+            ),
+            (
+                PathBuf::from("r/20260302-000000-000_utterance_90.wav"),
+                now - day * 2,
+            ),
+            (
+                PathBuf::from("r/20260303-000000-000_utterance_90.wav"),
+                now - day,
+            ),
+        ];
+
+        let mut deleted = expired(recordings, now, 2, day * 30);
+        deleted.sort();
+        assert_eq!(
+            deleted,
+            vec![
+                PathBuf::from("r/20260101-000000-000_utterance_90.wav"),
+                PathBuf::from("r/20260301-000000-000_utterance_90.wav"),
+            ]
+        );
+    }
+
+    #[test]
+    fn saves_wav_with_transcript_and_prunes_old_ones() {
+        let dir =
+            std::env::temp_dir().join(format!("voice-assistant-recordings-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let recorder = UtteranceRecorder::new(&RecordingsConfig {
+            enabled: true,
+            dir: dir.clone(),
+            max_files: 2,
+            max_age_days: 30.0,
+        })
+        .unwrap()
+        .unwrap();
+        let unrelated = dir.join("notes.wav");
+        fs::write(&unrelated, "").unwrap();
+
+        let audio = vec![0.25; 16000];
+        let mut saved = Vec::new();
+        for _ in 0..3 {
+            let recording = recorder.save(&audio, Some(0.87)).unwrap();
+            recording
+                .write_transcript("Alexa, what time is it?")
+                .unwrap();
+            saved.push(recording.wav_path);
+            std::thread::sleep(Duration::from_millis(5));
+        }
+
+        let reader = hound::WavReader::open(&saved[2]).unwrap();
+        assert_eq!(reader.spec().sample_rate, 16000);
+        assert_eq!(reader.len(), 16000);
+        assert!(saved[2].to_string_lossy().contains("_utterance_87_"));
+        assert_eq!(
+            fs::read_to_string(saved[2].with_extension("txt")).unwrap(),
+            "Alexa, what time is it?\n"
+        );
+        assert!(!saved[0].exists());
+        assert!(!saved[0].with_extension("txt").exists());
+        assert!(saved[1].exists());
+        assert!(unrelated.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,320 @@
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::Duration;
+
+use color_eyre::eyre::{Context, OptionExt, Result};
+use tracing::{debug, info, warn};
+
+use super::protocol::{Frame, read_frame, write_frame};
+use crate::audio_input::{InputEvent, RecoveryConfig, spawn_input_supervisor};
+use crate::config::AssistantConfig;
+use crate::pcm::{f32_to_pcm, pcm_to_f32};
+use crate::speech_listener::{PlaybackState, SAMPLE_RATE, SpeechEvent};
+use crate::{EXIT_INTERRUPTED, speech_pipeline_config};
+
+/// Samples per audio frame sent to the server, 100 ms at 16 kHz.
+const AUDIO_FRAME_SAMPLES: usize = 1600;
+
+/// Delay before reconnecting to a server that went away; doubles on every failure.
+const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How often the player checks whether queued speech has finished playing.
+const PLAYER_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The satellite's connection to the server.
+pub struct ServerLink {
+    stream: TcpStream,
+}
+
+impl ServerLink {
+    /// Connect to `address` and introduce ourselves as `name`.
+    pub fn connect(address: &str, name: &str) -> Result<Self> {
+        let stream = TcpStream::connect(address)
+            .wrap_err_with(|| format!("failed to connect to {}", address))?;
+        stream.set_nodelay(true)?;
+        let mut link = Self { stream };
+        link.send(&Frame::Hello(name.to_string()))?;
+        Ok(link)
+    }
+
+    /// A second handle on the connection for reading what the server sends.
+    pub fn reader(&self) -> Result<TcpStream> {
+        Ok(self.stream.try_clone()?)
+    }
+
+    fn send(&mut self, frame: &Frame) -> Result<()> {
+        write_frame(&mut self.stream, frame)
+    }
+
+    pub fn send_wake_word(&mut self, probability: f32) -> Result<()> {
+        self.send(&Frame::WakeWord(probability))
+    }
+
+    /// Send a 16 kHz mono utterance for the server to transcribe and answer.
+    pub fn send_utterance(&mut self, audio: &[f32]) -> Result<()> {
+        self.send(&Frame::AudioStart(SAMPLE_RATE))?;
+        for chunk in audio.chunks(AUDIO_FRAME_SAMPLES) {
+            self.send(&Frame::Audio(f32_to_pcm(chunk)))?;
+        }
+        self.send(&Frame::AudioEnd)
+    }
+}
+
+enum PlayerCommand {
+    /// A new stream of speech, played after whatever is still playing
+    Start(u32),
+    Audio(Vec<f32>),
+    End,
+    /// Cut off the current speech; audio up to the next `Start` is dropped
+    Stop,
+}
+
+/// Play speech from the server on the default output device as it arrives, keeping `playback`
+/// up to date for the wake word's self-trigger suppression.
+fn spawn_player(playback: Arc<PlaybackState>) -> Result<mpsc::Sender<PlayerCommand>> {
+    let (tx, rx) = mpsc::channel::<PlayerCommand>();
+    let (ready_tx, ready_rx) = mpsc::channel::<Result<()>>();
+
+    // The output stream is not Send, so it lives on the player thread
+    thread::spawn(move || {
+        let stream = match rodio::OutputStreamBuilder::open_default_stream() {
+            Ok(stream) => {
+                let _ = ready_tx.send(Ok(()));
+                stream
+            }
+            Err(e) => {
+                let _ = ready_tx.send(Err(color_eyre::eyre::eyre!(
+                    "failed to open audio output: {}",
+                    e
+                )));
+                return;
+            }
+        };
+
+        // The server sends speech faster than it plays, so a new stream usually starts while the
+        // last one is still playing; it is queued behind it on the same sink
+        let mut sink: Option<rodio::Sink> = None;
+        // Rate of the stream being received, `None` while audio is being dropped after a stop
+        let mut sample_rate: Option<u32> = None;
+        // Streams started but not yet ended
+        let mut open_streams: usize = 0;
+        loop {
+            match rx.recv_timeout(PLAYER_POLL_INTERVAL) {
+                Ok(PlayerCommand::Start(rate)) => {
+                    if sink.is_none() {
+                        sink = Some(rodio::Sink::connect_new(stream.mixer()));
+                        playback.set_playing(true);
+                    }
+                    sample_rate = Some(rate);
+                    open_streams += 1;
+                }
+                Ok(PlayerCommand::Audio(samples)) => {
+                    if let (Some(sink), Some(rate)) = (&sink, sample_rate) {
+                        sink.append(rodio::buffer::SamplesBuffer::new(1, rate, samples));
+                    }
+                }
+                Ok(PlayerCommand::End) => open_streams = open_streams.saturating_sub(1),
+                Ok(PlayerCommand::Stop) => {
+                    sample_rate = None;
+                    open_streams = 0;
+                    if let Some(sink) = sink.take() {
+                        sink.stop();
+                        playback.set_playing(false);
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+            if open_streams == 0 && sink.as_ref().is_some_and(|sink| sink.empty()) {
+                sink = None;
+                playback.set_playing(false);
+            }
+        }
+    });
+
+    ready_rx
+        .recv()
+        .map_err(|_| color_eyre::eyre::eyre!("player thread exited during startup"))??;
+    Ok(tx)
+}
+
+enum SatelliteEvent {
+    Input(InputEvent),
+    Connected(ServerLink),
+    Disconnected(String),
+    Server(Frame),
+    Shutdown,
+}
+
+/// Keep a connection to the server open, reconnecting with backoff whenever it drops, and
+/// forward everything it sends.
+fn spawn_connection(address: String, name: String, events: mpsc::Sender<SatelliteEvent>) {
+    thread::spawn(move || {
+        let mut backoff = RECONNECT_INITIAL_BACKOFF;
+        loop {
+            let reason = match ServerLink::connect(&address, &name) {
+                Ok(link) => match link.reader() {
+                    Ok(mut reader) => {
+                        backoff = RECONNECT_INITIAL_BACKOFF;
+                        if events.send(SatelliteEvent::Connected(link)).is_err() {
+                            return;
+                        }
+                        loop {
+                            match read_frame(&mut reader) {
+                                Ok(Some(frame)) => {
+                                    if events.send(SatelliteEvent::Server(frame)).is_err() {
+                                        return;
+                                    }
+                                }
+                                Ok(None) => break "server closed the connection".to_string(),
+                                Err(e) => break e.to_string(),
+                            }
+                        }
+                    }
+                    Err(e) => format!("failed to clone server connection: {}", e),
+                },
+                Err(e) => format!("{:#}", e),
+            };
+            if events.send(SatelliteEvent::Disconnected(reason)).is_err() {
+                return;
+            }
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+        }
+    });
+}
+
+/// Listen on the microphone, detect the wake word and the end of speech locally, and send each
+/// utterance to the server at `satellite.server_address`, playing back the speech it returns.
+pub fn run_satellite(config: &AssistantConfig) -> Result<()> {
+    let input_device_id = config
+        .audio
+        .input_device_id
+        .clone()
+        .ok_or_eyre("No input device configured")?;
+    let server_address = config
+        .satellite
+        .server_address
+        .clone()
+        .ok_or_eyre("No satellite server address configured")?;
+
+    let playback = Arc::new(PlaybackState::default());
+    let player = spawn_player(playback.clone())?;
+
+    let (app_tx, app_rx) = mpsc::channel::<SatelliteEvent>();
+
+    let shutdown_requested = Arc::new(AtomicBool::new(false));
+    let signal_app_tx = app_tx.clone();
+    ctrlc::set_handler(move || {
+        if shutdown_requested.swap(true, Ordering::SeqCst) {
+            warn!("second signal received, exiting immediately");
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+        info!("signal received, shutting down");
+        let _ = signal_app_tx.send(SatelliteEvent::Shutdown);
+    })?;
+
+    let (input_tx, input_rx) = mpsc::channel::<InputEvent>();
+    let input_supervisor = spawn_input_supervisor(
+        input_device_id,
+        speech_pipeline_config(config, playback, Default::default()),
+        RecoveryConfig::from_audio_config(&config.audio),
+        input_tx,
+    )?;
+    let input_app_tx = app_tx.clone();
+    thread::spawn(move || {
+        for event in input_rx {
+            if input_app_tx.send(SatelliteEvent::Input(event)).is_err() {
+                break;
+            }
+        }
+    });
+
+    spawn_connection(server_address, config.satellite.name.clone(), app_tx);
+    info!(name = %config.satellite.name, "satellite listening for the wake word");
+
+    let mut link: Option<ServerLink> = None;
+    for event in app_rx {
+        match event {
+            SatelliteEvent::Input(InputEvent::Speech(SpeechEvent::WakeWordDetected {
+                probability,
+            })) => {
+                info!(probability, "wake word detected");
+                if config.tts.barge_in {
+                    let _ = player.send(PlayerCommand::Stop);
+                }
+                if let Some(server) = &mut link
+                    && let Err(e) = server.send_wake_word(probability)
+                {
+                    warn!(error = %e, "failed to send wake word");
+                }
+            }
+            SatelliteEvent::Input(InputEvent::Speech(
+                SpeechEvent::SpeechDetected(audio) | SpeechEvent::MaxUtteranceReached(audio),
+            )) => {
+                let seconds = audio.len() as f64 / SAMPLE_RATE as f64;
+                match &mut link {
+                    Some(server) => {
+                        info!(duration_seconds = seconds, "sending utterance");
+                        if let Err(e) = server.send_utterance(&audio) {
+                            warn!(error = %e, "failed to send utterance");
+                        }
+                    }
+                    None => warn!(
+                        duration_seconds = seconds,
+                        "not connected to the server, dropping utterance"
+                    ),
+                }
+            }
+            SatelliteEvent::Input(InputEvent::Speech(SpeechEvent::NoSpeech)) => {
+                info!("no speech, cancelled");
+            }
+            SatelliteEvent::Input(InputEvent::Speech(SpeechEvent::FollowUpExpired)) => {}
+            SatelliteEvent::Input(InputEvent::Lost(reason)) => {
+                warn!(%reason, "lost audio input, waiting for it to come back");
+            }
+            SatelliteEvent::Input(InputEvent::Recovered) => info!("audio input recovered"),
+            SatelliteEvent::Connected(server) => {
+                info!("connected to the server");
+                link = Some(server);
+            }
+            SatelliteEvent::Disconnected(reason) => {
+                if link.take().is_some() {
+                    warn!(%reason, "lost the server, reconnecting");
+                } else {
+                    debug!(%reason, "server not reachable");
+                }
+            }
+            SatelliteEvent::Server(frame) => {
+                let command = match frame {
+                    Frame::Transcript(text) => {
+                        info!(%text, "server heard");
+                        continue;
+                    }
+                    Frame::Response(text) => {
+                        info!(%text, "server responded");
+                        continue;
+                    }
+                    Frame::AudioStart(sample_rate) => PlayerCommand::Start(sample_rate),
+                    Frame::Audio(pcm) => PlayerCommand::Audio(pcm_to_f32(&pcm)),
+                    Frame::AudioEnd => PlayerCommand::End,
+                    frame => {
+                        warn!(?frame, "unexpected frame from the server");
+                        continue;
+                    }
+                };
+                if player.send(command).is_err() {
+                    return Err(color_eyre::eyre::eyre!("player thread exited"));
+                }
+            }
+            SatelliteEvent::Shutdown => break,
+        }
+    }
+
+    input_supervisor.shutdown();
+    info!("shutdown complete");
+    Ok(())
+}
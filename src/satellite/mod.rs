@@ -0,0 +1,126 @@
+mod client;
+mod protocol;
+mod server;
+
+pub use client::run_satellite;
+pub use server::run_satellite_server;
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::{Arc, mpsc};
+    use std::thread;
+    use std::time::Duration;
+
+    use color_eyre::eyre::Result;
+
+    use super::client::ServerLink;
+    use super::protocol::{Frame, read_frame};
+    use super::server::{Server, serve};
+    use crate::command_executor::{CommandExecutorConfig, TimerManager};
+    use crate::speech_services::{Backend, WakeWordSession};
+
+    /// Hears a one second timer in any utterance of exactly a quarter second. Confirmations of a
+    /// new timer pause for `response_pause` between their two chunks of speech.
+    struct FakeBackend {
+        response_pause: Duration,
+    }
+
+    impl Backend for FakeBackend {
+        fn transcribe(&self, audio: Vec<f32>) -> Result<String> {
+            if audio.len() != 4000 {
+                return Err(color_eyre::eyre::eyre!("got {} samples", audio.len()));
+            }
+            Ok("Alexa, set a timer for one second.".to_string())
+        }
+
+        fn synthesize(
+            &self,
+            text: &str,
+            on_audio: &mut dyn FnMut(u32, &[f32]) -> Result<()>,
+        ) -> Result<()> {
+            on_audio(24000, &[0.5; 10])?;
+            if text.starts_with("Timer set") {
+                thread::sleep(self.response_pause);
+            }
+            on_audio(24000, &[-0.5; 10])
+        }
+
+        fn wake_word_session(&self, _: u32, _: u16) -> Result<Box<dyn WakeWordSession>> {
+            Err(color_eyre::eyre::eyre!(
+                "satellites detect the wake word themselves"
+            ))
+        }
+    }
+
+    /// Connect a satellite to a fresh server and say an utterance that sets a one second timer.
+    fn connect(backend: FakeBackend) -> impl FnMut() -> Frame {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (timer_tx, timer_rx) = mpsc::channel();
+        let server = Arc::new(Server::new(
+            Box::new(backend),
+            CommandExecutorConfig::new(
+                "http://127.0.0.1:1".parse().unwrap(),
+                "token".to_string(),
+                None,
+                None,
+            ),
+            TimerManager::new(timer_tx),
+            "alexa",
+        ));
+        thread::spawn(move || serve(listener, server, timer_rx));
+
+        let mut link = ServerLink::connect(&address.to_string(), "test").unwrap();
+        let mut reader = link.reader().unwrap();
+        reader
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        link.send_wake_word(0.9).unwrap();
+        link.send_utterance(&[0.1; 4000]).unwrap();
+        move || read_frame(&mut reader).unwrap().expect("server hung up")
+    }
+
+    #[test]
+    fn satellite_and_server_talk_over_loopback() {
+        let mut next = connect(FakeBackend {
+            response_pause: Duration::ZERO,
+        });
+
+        assert_eq!(
+            next(),
+            Frame::Transcript("Alexa, set a timer for one second.".to_string())
+        );
+        assert_eq!(
+            next(),
+            Frame::Response("Timer set for one second".to_string())
+        );
+        assert_eq!(next(), Frame::AudioStart(24000));
+        assert!(matches!(next(), Frame::Audio(pcm) if pcm.len() == 20));
+        assert!(matches!(next(), Frame::Audio(pcm) if pcm.len() == 20));
+        assert_eq!(next(), Frame::AudioEnd);
+
+        // The timer's announcement is pushed to the satellite unprompted
+        assert_eq!(next(), Frame::AudioStart(24000));
+        assert!(matches!(next(), Frame::Audio(_)));
+        assert!(matches!(next(), Frame::Audio(_)));
+        assert_eq!(next(), Frame::AudioEnd);
+    }
+
+    #[test]
+    fn announcements_wait_for_the_response_being_streamed() {
+        // The timer goes off while the response is still being synthesized
+        let mut next = connect(FakeBackend {
+            response_pause: Duration::from_millis(1500),
+        });
+
+        assert!(matches!(next(), Frame::Transcript(_)));
+        assert!(matches!(next(), Frame::Response(_)));
+        for _ in 0..2 {
+            assert_eq!(next(), Frame::AudioStart(24000));
+            assert!(matches!(next(), Frame::Audio(_)));
+            assert!(matches!(next(), Frame::Audio(_)));
+            assert_eq!(next(), Frame::AudioEnd);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use std::io::{self, Read, Write};
+
+use color_eyre::eyre::{Context, Result};
+
+/// Largest frame body accepted, far above any audio chunk, so a corrupt length cannot make the
+/// reader allocate gigabytes.
+const MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+
+/// A message between a satellite and the server. On the wire each frame is a one byte kind, the
+/// body length as a little-endian u32 and the body.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    /// First frame from a satellite, naming it in the server's logs
+    Hello(String),
+    /// The satellite heard the wake word with this probability
+    WakeWord(f32),
+    /// Mono audio at this sample rate follows: an utterance from the satellite, speech from the
+    /// server
+    AudioStart(u32),
+    /// 16-bit little-endian PCM
+    Audio(Vec<u8>),
+    /// The utterance or speech is complete
+    AudioEnd,
+    /// What the server heard, for the satellite's logs
+    Transcript(String),
+    /// What the server answered, sent before the speech for it
+    Response(String),
+}
+
+impl Frame {
+    fn kind(&self) -> u8 {
+        match self {
+            Frame::Hello(_) => 1,
+            Frame::WakeWord(_) => 2,
+            Frame::AudioStart(_) => 3,
+            Frame::Audio(_) => 4,
+            Frame::AudioEnd => 5,
+            Frame::Transcript(_) => 6,
+            Frame::Response(_) => 7,
+        }
+    }
+
+    fn body(&self) -> Vec<u8> {
+        match self {
+            Frame::Hello(text) | Frame::Transcript(text) | Frame::Response(text) => {
+                text.as_bytes().to_vec()
+            }
+            Frame::WakeWord(probability) => probability.to_le_bytes().to_vec(),
+            Frame::AudioStart(sample_rate) => sample_rate.to_le_bytes().to_vec(),
+            Frame::Audio(pcm) => pcm.clone(),
+            Frame::AudioEnd => Vec::new(),
+        }
+    }
+
+    fn decode(kind: u8, body: Vec<u8>) -> Result<Self> {
+        let text = |body: Vec<u8>| String::from_utf8(body).wrap_err("frame text is not UTF-8");
+        let array = |body: &[u8]| -> Result<[u8; 4]> {
+            body.try_into()
+                .map_err(|_| color_eyre::eyre::eyre!("expected 4 bytes, got {}", body.len()))
+        };
+        Ok(match kind {
+            1 => Frame::Hello(text(body)?),
+            2 => Frame::WakeWord(f32::from_le_bytes(array(&body)?)),
+            3 => Frame::AudioStart(u32::from_le_bytes(array(&body)?)),
+            4 => Frame::Audio(body),
+            5 => Frame::AudioEnd,
+            6 => Frame::Transcript(text(body)?),
+            7 => Frame::Response(text(body)?),
+            kind => return Err(color_eyre::eyre::eyre!("unknown frame kind {}", kind)),
+        })
+    }
+}
+
+/// Read the next frame, or `None` once the peer has closed the connection.
+pub fn read_frame(reader: &mut impl Read) -> Result<Option<Frame>> {
+    let mut kind = [0u8; 1];
+    match reader.read_exact(&mut kind) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let mut len_bytes = [0u8; 4];
+    reader.read_exact(&mut len_bytes)?;
+    let len = u32::from_le_bytes(len_bytes) as usize;
+    if len > MAX_FRAME_LENGTH {
+        return Err(color_eyre::eyre::eyre!(
+            "frame of {} bytes is too long",
+            len
+        ));
+    }
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body)?;
+    Frame::decode(kind[0], body).map(Some)
+}
+
+pub fn write_frame(writer: &mut impl Write, frame: &Frame) -> Result<()> {
+    let body = frame.body();
+    let mut message = Vec::with_capacity(5 + body.len());
+    message.push(frame.kind());
+    message.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    message.extend_from_slice(&body);
+    writer.write_all(&message)?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip() {
+        let frames = [
+            Frame::Hello("kitchen".to_string()),
+            Frame::WakeWord(0.75),
+            Frame::AudioStart(16000),
+            Frame::Audio(vec![1, 2, 3, 4]),
+            Frame::AudioEnd,
+            Frame::Transcript("Alexa, what time is it?".to_string()),
+            Frame::Response("It is noon".to_string()),
+        ];
+        let mut bytes = Vec::new();
+        for frame in &frames {
+            write_frame(&mut bytes, frame).unwrap();
+        }
+
+        let mut reader = &bytes[..];
+        for frame in &frames {
+            assert_eq!(read_frame(&mut reader).unwrap().as_ref(), Some(frame));
+        }
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+
+        assert!(read_frame(&mut &[9, 0, 0, 0, 0][..]).is_err());
+        assert!(read_frame(&mut &[4, 0xff, 0xff, 0xff, 0xff][..]).is_err());
+    }
+}
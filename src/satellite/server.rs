@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+
+use color_eyre::eyre::{Context, Result};
+use tracing::{Span, debug, error, info, info_span, warn};
+
+use super::protocol::{Frame, read_frame, write_frame};
+use crate::clean_text;
+use crate::command_executor::{self, CommandExecutorConfig, TimerEvent, TimerManager};
+use crate::config::AssistantConfig;
+use crate::create_command_executor_config;
+use crate::pcm::{f32_to_pcm, pcm_to_f32, to_whisper_input};
+use crate::speech_services::{Backend, LocalBackend};
+
+/// Spoken when transcription or the command fails.
+const ERROR_RESPONSE: &str = "Something went wrong. Please try again.";
+
+/// Transcribes utterances from satellites, runs them through the command executor and sends the
+/// spoken response back. Timer announcements go to every connected satellite.
+pub struct Server {
+    backend: Box<dyn Backend>,
+    command_executor_config: CommandExecutorConfig,
+    timer_manager: TimerManager,
+    voice_activation_text: String,
+    /// Outgoing frames of each connected satellite, by connection id. Whoever sends a stream
+    /// of audio holds the lock until it is done, so two streams never interleave.
+    satellites: Mutex<HashMap<u64, Arc<Mutex<mpsc::Sender<Frame>>>>>,
+    next_id: AtomicU64,
+}
+
+impl Server {
+    pub fn new(
+        backend: Box<dyn Backend>,
+        command_executor_config: CommandExecutorConfig,
+        timer_manager: TimerManager,
+        voice_activation_text: &str,
+    ) -> Self {
+        Self {
+            backend,
+            command_executor_config,
+            timer_manager,
+            voice_activation_text: voice_activation_text.to_lowercase(),
+            satellites: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Transcribe an utterance and run it; returns the transcript and the text to speak.
+    fn respond(&self, audio: Vec<f32>) -> (String, String) {
+        let transcript =
+            match info_span!("transcription").in_scope(|| self.backend.transcribe(audio)) {
+                Ok(transcript) => transcript,
+                Err(e) => {
+                    error!(error = %e, "transcription failed");
+                    return (String::new(), ERROR_RESPONSE.to_string());
+                }
+            };
+        let cleaned_text = clean_text(&transcript, &self.voice_activation_text);
+        info!(text = %cleaned_text, "transcribed");
+        let intent = command_executor::parse_intent(&cleaned_text);
+        let response = command_executor::execute_intent(
+            &self.command_executor_config,
+            &self.timer_manager,
+            intent,
+            &cleaned_text,
+        )
+        .unwrap_or_else(|e| {
+            error!(error = %e, "error executing command");
+            ERROR_RESPONSE.to_string()
+        });
+        info!(response = %response, "responding");
+        (transcript, response)
+    }
+
+    /// Synthesize `text`, handing the frames to `send` as the audio is generated.
+    fn speak(&self, text: &str, send: &mut dyn FnMut(Frame)) -> Result<()> {
+        let mut started = false;
+        self.backend.synthesize(text, &mut |sample_rate, samples| {
+            if !started {
+                started = true;
+                send(Frame::AudioStart(sample_rate));
+            }
+            send(Frame::Audio(f32_to_pcm(samples)));
+            Ok(())
+        })?;
+        if started {
+            send(Frame::AudioEnd);
+        }
+        Ok(())
+    }
+
+    /// Say `text` on every connected satellite. It is synthesized once, then sent to each.
+    fn announce(&self, text: &str) -> Result<()> {
+        let mut frames = Vec::new();
+        self.speak(text, &mut |frame| frames.push(frame))?;
+        // Taken out of the map first, so waiting for a satellite that is mid-response does not
+        // hold up others connecting
+        let satellites: Vec<_> = self.satellites.lock().unwrap().values().cloned().collect();
+        for satellite in satellites {
+            let satellite = satellite.lock().unwrap();
+            for frame in &frames {
+                let _ = satellite.send(frame.clone());
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_connection(&self, stream: TcpStream) -> Result<()> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let span = info_span!("satellite", id, name = tracing::field::Empty);
+        let _span = span.enter();
+
+        // Responses and announcements are written from other threads, so all writes go through
+        // one queue to keep frames whole
+        let (tx, rx) = mpsc::channel::<Frame>();
+        let mut writer = stream.try_clone()?;
+        let writer_span = Span::current();
+        thread::spawn(move || {
+            let _span = writer_span.enter();
+            for frame in rx {
+                if let Err(e) = write_frame(&mut writer, &frame) {
+                    debug!(error = %e, "failed to write to satellite");
+                    break;
+                }
+            }
+        });
+        let tx = Arc::new(Mutex::new(tx));
+        self.satellites.lock().unwrap().insert(id, tx.clone());
+
+        let result = self.read_frames(stream, &tx, &span);
+        self.satellites.lock().unwrap().remove(&id);
+        info!("satellite disconnected");
+        result
+    }
+
+    fn read_frames(
+        &self,
+        mut stream: TcpStream,
+        tx: &Mutex<mpsc::Sender<Frame>>,
+        span: &Span,
+    ) -> Result<()> {
+        let mut utterance: Option<(u32, Vec<f32>)> = None;
+        while let Some(frame) = read_frame(&mut stream)? {
+            match frame {
+                Frame::Hello(name) => {
+                    span.record("name", name.as_str());
+                    info!(%name, "satellite connected");
+                }
+                Frame::WakeWord(probability) => info!(probability, "wake word detected"),
+                Frame::AudioStart(sample_rate) => utterance = Some((sample_rate, Vec::new())),
+                Frame::Audio(pcm) => match &mut utterance {
+                    Some((_, samples)) => samples.extend(pcm_to_f32(&pcm)),
+                    None => warn!("audio without a start, dropping it"),
+                },
+                Frame::AudioEnd => {
+                    let Some((sample_rate, samples)) = utterance.take() else {
+                        continue;
+                    };
+                    let _interaction = info_span!("interaction").entered();
+                    let audio = match to_whisper_input(&samples, sample_rate, 1) {
+                        Ok(audio) => audio,
+                        Err(e) => {
+                            warn!(error = %e, "dropping utterance");
+                            continue;
+                        }
+                    };
+                    let (transcript, response) = self.respond(audio);
+                    let tx = tx.lock().unwrap();
+                    let _ = tx.send(Frame::Transcript(transcript));
+                    let _ = tx.send(Frame::Response(response.clone()));
+                    if let Err(e) = self.speak(&response, &mut |frame| {
+                        let _ = tx.send(frame);
+                    }) {
+                        error!(error = %e, "failed to synthesize response");
+                    }
+                }
+                frame => warn!(?frame, "unexpected frame from satellite"),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Accept satellites forever, each on its own thread, and announce timers from `timer_events`
+/// on all of them.
+pub fn serve(
+    listener: TcpListener,
+    server: Arc<Server>,
+    timer_events: mpsc::Receiver<TimerEvent>,
+) -> Result<()> {
+    let timer_server = server.clone();
+    thread::spawn(move || {
+        for event in timer_events {
+            let message = event.announcement();
+            let _span = info_span!("timer_announcement").entered();
+            info!(message = %message, "timer fired");
+            if let Err(e) = timer_server.announce(&message) {
+                error!(error = %e, "failed to announce timer");
+            }
+        }
+    });
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!(error = %e, "failed to accept satellite connection");
+                continue;
+            }
+        };
+        let server = server.clone();
+        thread::spawn(move || {
+            if let Err(e) = server.handle_connection(stream) {
+                warn!(error = %e, "satellite connection failed");
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Run whisper, the command executor and TTS for satellites connecting on
+/// `satellite.listen_address`, until the process is stopped.
+pub fn run_satellite_server(config: &AssistantConfig) -> Result<()> {
+    let backend = LocalBackend::load(config)?;
+    let command_executor_config = create_command_executor_config(config)?;
+
+    let (timer_tx, timer_rx) = mpsc::channel::<TimerEvent>();
+    let timer_manager = TimerManager::new(timer_tx);
+    match timer_manager.restore(&config.timers.state_path) {
+        Ok(0) => {}
+        Ok(count) => info!(count, "restored timers from the previous run"),
+        Err(e) => warn!(error = %e, "failed to restore timers"),
+    }
+
+    let server = Arc::new(Server::new(
+        Box::new(backend),
+        command_executor_config,
+        timer_manager,
+        &config.wake_word.activation_text,
+    ));
+
+    // Satellites reconnect on their own, so stopping only has to keep the timers
+    let signal_server = server.clone();
+    let state_path = config.timers.state_path.clone();
+    ctrlc::set_handler(move || {
+        info!("signal received, shutting down");
+        match signal_server.timer_manager.save(&state_path) {
+            Ok(0) => {}
+            Ok(count) => info!(count, path = %state_path.display(), "saved timers"),
+            Err(e) => error!(error = %e, "failed to save timers"),
+        }
+        std::process::exit(0);
+    })?;
+
+    let address = config.satellite.listen_address;
+    let listener =
+        TcpListener::bind(address).wrap_err_with(|| format!("failed to listen on {}", address))?;
+    info!(%address, "waiting for satellites");
+    serve(listener, server, timer_rx)
+}
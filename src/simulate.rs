@@ -0,0 +1,88 @@
+use std::io::{BufRead, Write};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+
+use color_eyre::eyre::Result;
+
+use crate::command_executor::{self, TimerEvent, TimerManager};
+use crate::config::AssistantConfig;
+use crate::create_command_executor_config;
+use crate::tts_client::TtsClient;
+
+/// Read commands from stdin and run them through the command executor exactly as if whisper had
+/// transcribed them. Timer announcements are printed from a background thread as they fire.
+pub fn simulate(config: &AssistantConfig, speak: bool) -> Result<()> {
+    let command_executor_config = create_command_executor_config(config)?;
+
+    let tts_client = if speak {
+        Some(Arc::new(Mutex::new(TtsClient::new(
+            &config.tts.model_path,
+            &config.tts.voice_path,
+        )?)))
+    } else {
+        None
+    };
+
+    let (timer_tx, timer_rx) = mpsc::channel::<TimerEvent>();
+    let timer_manager = TimerManager::new(timer_tx);
+
+    let timer_tts_client = tts_client.clone();
+    let alarm_volume = config.timers.alarm_volume;
+    thread::spawn(move || {
+        for event in timer_rx {
+            let message = event.announcement();
+            println!("\nTimer fired: {}", message);
+            print!("> ");
+            let _ = std::io::stdout().flush();
+
+            if let Some(tts_client) = &timer_tts_client {
+                let mut tts_client = tts_client.lock().unwrap();
+                let result = tts_client
+                    .set_volume(alarm_volume)
+                    .and_then(|_| tts_client.generate_audio(message))
+                    .and_then(|_| tts_client.set_volume(1.0));
+                if let Err(e) = result {
+                    eprintln!("Failed to speak timer announcement: {}", e);
+                }
+            }
+        }
+    });
+
+    println!("Type a command as it would be spoken (without the wake word). Ctrl-D to exit.");
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("> ");
+        std::io::stdout().flush()?;
+
+        let Some(line) = lines.next() else {
+            break;
+        };
+        let line = line?;
+        let command = line.trim();
+        if command.is_empty() {
+            continue;
+        }
+
+        let response_text = match command_executor::execute_command(
+            &command_executor_config,
+            &timer_manager,
+            command,
+        ) {
+            Ok(response_text) => response_text,
+            Err(e) => {
+                println!("Error executing command: {}", e);
+                "Something went wrong. Please try again.".to_string()
+            }
+        };
+        println!("{}", response_text);
+
+        if let Some(tts_client) = &tts_client
+            && let Err(e) = tts_client.lock().unwrap().generate_audio(response_text)
+        {
+            println!("Error speaking response: {}", e);
+        }
+    }
+
+    Ok(())
+}
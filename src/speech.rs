@@ -3,10 +3,12 @@ use std::{sync::mpsc, thread};
 use color_eyre::eyre::{Context, Result};
 use whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperState};
 
+use crate::config::{SttConfig, SttStrategy};
+
 pub struct SpeechSegment {
-    #[allow(dead_code)]
+    /// Start of the segment in centiseconds from the start of the utterance
     pub start_timestamp: i64,
-    #[allow(dead_code)]
+    /// End of the segment in centiseconds from the start of the utterance
     pub end_timestamp: i64,
     pub text: String,
 }
@@ -18,8 +20,36 @@ struct SpeechDetector {
 }
 
 impl SpeechDetector {
-    pub fn new(ctx: WhisperContext, sampling_strategy: SamplingStrategy) -> Result<Self> {
-        let params = FullParams::new(sampling_strategy);
+    pub fn new(ctx: WhisperContext, config: &SttConfig) -> Result<Self> {
+        let sampling_strategy = match config.strategy {
+            SttStrategy::Greedy => SamplingStrategy::Greedy {
+                best_of: config.best_of,
+            },
+            SttStrategy::BeamSearch => SamplingStrategy::BeamSearch {
+                beam_size: config.beam_size,
+                patience: -1.0,
+            },
+        };
+        let mut params = FullParams::new(sampling_strategy);
+
+        // whisper keeps a borrowed language string, so use its own static name for the language
+        let language = match config.language.as_str() {
+            "auto" => None,
+            language => Some(
+                whisper_rs::get_lang_id(language)
+                    .and_then(whisper_rs::get_lang_str)
+                    .ok_or_else(|| {
+                        color_eyre::eyre::eyre!("unknown whisper language: {}", language)
+                    })?,
+            ),
+        };
+        params.set_language(language);
+        if let Some(threads) = config.threads {
+            params.set_n_threads(threads);
+        }
+        params.set_temperature_inc(config.temperature_inc);
+        params.set_no_speech_thold(config.no_speech_threshold);
+
         Ok(Self {
             whisper: ctx.create_state()?,
             whisper_params: params,
@@ -60,41 +90,40 @@ impl SpeechDetector {
 pub enum TextToSpeechEvent {
     ConvertSpeechToText {
         audio_data: Vec<f32>,
-        response_tx: oneshot::Sender<Vec<SpeechSegment>>,
+        response_tx: oneshot::Sender<Result<Vec<SpeechSegment>>>,
     },
-    #[allow(dead_code)]
     Stop,
 }
 
 pub struct SpeechToTextClient {
     channel_tx: mpsc::Sender<TextToSpeechEvent>,
-    #[allow(dead_code)]
     thread_handle: thread::JoinHandle<Result<()>>,
 }
 
 impl SpeechToTextClient {
-    pub fn new(ctx: WhisperContext, sampling_strategy: SamplingStrategy) -> Result<Self> {
-        let mut speech_detector = SpeechDetector::new(ctx, sampling_strategy)?;
+    pub fn new(ctx: WhisperContext, config: &SttConfig) -> Result<Self> {
+        let mut speech_detector = SpeechDetector::new(ctx, config)?;
         let (channel_tx, channel_rx) = mpsc::channel();
         let thread_handle = thread::spawn(move || {
-            loop {
-                while let Ok(event) = channel_rx.recv() {
-                    match event {
-                        TextToSpeechEvent::ConvertSpeechToText {
-                            audio_data,
-                            response_tx,
-                        } => {
-                            speech_detector.add_audio_data(audio_data);
-                            let segments = speech_detector.process()?;
-                            response_tx.send(segments).unwrap();
-                            speech_detector.clear_audio_data();
-                        }
-                        TextToSpeechEvent::Stop => {
-                            break;
-                        }
+            // Runs until told to stop or the client is dropped
+            while let Ok(event) = channel_rx.recv() {
+                match event {
+                    TextToSpeechEvent::ConvertSpeechToText {
+                        audio_data,
+                        response_tx,
+                    } => {
+                        // A failed transcription is the caller's problem, the worker keeps going
+                        // so the next one can succeed
+                        speech_detector.add_audio_data(audio_data);
+                        let segments = speech_detector.process();
+                        speech_detector.clear_audio_data();
+                        // The caller may have stopped waiting
+                        let _ = response_tx.send(segments);
                     }
+                    TextToSpeechEvent::Stop => break,
                 }
             }
+            Ok(())
         });
         Ok(Self {
             channel_tx,
@@ -112,6 +141,14 @@ impl SpeechToTextClient {
             });
         response_rx
             .recv()
-            .wrap_err("Failed to receive response from speech detector")
+            .wrap_err("Failed to receive response from speech detector")?
+    }
+
+    /// Stop the worker thread once any in-flight transcription is done and wait for it to exit.
+    pub fn stop(self) -> Result<()> {
+        let _ = self.channel_tx.send(TextToSpeechEvent::Stop);
+        self.thread_handle
+            .join()
+            .map_err(|_| color_eyre::eyre::eyre!("speech to text worker panicked"))?
     }
 }
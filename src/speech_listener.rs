@@ -1,23 +1,134 @@
 use std::{
     collections::VecDeque,
-    sync::{Arc, Mutex, mpsc},
-    time::Duration,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 use color_eyre::eyre::Result;
 use cpal::traits::DeviceTrait;
-use cpal::{Device, SampleFormat, Stream, StreamConfig};
-use oww_rs::{
-    mic::{process_audio::resample_into_chunks, resampler::make_resampler},
-    oww::{OWW_MODEL_CHUNK_SIZE, OwwModel},
-};
+use cpal::{Device, SampleFormat, Stream, StreamConfig, StreamError};
+use oww_rs::oww::{OWW_MODEL_CHUNK_SIZE, OwwModel};
+use rtrb::{Consumer, Producer, RingBuffer};
+use tracing::{debug, warn};
 use voice_activity_detector::VoiceActivityDetector;
 
-use crate::audio_resampler::AudioResampler;
+use crate::audio_frontend::{AudioFrontEnd, Tap};
+use crate::audio_resampler::Downmix;
+use crate::config::{ResamplerQuality, WhileSpeakingPolicy};
+use crate::metrics::METRICS;
 
-const SAMPLE_RATE: u32 = 16000;
+/// Rate of the audio handed out in [`SpeechEvent::SpeechDetected`].
+pub const SAMPLE_RATE: u32 = 16000;
 const CHUNK_SIZE: usize = 512;
 
+/// Settings for the wake word and end-of-speech detection in [`SpeechPipeline`].
+#[derive(Debug, Clone)]
+pub struct SpeechPipelineConfig {
+    /// openWakeWord ONNX model; the bundled "alexa" model is used when `None`
+    pub wake_word_model_path: Option<PathBuf>,
+    pub wake_word_threshold: f32,
+    pub vad_threshold: f32,
+    pub silence_seconds: f64,
+    /// How long to wait after the wake word for speech to start before giving up
+    pub no_speech_timeout: Duration,
+    /// Longest utterance before it is cut off and transcribed regardless of the VAD
+    pub max_utterance: Duration,
+    pub rolling_buffer_duration_seconds: f64,
+    /// Whether the assistant is talking, reported by the TTS client
+    pub playback: Arc<PlaybackState>,
+    pub while_speaking: WhileSpeakingPolicy,
+    pub speaking_threshold: f32,
+    pub speaking_hangover: Duration,
+    /// Requests from the event loop, such as listening for a follow-up
+    pub control: Arc<PipelineControl>,
+    /// How a multichannel input is turned into mono
+    pub downmix: Downmix,
+    pub resampler_quality: ResamplerQuality,
+}
+
+/// Lets the event loop steer the speech pipeline, which runs on the audio thread.
+#[derive(Debug, Default)]
+pub struct PipelineControl {
+    follow_up_window: Mutex<Option<Duration>>,
+}
+
+impl PipelineControl {
+    /// Listen for a command without the wake word for up to `window`. Ignored if the pipeline is
+    /// already listening, e.g. because the wake word was said during the response.
+    pub fn request_follow_up(&self, window: Duration) {
+        *self.follow_up_window.lock().unwrap() = Some(window);
+    }
+
+    fn take_follow_up_request(&self) -> Option<Duration> {
+        self.follow_up_window.lock().unwrap().take()
+    }
+}
+
+/// Whether the assistant is talking. The TTS client reports when playback starts and stops, and
+/// the speech pipeline uses it to keep the assistant's own voice from triggering the wake word.
+#[derive(Debug, Default)]
+pub struct PlaybackState {
+    playing: AtomicBool,
+    /// When the last sound stopped, or will stop for sounds of a known length such as earcons
+    stopped_at: Mutex<Option<Instant>>,
+}
+
+impl PlaybackState {
+    pub fn set_playing(&self, playing: bool) {
+        if !playing {
+            self.stop_at(Instant::now());
+        }
+        self.playing.store(playing, Ordering::SeqCst);
+    }
+
+    /// Count a sound that plays for `duration` from now, without waiting for it to end.
+    pub fn play_for(&self, duration: Duration) {
+        self.stop_at(Instant::now() + duration);
+    }
+
+    fn stop_at(&self, at: Instant) {
+        let mut stopped_at = self.stopped_at.lock().unwrap();
+        if stopped_at.is_none_or(|stopped_at| stopped_at < at) {
+            *stopped_at = Some(at);
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing.load(Ordering::SeqCst)
+    }
+
+    /// Playing now, or stopped less than `hangover` ago.
+    fn playing_within(&self, hangover: Duration) -> bool {
+        self.playing.load(Ordering::SeqCst)
+            || self
+                .stopped_at
+                .lock()
+                .unwrap()
+                .is_some_and(|stopped_at| Instant::now() < stopped_at + hangover)
+    }
+}
+
+/// Whether a wake word detected with `probability` counts, given whether the assistant is talking.
+fn accept_wake_word(
+    policy: WhileSpeakingPolicy,
+    speaking: bool,
+    probability: f32,
+    speaking_threshold: f32,
+) -> bool {
+    match policy {
+        _ if !speaking => true,
+        WhileSpeakingPolicy::Normal => true,
+        WhileSpeakingPolicy::RaiseThreshold => probability >= speaking_threshold,
+        WhileSpeakingPolicy::Ignore => false,
+    }
+}
+
 /// Rolling buffer that stores audio chunks with a configurable maximum duration.
 /// When a wake word is detected, this buffer can be drained to include preceding audio
 /// that occurred before the wake word detection (which has inherent latency).
@@ -45,6 +156,10 @@ impl RollingBuffer {
         self.chunks.push_back(chunk);
     }
 
+    fn clear(&mut self) {
+        self.chunks.clear();
+    }
+
     /// Drain all chunks from the buffer and flatten them into a single Vec<f32>.
     fn drain_flat(&mut self) -> Vec<f32> {
         let mut result = Vec::new();
@@ -65,62 +180,67 @@ struct InProgressSpeechState {
     /// True means non-speech, false means speech
     /// This is used to ensure we wait for N seconds of no speech before declaring speech end
     past_has_been_speech: VecDeque<bool>,
+    /// Whether the VAD has heard speech yet; silence only ends an utterance that has started
+    heard_speech: bool,
 }
 
 #[derive(Debug, Default)]
 enum SpeechListenerState {
     #[default]
     WaitingForWakeWord,
+    /// After a response, listening for speech without the wake word until `remaining` runs out
+    WaitingForFollowUp {
+        remaining: Duration,
+    },
     ListeningForEndOfSpeech(InProgressSpeechState),
 }
 
-struct WakeWordDetector {
-    buffer: Arc<Mutex<Vec<f32>>>,
-    channels: usize,
-    audio_resampler: oww_rs::mic::resampler::Resamplers,
+pub struct WakeWordDetector {
     model: OwwModel,
 }
 
 impl WakeWordDetector {
     /// Create a new wake word detector.
+    /// `model_path` is an openWakeWord ONNX file, or `None` for the bundled "alexa" model.
     /// `threshold` is the detection threshold passed to OwwModel (typically 0.3).
-    /// `channels` is the number of audio channels.
-    /// `input_rate` is the input sample rate.
-    fn new(threshold: f32, channels: usize, input_rate: u32) -> Result<Self> {
-        let audio_resampler =
-            make_resampler(input_rate as _, OWW_MODEL_CHUNK_SIZE as _, channels as _)
-                .map_err(|e| color_eyre::eyre::eyre!("failed to create OWW resampler: {}", e))?;
-
-        let model = OwwModel::new(
-            oww_rs::config::SpeechUnlockType::OpenWakeWordAlexa,
-            threshold,
-        )
-        .map_err(|e| color_eyre::eyre::eyre!("failed to load OWW model: {}", e))?;
-
-        Ok(Self {
-            buffer: Arc::new(Mutex::new(Vec::new())),
-            channels,
-            audio_resampler,
-            model,
-        })
+    pub fn new(model_path: Option<&Path>, threshold: f32) -> Result<Self> {
+        let model = match model_path {
+            Some(model_path) => {
+                let unlock_word = model_path
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                OwwModel::from_file(model_path, unlock_word, threshold).map_err(|e| {
+                    color_eyre::eyre::eyre!(
+                        "failed to load OWW model {}: {}",
+                        model_path.display(),
+                        e
+                    )
+                })?
+            }
+            None => OwwModel::new(
+                oww_rs::config::SpeechUnlockType::OpenWakeWordAlexa,
+                threshold,
+            )
+            .map_err(|e| color_eyre::eyre::eyre!("failed to load OWW model: {}", e))?,
+        };
+
+        Ok(Self { model })
     }
 
-    /// Detect wake word in raw audio data.
-    /// Returns true if wake word is detected.
-    fn detect(&mut self, data: &[f32]) -> bool {
-        let chunks = resample_into_chunks(
-            data,
-            &self.buffer.clone(),
-            self.channels as _,
-            &mut self.audio_resampler,
-        );
+    /// Detect wake word in 16 kHz mono chunks of [`OWW_MODEL_CHUNK_SIZE`] samples.
+    /// Returns the model's probability if the wake word is detected.
+    pub fn detect(&mut self, chunks: Vec<Vec<f32>>) -> Option<f32> {
         for chunk in chunks {
-            let d = self.model.detection(chunk.data_f32.first().clone());
+            let d = self.model.detection(chunk);
+            METRICS
+                .wake_word_inference_seconds
+                .observe(d.duration_ms as f64 / 1000.0);
             if d.detected {
-                return true;
+                return Some(d.probability);
             }
         }
-        false
+        None
     }
 }
 
@@ -130,14 +250,77 @@ enum EndOfSpeechResult {
         audio_data: Vec<f32>,
         duration: Duration,
     },
+    /// Nothing was said within the no-speech timeout
+    NoSpeech,
+    /// The utterance hit the maximum length before the speaker paused
+    MaxUtteranceReached {
+        audio_data: Vec<f32>,
+        duration: Duration,
+    },
 }
 
-struct EndOfSpeechDetector {
-    vad: VoiceActivityDetector,
-    probability_threshold: f32,
+/// When an utterance ends, given the VAD's verdict on each chunk.
+#[derive(Debug, Clone)]
+struct EndpointRules {
     chunk_duration: Duration,
     /// Number of consecutive non-speech chunks needed to declare end of speech
     silence_chunks_needed: usize,
+    no_speech_timeout: Duration,
+    max_utterance: Duration,
+}
+
+impl EndpointRules {
+    /// Add one chunk to the utterance and decide whether it is over.
+    fn advance(
+        &self,
+        mut speech: InProgressSpeechState,
+        chunk: Vec<f32>,
+        is_speech: bool,
+    ) -> EndOfSpeechResult {
+        if is_speech {
+            // Speech detected
+            speech.heard_speech = true;
+            if speech.past_has_been_speech.len() >= self.silence_chunks_needed {
+                speech.past_has_been_speech.pop_front();
+            }
+            speech.past_has_been_speech.push_back(false); // false = speech
+        } else if speech.past_has_been_speech.len() >= self.silence_chunks_needed {
+            // Check if all recent chunks were non-speech
+            let all_non_speech = speech.past_has_been_speech.iter().all(|&b| b);
+            if all_non_speech && speech.heard_speech {
+                // Configured silence duration reached - speech has ended
+                return EndOfSpeechResult::SpeechEnded {
+                    audio_data: speech.audio_data,
+                    duration: speech.speech_duration,
+                };
+            }
+            // Some speech in the window, or none yet, continue listening
+            speech.past_has_been_speech.pop_front();
+            speech.past_has_been_speech.push_back(true); // true = non-speech
+        } else {
+            // Not enough chunks yet, add to front and continue
+            speech.past_has_been_speech.push_front(true); // true = non-speech
+        }
+        speech.audio_data.extend(chunk);
+        speech.speech_duration += self.chunk_duration;
+
+        if !speech.heard_speech && speech.speech_duration >= self.no_speech_timeout {
+            return EndOfSpeechResult::NoSpeech;
+        }
+        if speech.speech_duration >= self.max_utterance {
+            return EndOfSpeechResult::MaxUtteranceReached {
+                audio_data: speech.audio_data,
+                duration: speech.speech_duration,
+            };
+        }
+        EndOfSpeechResult::StillListening(speech)
+    }
+}
+
+struct EndOfSpeechDetector {
+    vad: VoiceActivityDetector,
+    probability_threshold: f32,
+    rules: EndpointRules,
 }
 
 impl EndOfSpeechDetector {
@@ -146,11 +329,14 @@ impl EndOfSpeechDetector {
     /// `chunk_size` is the size of each chunk (should be 512).
     /// `probability_threshold` is the VAD threshold (typically 0.75).
     /// `silence_seconds` is how many seconds of consecutive silence triggers end-of-speech.
+    /// `no_speech_timeout` and `max_utterance` bound how long a single utterance can listen.
     fn new(
         sample_rate: u32,
         chunk_size: usize,
         probability_threshold: f32,
         silence_seconds: f64,
+        no_speech_timeout: Duration,
+        max_utterance: Duration,
     ) -> Result<Self> {
         let vad = VoiceActivityDetector::builder()
             .sample_rate(sample_rate)
@@ -164,55 +350,36 @@ impl EndOfSpeechDetector {
         Ok(Self {
             vad,
             probability_threshold,
-            chunk_duration,
-            silence_chunks_needed,
+            rules: EndpointRules {
+                chunk_duration,
+                silence_chunks_needed,
+                no_speech_timeout,
+                max_utterance,
+            },
         })
     }
 
+    fn is_speech(&mut self, chunk: &[f32]) -> bool {
+        self.vad.predict(chunk.to_vec()) > self.probability_threshold
+    }
+
     /// Process chunks and determine if speech has ended.
     /// `chunks` are pre-resampled 16kHz chunks from the pipeline.
-    /// Returns `StillListening` if speech continues, or `SpeechEnded` once the configured
-    /// silence duration of consecutive non-speech is detected.
+    /// Returns `StillListening` if speech continues, `SpeechEnded` once the configured silence
+    /// duration of consecutive non-speech is detected, or one of the timeouts.
+    /// While `playing`, the assistant's own sounds are all the VAD could hear, so nothing counts
+    /// as speech.
     fn process_chunks(
         &mut self,
         chunks: Vec<Vec<f32>>,
         mut speech: InProgressSpeechState,
+        playing: bool,
     ) -> EndOfSpeechResult {
         for chunk in chunks {
-            let probability = self.vad.predict(chunk.clone());
-
-            if probability > self.probability_threshold {
-                // Speech detected
-                if speech.past_has_been_speech.len() >= self.silence_chunks_needed {
-                    speech.past_has_been_speech.pop_front();
-                }
-                speech.past_has_been_speech.push_back(false); // false = speech
-                speech.audio_data.extend(chunk);
-                speech.speech_duration += self.chunk_duration;
-            } else {
-                // Non-speech detected
-                if speech.past_has_been_speech.len() >= self.silence_chunks_needed {
-                    // Check if all recent chunks were non-speech
-                    let all_non_speech = speech.past_has_been_speech.iter().all(|&b| b);
-                    if all_non_speech {
-                        // Configured silence duration reached - speech has ended
-                        return EndOfSpeechResult::SpeechEnded {
-                            audio_data: speech.audio_data,
-                            duration: speech.speech_duration,
-                        };
-                    } else {
-                        // Some speech in the window, continue listening
-                        speech.past_has_been_speech.pop_front();
-                        speech.past_has_been_speech.push_back(true); // true = non-speech
-                        speech.audio_data.extend(chunk);
-                        speech.speech_duration += self.chunk_duration;
-                    }
-                } else {
-                    // Not enough chunks yet, add to front and continue
-                    speech.past_has_been_speech.push_front(true); // true = non-speech
-                    speech.audio_data.extend(chunk);
-                    speech.speech_duration += self.chunk_duration;
-                }
+            let is_speech = !playing && self.is_speech(&chunk);
+            match self.rules.advance(speech, chunk, is_speech) {
+                EndOfSpeechResult::StillListening(updated_state) => speech = updated_state,
+                ended => return ended,
             }
         }
 
@@ -221,53 +388,103 @@ impl EndOfSpeechDetector {
 }
 
 pub enum SpeechEvent {
+    /// The wake word was heard and the pipeline started listening for the command
+    WakeWordDetected { probability: f32 },
     /// Speech detected, send the audio data, this needs to be f32 bit, 16KHz, mono
     SpeechDetected(Vec<f32>),
+    /// The follow-up window passed without anyone speaking
+    FollowUpExpired,
+    /// Nobody spoke within the no-speech timeout after the wake word, so listening was cancelled
+    NoSpeech,
+    /// The utterance ran into the maximum length and was cut off; the audio heard so far, in the
+    /// same format as [`SpeechEvent::SpeechDetected`]
+    MaxUtteranceReached(Vec<f32>),
 }
 
-struct SpeechPipeline {
+pub struct SpeechPipeline {
     state: SpeechListenerState,
-    audio_resampler: AudioResampler,
+    /// Shared by the wake word and the VAD so both hear the same 16 kHz mono signal
+    front_end: AudioFrontEnd,
+    wake_word_tap: Tap,
+    vad_tap: Tap,
     wake_word_detector: WakeWordDetector,
     end_of_speech_detector: EndOfSpeechDetector,
     rolling_buffer: RollingBuffer,
+    playback: Arc<PlaybackState>,
+    while_speaking: WhileSpeakingPolicy,
+    speaking_threshold: f32,
+    speaking_hangover: Duration,
+    control: Arc<PipelineControl>,
 }
 
 impl SpeechPipeline {
-    fn new(
-        config: &StreamConfig,
-        wake_word_threshold: f32,
-        vad_threshold: f32,
-        silence_seconds: f64,
-        rolling_buffer_duration_seconds: f64,
-    ) -> Result<Self> {
+    pub fn new(config: &StreamConfig, pipeline_config: &SpeechPipelineConfig) -> Result<Self> {
         let input_rate = config.sample_rate;
         let channels = config.channels;
 
-        let audio_resampler = AudioResampler::new(input_rate, SAMPLE_RATE, channels, CHUNK_SIZE);
-
-        let wake_word_detector =
-            WakeWordDetector::new(wake_word_threshold, channels as usize, input_rate)?;
-
-        let end_of_speech_detector =
-            EndOfSpeechDetector::new(SAMPLE_RATE, CHUNK_SIZE, vad_threshold, silence_seconds)?;
+        let mut front_end = AudioFrontEnd::new(
+            input_rate,
+            channels,
+            pipeline_config.downmix,
+            pipeline_config.resampler_quality,
+        )?;
+        let wake_word_tap = front_end.tap(OWW_MODEL_CHUNK_SIZE);
+        let vad_tap = front_end.tap(CHUNK_SIZE);
+
+        let wake_word_detector = WakeWordDetector::new(
+            pipeline_config.wake_word_model_path.as_deref(),
+            pipeline_config.wake_word_threshold,
+        )?;
+
+        let end_of_speech_detector = EndOfSpeechDetector::new(
+            SAMPLE_RATE,
+            CHUNK_SIZE,
+            pipeline_config.vad_threshold,
+            pipeline_config.silence_seconds,
+            pipeline_config.no_speech_timeout,
+            pipeline_config.max_utterance,
+        )?;
 
         let chunk_duration = Duration::from_secs_f64(CHUNK_SIZE as f64 / SAMPLE_RATE as f64);
-        let rolling_buffer = RollingBuffer::new(rolling_buffer_duration_seconds, chunk_duration);
+        let rolling_buffer = RollingBuffer::new(
+            pipeline_config.rolling_buffer_duration_seconds,
+            chunk_duration,
+        );
 
         Ok(Self {
             state: SpeechListenerState::WaitingForWakeWord,
-            audio_resampler,
+            front_end,
+            wake_word_tap,
+            vad_tap,
             wake_word_detector,
             end_of_speech_detector,
             rolling_buffer,
+            playback: pipeline_config.playback.clone(),
+            while_speaking: pipeline_config.while_speaking,
+            speaking_threshold: pipeline_config.speaking_threshold,
+            speaking_hangover: pipeline_config.speaking_hangover,
+            control: pipeline_config.control.clone(),
         })
     }
 
-    /// Process raw audio data and return a SpeechEvent if speech has been detected and completed.
-    fn process(&mut self, raw_data: &[f32]) -> Option<SpeechEvent> {
-        // Always resample to 16kHz chunks
-        let chunks = self.audio_resampler.resample(raw_data);
+    /// Process raw audio data and return a SpeechEvent when the wake word is heard or when
+    /// speech following it has completed.
+    pub fn process(&mut self, raw_data: &[f32]) -> Option<SpeechEvent> {
+        self.front_end.process(raw_data);
+        let chunks = self.front_end.chunks(self.vad_tap);
+        let wake_word_chunks = self.front_end.chunks(self.wake_word_tap);
+
+        if let Some(window) = self.control.take_follow_up_request()
+            && matches!(self.state, SpeechListenerState::WaitingForWakeWord)
+        {
+            debug!(
+                window_seconds = window.as_secs_f64(),
+                "listening for a follow-up"
+            );
+            // Whatever is buffered is the tail of the response, not the user
+            self.rolling_buffer.clear();
+            self.state = SpeechListenerState::WaitingForFollowUp { remaining: window };
+        }
 
         // Use mem::take to avoid borrow checker issues
         let state = std::mem::take(&mut self.state);
@@ -279,9 +496,26 @@ impl SpeechPipeline {
                     self.rolling_buffer.push(chunk.clone());
                 }
 
-                // Check for wake word on raw data
-                if self.wake_word_detector.detect(raw_data) {
-                    println!("Wake word detected!");
+                // Check for wake word. The detector always runs so its state stays
+                // current; only the decision depends on whether the assistant is talking
+                let detection =
+                    self.wake_word_detector
+                        .detect(wake_word_chunks)
+                        .filter(|&probability| {
+                            let speaking = self.playback.playing_within(self.speaking_hangover);
+                            let accepted = accept_wake_word(
+                                self.while_speaking,
+                                speaking,
+                                probability,
+                                self.speaking_threshold,
+                            );
+                            if !accepted {
+                                debug!(probability, "wake word ignored while speaking");
+                            }
+                            accepted
+                        });
+                if let Some(probability) = detection {
+                    debug!(probability, "wake word detected");
 
                     // Drain the rolling buffer to get preceding audio
                     let preceding_audio = self.rolling_buffer.drain_flat();
@@ -292,11 +526,17 @@ impl SpeechPipeline {
                             speech_duration: Duration::from_secs(0),
                             audio_data: preceding_audio,
                             past_has_been_speech: VecDeque::new(),
+                            heard_speech: false,
                         });
+                    Some(SpeechEvent::WakeWordDetected { probability })
                 } else {
                     self.state = SpeechListenerState::WaitingForWakeWord;
+                    None
                 }
-                None
+            }
+            SpeechListenerState::WaitingForFollowUp { remaining } => {
+                let _ = self.wake_word_detector.detect(wake_word_chunks);
+                self.wait_for_follow_up(chunks, remaining)
             }
             SpeechListenerState::ListeningForEndOfSpeech(in_progress_speech_state) => {
                 // Keep the wake word detector's internal state current by feeding it audio,
@@ -304,101 +544,280 @@ impl SpeechPipeline {
                 // Without this, the model's internal activation from the previous wake word
                 // detection remains frozen and immediately re-triggers when we return to
                 // WaitingForWakeWord.
-                let _ = self.wake_word_detector.detect(raw_data);
-
-                // Process chunks through end-of-speech detector
-                match self
-                    .end_of_speech_detector
-                    .process_chunks(chunks, in_progress_speech_state)
-                {
-                    EndOfSpeechResult::StillListening(updated_state) => {
-                        self.state = SpeechListenerState::ListeningForEndOfSpeech(updated_state);
-                        None
-                    }
-                    EndOfSpeechResult::SpeechEnded {
-                        audio_data,
-                        duration,
-                    } => {
-                        println!("Speech detected for {:.2} seconds", duration.as_secs_f64());
-                        self.state = SpeechListenerState::WaitingForWakeWord;
-                        Some(SpeechEvent::SpeechDetected(audio_data))
-                    }
-                }
+                let _ = self.wake_word_detector.detect(wake_word_chunks);
+                self.listen_for_end_of_speech(chunks, in_progress_speech_state)
             }
         }
     }
+
+    /// Buffer audio until the VAD hears speech, which then starts an utterance as if the wake
+    /// word had been said, or until the window runs out.
+    fn wait_for_follow_up(
+        &mut self,
+        chunks: Vec<Vec<f32>>,
+        mut remaining: Duration,
+    ) -> Option<SpeechEvent> {
+        // The assistant's own voice must not count as the user speaking
+        let speaking = self.playback.playing_within(self.speaking_hangover);
+        let chunk_duration = self.end_of_speech_detector.rules.chunk_duration;
+
+        let mut chunks = chunks.into_iter();
+        while let Some(chunk) = chunks.next() {
+            let is_speech = !speaking && self.end_of_speech_detector.is_speech(&chunk);
+            self.rolling_buffer.push(chunk);
+            if is_speech {
+                debug!("follow-up speech started");
+                let in_progress_speech_state = InProgressSpeechState {
+                    speech_duration: chunk_duration,
+                    audio_data: self.rolling_buffer.drain_flat(),
+                    past_has_been_speech: VecDeque::from([false]),
+                    heard_speech: true,
+                };
+                return self.listen_for_end_of_speech(chunks.collect(), in_progress_speech_state);
+            }
+
+            remaining = remaining.saturating_sub(chunk_duration);
+            if remaining.is_zero() {
+                debug!("no follow-up");
+                self.state = SpeechListenerState::WaitingForWakeWord;
+                return Some(SpeechEvent::FollowUpExpired);
+            }
+        }
+
+        self.state = SpeechListenerState::WaitingForFollowUp { remaining };
+        None
+    }
+
+    fn listen_for_end_of_speech(
+        &mut self,
+        chunks: Vec<Vec<f32>>,
+        in_progress_speech_state: InProgressSpeechState,
+    ) -> Option<SpeechEvent> {
+        // An earcon or speech playing right now must not start or extend the utterance. Unlike
+        // the wake word this ignores the hangover, the user may start talking as soon as the
+        // wake word sound ends
+        let playing = self.playback.playing_within(Duration::ZERO);
+        // Process chunks through end-of-speech detector
+        match self
+            .end_of_speech_detector
+            .process_chunks(chunks, in_progress_speech_state, playing)
+        {
+            EndOfSpeechResult::StillListening(updated_state) => {
+                self.state = SpeechListenerState::ListeningForEndOfSpeech(updated_state);
+                None
+            }
+            EndOfSpeechResult::SpeechEnded {
+                audio_data,
+                duration,
+            } => {
+                debug!(
+                    duration_seconds = duration.as_secs_f64(),
+                    "end of speech detected"
+                );
+                self.state = SpeechListenerState::WaitingForWakeWord;
+                Some(SpeechEvent::SpeechDetected(audio_data))
+            }
+            EndOfSpeechResult::NoSpeech => {
+                debug!("no speech after the wake word");
+                self.state = SpeechListenerState::WaitingForWakeWord;
+                Some(SpeechEvent::NoSpeech)
+            }
+            EndOfSpeechResult::MaxUtteranceReached {
+                audio_data,
+                duration,
+            } => {
+                // Usually something like a TV keeping the VAD busy, so worth seeing
+                warn!(
+                    duration_seconds = duration.as_secs_f64(),
+                    "maximum utterance length reached, transcribing what was heard"
+                );
+                self.state = SpeechListenerState::WaitingForWakeWord;
+                Some(SpeechEvent::MaxUtteranceReached(audio_data))
+            }
+        }
+    }
+}
+
+/// Seconds of input the ring buffer between the audio callback and the processing thread holds.
+const RING_BUFFER_SECONDS: f64 = 2.0;
+
+/// Audio handed to the pipeline per call, shorter than one VAD chunk.
+const PROCESSING_BLOCK: Duration = Duration::from_millis(10);
+
+/// How long the processing thread sleeps when the ring buffer is empty.
+const PROCESSING_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Dropped audio is logged at most this often, the counter keeps the full tally.
+const DROP_WARNING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Liveness of an input stream, shared between its cpal callbacks and whoever supervises it.
+/// cpal never reports a device that silently stops delivering audio, so the callback counter is
+/// what lets a stalled stream be told apart from a healthy one.
+#[derive(Default)]
+pub struct StreamHealth {
+    callbacks: AtomicU64,
+    dropped_frames: AtomicU64,
+    error: Mutex<Option<String>>,
+}
+
+impl StreamHealth {
+    fn record_callback(&self) {
+        self.callbacks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Called from the audio callback, so this must not block or log.
+    fn record_dropped(&self, frames: usize) {
+        if frames > 0 {
+            self.dropped_frames
+                .fetch_add(frames as u64, Ordering::Relaxed);
+            METRICS.audio_frames_dropped.inc_by(frames as u64);
+        }
+    }
+
+    fn record_error(&self, err: StreamError) {
+        warn!(error = %err, "stream error");
+        // Overruns drop a few samples but the stream keeps going, so they are not worth a rebuild
+        if matches!(err, StreamError::BufferUnderrun) {
+            METRICS.audio_callback_overruns.inc();
+            return;
+        }
+        let mut error = self.error.lock().unwrap();
+        if error.is_none() {
+            *error = Some(err.to_string());
+        }
+    }
+
+    /// Number of data callbacks delivered so far.
+    pub fn callbacks(&self) -> u64 {
+        self.callbacks.load(Ordering::Relaxed)
+    }
+
+    /// Input frames dropped because the processing thread fell behind.
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped_frames.load(Ordering::Relaxed)
+    }
+
+    /// The first fatal error reported by the stream, if any.
+    pub fn error(&self) -> Option<String> {
+        self.error.lock().unwrap().clone()
+    }
 }
 
+/// Copy one callback's interleaved samples into the ring buffer without blocking or allocating.
+/// Only whole frames are written, so the channels stay aligned; returns the frames that did not
+/// fit.
+fn push_frames(
+    producer: &mut Producer<f32>,
+    samples: impl ExactSizeIterator<Item = f32>,
+    channels: usize,
+) -> usize {
+    let len = samples.len();
+    let room = producer.slots();
+    let writable = len.min(room - room % channels);
+    if let Ok(chunk) = producer.write_chunk_uninit(writable) {
+        chunk.fill_from_iter(samples);
+    }
+    (len - writable) / channels
+}
+
+/// Run the pipeline on whatever the callback has buffered until the stream is dropped or nobody
+/// listens for events anymore.
+fn process_audio(
+    mut pipeline: SpeechPipeline,
+    mut consumer: Consumer<f32>,
+    block_samples: usize,
+    health: Arc<StreamHealth>,
+    events: mpsc::Sender<SpeechEvent>,
+) {
+    let mut reported_drops = 0;
+    let mut last_drop_warning: Option<Instant> = None;
+    loop {
+        let available = consumer.slots();
+        if available == 0 {
+            if consumer.is_abandoned() {
+                return;
+            }
+            thread::sleep(PROCESSING_POLL_INTERVAL);
+            continue;
+        }
+
+        // Small blocks, so one call never covers more than one VAD chunk and nothing after an
+        // event is lost when the thread catches up on a backlog
+        let chunk = consumer
+            .read_chunk(available.min(block_samples))
+            .expect("slots were just counted");
+        let (first, second) = chunk.as_slices();
+        for samples in [first, second] {
+            if let Some(event) = pipeline.process(samples)
+                && events.send(event).is_err()
+            {
+                return;
+            }
+        }
+        chunk.commit_all();
+
+        let dropped = health.dropped_frames();
+        if dropped > reported_drops
+            && last_drop_warning.is_none_or(|at| at.elapsed() >= DROP_WARNING_INTERVAL)
+        {
+            warn!(
+                frames = dropped - reported_drops,
+                total = dropped,
+                "audio processing fell behind, dropped input"
+            );
+            reported_drops = dropped;
+            last_drop_warning = Some(Instant::now());
+        }
+    }
+}
+
+/// Open an input stream whose callback only copies samples into a lock-free ring buffer. A
+/// processing thread runs the speech pipeline on them and sends what it hears on the returned
+/// receiver, so slow inference can never stall the audio device.
 pub fn create_stream(
     device: Device,
     config: StreamConfig,
     sample_format: SampleFormat,
-    wake_word_threshold: f32,
-    vad_threshold: f32,
-    silence_seconds: f64,
-    rolling_buffer_duration_seconds: f64,
-) -> Result<(Stream, mpsc::Receiver<SpeechEvent>)> {
-    let pipeline = Arc::new(Mutex::new(SpeechPipeline::new(
-        &config,
-        wake_word_threshold,
-        vad_threshold,
-        silence_seconds,
-        rolling_buffer_duration_seconds,
-    )?));
+    pipeline_config: &SpeechPipelineConfig,
+) -> Result<(Stream, mpsc::Receiver<SpeechEvent>, Arc<StreamHealth>)> {
+    let pipeline = SpeechPipeline::new(&config, pipeline_config)?;
+    let channels = config.channels.max(1) as usize;
+    let capacity = (config.sample_rate as f64 * RING_BUFFER_SECONDS) as usize * channels;
+    let (mut producer, consumer) = RingBuffer::<f32>::new(capacity);
+    let block_samples =
+        ((config.sample_rate as f64 * PROCESSING_BLOCK.as_secs_f64()) as usize).max(1) * channels;
 
     // Channel to send audio data assumes f32 bit, 16KHz, mono
     let (channel_tx, channel_rx) = mpsc::channel::<SpeechEvent>();
+    let health = Arc::new(StreamHealth::default());
+    let callback_health = health.clone();
+    let error_health = health.clone();
 
     let stream = match sample_format {
-        SampleFormat::F32 => {
-            let pipeline_clone = pipeline.clone();
-            device.build_input_stream(
-                &config,
-                move |data: &[f32], _: &cpal::InputCallbackInfo| {
-                    let mut pipeline_guard = match pipeline_clone.lock() {
-                        Ok(guard) => guard,
-                        Err(e) => {
-                            eprintln!("Failed to acquire pipeline lock: {e}");
-                            return;
-                        }
-                    };
-
-                    if let Some(event) = pipeline_guard.process(data) {
-                        let _ = channel_tx.send(event);
-                    }
-                },
-                |err| eprintln!("Stream error: {err}"),
-                None,
-            )?
-        }
-        SampleFormat::I16 => {
-            let pipeline_clone = pipeline.clone();
-            device.build_input_stream(
-                &config,
-                move |data: &[i16], _: &cpal::InputCallbackInfo| {
-                    // Convert i16 to f32
-                    let data_f32: Vec<f32> =
-                        // Convert I16 samples to F32: I16 range is -32768 to 32767,
-                        // we divide by 32768.0 to normalize to F32 range of -1.0 to 1.0.
-                        // This is the standard audio sample conversion formula.
-                        data.iter().map(|&sample| sample as f32 / 32768.0).collect();
-
-                    let mut pipeline_guard = match pipeline_clone.lock() {
-                        Ok(guard) => guard,
-                        Err(e) => {
-                            eprintln!("Failed to acquire pipeline lock: {e}");
-                            return;
-                        }
-                    };
-
-                    if let Some(event) = pipeline_guard.process(&data_f32) {
-                        let _ = channel_tx.send(event);
-                    }
-                },
-                |err| eprintln!("Stream error: {err}"),
-                None,
-            )?
-        }
+        SampleFormat::F32 => device.build_input_stream(
+            &config,
+            move |data: &[f32], _: &cpal::InputCallbackInfo| {
+                callback_health.record_callback();
+                let dropped = push_frames(&mut producer, data.iter().copied(), channels);
+                callback_health.record_dropped(dropped);
+            },
+            move |err| error_health.record_error(err),
+            None,
+        )?,
+        SampleFormat::I16 => device.build_input_stream(
+            &config,
+            move |data: &[i16], _: &cpal::InputCallbackInfo| {
+                callback_health.record_callback();
+                // Convert I16 samples to F32: I16 range is -32768 to 32767,
+                // we divide by 32768.0 to normalize to F32 range of -1.0 to 1.0.
+                // This is the standard audio sample conversion formula.
+                let samples = data.iter().map(|&sample| sample as f32 / 32768.0);
+                let dropped = push_frames(&mut producer, samples, channels);
+                callback_health.record_dropped(dropped);
+            },
+            move |err| error_health.record_error(err),
+            None,
+        )?,
         _ => {
             return Err(color_eyre::eyre::eyre!(
                 "Unsupported sample format: {:?}. Only F32 and I16 are supported.",
@@ -407,5 +826,143 @@ pub fn create_stream(
         }
     };
 
-    Ok((stream, channel_rx))
+    // The thread ends once the stream, and with it the producer, is dropped
+    let processing_health = health.clone();
+    thread::Builder::new()
+        .name("audio-processing".to_string())
+        .spawn(move || {
+            process_audio(
+                pipeline,
+                consumer,
+                block_samples,
+                processing_health,
+                channel_tx,
+            )
+        })?;
+
+    Ok((stream, channel_rx, health))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_frames_drops_whole_frames_that_do_not_fit() {
+        let (mut producer, mut consumer) = RingBuffer::<f32>::new(7);
+        assert_eq!(
+            push_frames(&mut producer, [1.0, 2.0, 3.0, 4.0].into_iter(), 2),
+            0
+        );
+        // Room for three more samples, which is one whole stereo frame
+        assert_eq!(
+            push_frames(&mut producer, [5.0, 6.0, 7.0, 8.0].into_iter(), 2),
+            1
+        );
+
+        let chunk = consumer.read_chunk(consumer.slots()).unwrap();
+        let (first, second) = chunk.as_slices();
+        assert_eq!([first, second].concat(), vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        chunk.commit_all();
+
+        let health = StreamHealth::default();
+        health.record_dropped(3);
+        health.record_dropped(0);
+        assert_eq!(health.dropped_frames(), 3);
+    }
+
+    /// Feed one chunk per verdict until the utterance ends; returns how it ended and after how
+    /// many chunks.
+    fn listen(verdicts: impl IntoIterator<Item = bool>) -> (EndOfSpeechResult, usize) {
+        let rules = EndpointRules {
+            chunk_duration: Duration::from_millis(100),
+            silence_chunks_needed: 3,
+            no_speech_timeout: Duration::from_secs(1),
+            max_utterance: Duration::from_secs(2),
+        };
+        let mut speech = InProgressSpeechState {
+            speech_duration: Duration::ZERO,
+            audio_data: Vec::new(),
+            past_has_been_speech: VecDeque::new(),
+            heard_speech: false,
+        };
+        for (i, is_speech) in verdicts.into_iter().enumerate() {
+            match rules.advance(speech, vec![0.0; 4], is_speech) {
+                EndOfSpeechResult::StillListening(updated) => speech = updated,
+                ended => return (ended, i + 1),
+            }
+        }
+        (EndOfSpeechResult::StillListening(speech), 0)
+    }
+
+    #[test]
+    fn silence_before_any_speech_times_out_instead_of_ending() {
+        let (ended, chunks) = listen(std::iter::repeat(false));
+        assert!(matches!(ended, EndOfSpeechResult::NoSpeech));
+        assert_eq!(chunks, 10);
+
+        // Speech starting just before the timeout keeps the utterance going
+        let verdicts = std::iter::repeat_n(false, 9).chain([true]);
+        assert!(matches!(
+            listen(verdicts).0,
+            EndOfSpeechResult::StillListening(_)
+        ));
+    }
+
+    #[test]
+    fn silence_after_speech_ends_the_utterance() {
+        let verdicts = [true, true].into_iter().chain(std::iter::repeat(false));
+        let (ended, chunks) = listen(verdicts);
+        let EndOfSpeechResult::SpeechEnded { audio_data, .. } = ended else {
+            panic!("expected the utterance to end on silence");
+        };
+        assert!(chunks < 10, "ended after {} chunks", chunks);
+        assert_eq!(audio_data.len(), (chunks - 1) * 4);
+    }
+
+    #[test]
+    fn endless_speech_is_cut_off_at_the_maximum_length() {
+        let (ended, chunks) = listen(std::iter::repeat(true));
+        let EndOfSpeechResult::MaxUtteranceReached {
+            audio_data,
+            duration,
+        } = ended
+        else {
+            panic!("expected the utterance to be cut off");
+        };
+        assert_eq!(chunks, 20);
+        assert_eq!(duration, Duration::from_secs(2));
+        assert_eq!(audio_data.len(), 20 * 4);
+    }
+
+    #[test]
+    fn playback_state_covers_hangover() {
+        let playback = PlaybackState::default();
+        assert!(!playback.playing_within(Duration::from_secs(1)));
+
+        playback.set_playing(true);
+        assert!(playback.playing_within(Duration::ZERO));
+
+        playback.set_playing(false);
+        assert!(playback.playing_within(Duration::from_secs(60)));
+        assert!(!playback.playing_within(Duration::ZERO));
+
+        // A sound of known length counts until it ends, and speech ending meanwhile does not
+        // cut it short
+        playback.play_for(Duration::from_secs(60));
+        playback.set_playing(false);
+        assert!(playback.playing_within(Duration::ZERO));
+    }
+
+    #[test]
+    fn wake_word_policy_only_applies_while_speaking() {
+        use WhileSpeakingPolicy::*;
+        for policy in [Normal, RaiseThreshold, Ignore] {
+            assert!(accept_wake_word(policy, false, 0.3, 0.6));
+        }
+        assert!(accept_wake_word(Normal, true, 0.3, 0.6));
+        assert!(!accept_wake_word(RaiseThreshold, true, 0.3, 0.6));
+        assert!(accept_wake_word(RaiseThreshold, true, 0.7, 0.6));
+        assert!(!accept_wake_word(Ignore, true, 0.99, 0.6));
+    }
 }
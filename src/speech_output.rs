@@ -0,0 +1,455 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, mpsc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::Result;
+use serde::Deserialize;
+use tracing::{Span, debug, info, info_span, warn};
+
+use crate::tts_client::{Playback, TtsClient, TtsInterrupter};
+
+/// How urgent a piece of speech is. A higher priority cuts off a lower one that is playing, which
+/// is then spoken again once the higher one is done; equal priorities queue up in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Status messages such as "Microphone reconnected."
+    Announcement,
+    /// The answer to a command
+    Response,
+    /// A timer going off
+    Alarm,
+}
+
+const PRIORITIES: usize = 3;
+
+/// Something to say, posted to [`SpeechOutput::say`].
+pub struct Utterance {
+    pub text: String,
+    pub priority: Priority,
+    /// Playback volume, 0.0 to 1.0
+    pub volume: f32,
+    /// Interaction this answers, reported back in [`OutputEvent`]
+    pub interaction: Option<u64>,
+    /// Parent of the `tts` span the speech is logged in
+    pub span: Span,
+}
+
+impl Utterance {
+    /// Speech at full volume, logged under the current span.
+    pub fn new(text: impl Into<String>, priority: Priority) -> Self {
+        Self {
+            text: text.into(),
+            priority,
+            volume: 1.0,
+            interaction: None,
+            span: Span::current(),
+        }
+    }
+}
+
+/// Reported once an utterance is done with, whether it played to the end or not.
+pub enum OutputEvent {
+    Spoken {
+        interaction: Option<u64>,
+        playback: Playback,
+        /// Time spent generating and playing it
+        duration: Duration,
+    },
+    /// The TTS processor could not speak the utterance
+    Failed {
+        interaction: Option<u64>,
+        error: String,
+        /// The processor is gone, so nothing more will be spoken and the output has stopped
+        processor_exited: bool,
+    },
+}
+
+enum Item {
+    Sound(PathBuf),
+    Speech(Utterance),
+}
+
+/// Pending output, shared between the poster and the scheduler thread.
+#[derive(Default)]
+struct Queue {
+    /// Earcons go ahead of all speech; they are short and only make sense right away
+    sounds: VecDeque<PathBuf>,
+    speech: [VecDeque<Utterance>; PRIORITIES],
+    /// Priority of the utterance being spoken, if any
+    playing: Option<Priority>,
+    /// The utterance being spoken was interrupted to make way for a higher priority one
+    preempting: bool,
+    /// The user is talking to the assistant, so speech waits until they are done
+    held: bool,
+    shutdown: bool,
+}
+
+impl Queue {
+    fn push(&mut self, utterance: Utterance) {
+        self.speech[utterance.priority as usize].push_back(utterance);
+    }
+
+    /// Put back an utterance that was cut off, ahead of others of the same priority.
+    fn requeue(&mut self, utterance: Utterance) {
+        self.speech[utterance.priority as usize].push_front(utterance);
+    }
+
+    fn pop(&mut self) -> Option<Item> {
+        if let Some(sound) = self.sounds.pop_front() {
+            return Some(Item::Sound(sound));
+        }
+        if self.held {
+            return None;
+        }
+        self.speech
+            .iter_mut()
+            .rev()
+            .find_map(|queue| queue.pop_front())
+            .map(Item::Speech)
+    }
+
+    /// Whether posting speech with `priority` should cut off what is playing.
+    fn should_preempt(&self, priority: Priority) -> bool {
+        !self.preempting && self.playing.is_some_and(|playing| priority > playing)
+    }
+
+    /// Mark speech with `priority` as playing once the TTS processor has started on it. Returns
+    /// whether it has to be cut off straight away, because something more urgent or a barge-in
+    /// arrived while it was being handed over and their interrupt went nowhere.
+    fn start(&mut self, priority: Priority) -> bool {
+        self.playing = Some(priority);
+        if self.held {
+            return true;
+        }
+        let more_urgent = self.speech[priority as usize + 1..]
+            .iter()
+            .any(|queue| !queue.is_empty());
+        if more_urgent && !self.preempting {
+            self.preempting = true;
+            return true;
+        }
+        false
+    }
+
+    /// Drop queued responses and announcements, which answer what was said before the user
+    /// spoke up, and hold the rest until [`Queue::resume`]. Alarms are kept: a timer still went
+    /// off. Returns how many utterances were dropped.
+    fn barge_in(&mut self) -> usize {
+        let mut dropped = 0;
+        for priority in [Priority::Announcement, Priority::Response] {
+            let queue = &mut self.speech[priority as usize];
+            dropped += queue.len();
+            queue.clear();
+        }
+        // Whatever is cut off now is not said again
+        self.preempting = false;
+        self.held = true;
+        dropped
+    }
+
+    fn resume(&mut self) {
+        self.held = false;
+    }
+
+    fn len(&self) -> usize {
+        self.sounds.len() + self.speech.iter().map(VecDeque::len).sum::<usize>()
+    }
+}
+
+/// Cuts off the assistant when the user starts talking, from any thread. See
+/// [`SpeechOutput::barge_in`].
+#[derive(Clone)]
+pub struct BargeIn {
+    queue: Arc<(Mutex<Queue>, Condvar)>,
+    interrupter: TtsInterrupter,
+}
+
+impl BargeIn {
+    /// Stop speaking, drop queued responses and announcements and hold queued alarms until
+    /// [`SpeechOutput::resume`].
+    pub fn barge_in(&self) {
+        let (queue, _) = &*self.queue;
+        let mut queue = queue.lock().unwrap();
+        let dropped = queue.barge_in();
+        if dropped > 0 {
+            debug!(dropped, "dropped queued speech for barge-in");
+        }
+        if let Err(e) = self.interrupter.interrupt() {
+            warn!(error = %e, "failed to interrupt speech");
+        }
+    }
+}
+
+/// Speaks on its own thread, so the event loop can keep handling wake words and timers while a
+/// long answer plays. Owns the [`TtsClient`].
+pub struct SpeechOutput {
+    queue: Arc<(Mutex<Queue>, Condvar)>,
+    interrupter: TtsInterrupter,
+    thread_handle: JoinHandle<()>,
+}
+
+impl SpeechOutput {
+    pub fn spawn(tts_client: TtsClient, events: mpsc::Sender<OutputEvent>) -> Self {
+        let queue = Arc::new((Mutex::new(Queue::default()), Condvar::new()));
+        let interrupter = tts_client.interrupter();
+        let thread_queue = queue.clone();
+        let thread_handle = thread::spawn(move || run_scheduler(tts_client, &thread_queue, events));
+        Self {
+            queue,
+            interrupter,
+            thread_handle,
+        }
+    }
+
+    /// Queue `utterance` without waiting for it to be spoken.
+    pub fn say(&self, utterance: Utterance) {
+        let (queue, ready) = &*self.queue;
+        let mut queue = queue.lock().unwrap();
+        if queue.should_preempt(utterance.priority) {
+            debug!(priority = ?utterance.priority, "cutting off lower priority speech");
+            queue.preempting = true;
+            if let Err(e) = self.interrupter.interrupt() {
+                warn!(error = %e, "failed to interrupt speech");
+            }
+        }
+        queue.push(utterance);
+        ready.notify_one();
+    }
+
+    /// A handle for cutting off speech from the thread that hears the wake word.
+    pub fn barge_in(&self) -> BargeIn {
+        BargeIn {
+            queue: self.queue.clone(),
+            interrupter: self.interrupter.clone(),
+        }
+    }
+
+    /// Let held speech play again once the user has finished talking.
+    pub fn resume(&self) {
+        let (queue, ready) = &*self.queue;
+        queue.lock().unwrap().resume();
+        ready.notify_one();
+    }
+
+    /// Play a sound file as soon as what is being spoken now is done.
+    pub fn play_sound(&self, path: &Path) {
+        let (queue, ready) = &*self.queue;
+        queue.lock().unwrap().sounds.push_back(path.to_path_buf());
+        ready.notify_one();
+    }
+
+    /// Let the current utterance finish, drop whatever is still queued and close the TTS
+    /// processor.
+    pub fn shutdown(self) {
+        let (queue, ready) = &*self.queue;
+        queue.lock().unwrap().shutdown = true;
+        ready.notify_one();
+        if self.thread_handle.join().is_err() {
+            warn!("speech output thread panicked");
+        }
+    }
+}
+
+fn run_scheduler(
+    mut tts_client: TtsClient,
+    queue: &(Mutex<Queue>, Condvar),
+    events: mpsc::Sender<OutputEvent>,
+) {
+    let (queue, ready) = queue;
+    let interrupter = tts_client.interrupter();
+    loop {
+        let item = {
+            let mut queue = queue.lock().unwrap();
+            loop {
+                if queue.shutdown {
+                    break None;
+                }
+                if let Some(item) = queue.pop() {
+                    break Some(item);
+                }
+                queue = ready.wait(queue).unwrap();
+            }
+        };
+
+        let utterance = match item {
+            None => break,
+            Some(Item::Sound(path)) => {
+                if let Err(e) = tts_client.play_sound(&path) {
+                    warn!(error = %e, sound = %path.display(), "failed to play sound");
+                }
+                continue;
+            }
+            Some(Item::Speech(utterance)) => utterance,
+        };
+
+        let started = Instant::now();
+        let result = info_span!(parent: &utterance.span, "tts", priority = ?utterance.priority)
+            .in_scope(|| {
+                // Nothing counts as playing until the processor has it, since it ignores
+                // interrupts before then
+                speak(&mut tts_client, &utterance, || {
+                    if queue.lock().unwrap().start(utterance.priority)
+                        && let Err(e) = interrupter.interrupt()
+                    {
+                        warn!(error = %e, "failed to interrupt speech");
+                    }
+                })
+            });
+        let duration = started.elapsed();
+
+        let preempted = {
+            let mut queue = queue.lock().unwrap();
+            queue.playing = None;
+            std::mem::take(&mut queue.preempting)
+        };
+        let event = match result {
+            Ok(Playback::Interrupted) if preempted => {
+                // Said again in full once the more urgent speech is done
+                queue.lock().unwrap().requeue(utterance);
+                continue;
+            }
+            Ok(playback) => OutputEvent::Spoken {
+                interaction: utterance.interaction,
+                playback,
+                duration,
+            },
+            Err(e) => OutputEvent::Failed {
+                interaction: utterance.interaction,
+                error: e.to_string(),
+                processor_exited: tts_client.has_exited(),
+            },
+        };
+        let stopped = matches!(
+            event,
+            OutputEvent::Failed {
+                processor_exited: true,
+                ..
+            }
+        );
+        if events.send(event).is_err() || stopped {
+            break;
+        }
+    }
+
+    let dropped = queue.lock().unwrap().len();
+    if dropped > 0 {
+        info!(dropped, "dropped queued speech on shutdown");
+    }
+    if let Err(e) = tts_client.wait_until_finished() {
+        warn!(error = %e, "failed to wait for playback to finish");
+    }
+}
+
+fn speak(
+    tts_client: &mut TtsClient,
+    utterance: &Utterance,
+    on_started: impl FnOnce(),
+) -> Result<Playback> {
+    if utterance.volume == 1.0 {
+        return tts_client.generate_audio_notifying(utterance.text.clone(), on_started);
+    }
+    tts_client.set_volume(utterance.volume)?;
+    let playback = tts_client.generate_audio_notifying(utterance.text.clone(), on_started);
+    tts_client.set_volume(1.0)?;
+    playback
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(queue: &mut Queue) -> Vec<String> {
+        std::iter::from_fn(|| queue.pop())
+            .map(|item| match item {
+                Item::Sound(path) => path.display().to_string(),
+                Item::Speech(utterance) => utterance.text,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn queue_orders_sounds_then_priority_then_arrival() {
+        let mut queue = Queue::default();
+        queue.push(Utterance::new("reconnected", Priority::Announcement));
+        queue.push(Utterance::new("first answer", Priority::Response));
+        queue.push(Utterance::new("tea is ready", Priority::Alarm));
+        queue.push(Utterance::new("second answer", Priority::Response));
+        queue.sounds.push_back(PathBuf::from("wake.wav"));
+        queue.requeue(Utterance::new("cut off answer", Priority::Response));
+
+        assert_eq!(queue.len(), 6);
+        assert_eq!(
+            texts(&mut queue),
+            [
+                "wake.wav",
+                "tea is ready",
+                "cut off answer",
+                "first answer",
+                "second answer",
+                "reconnected"
+            ]
+        );
+    }
+
+    #[test]
+    fn barge_in_drops_answers_and_holds_alarms() {
+        let mut queue = Queue::default();
+        queue.push(Utterance::new("reconnected", Priority::Announcement));
+        queue.push(Utterance::new("stale answer", Priority::Response));
+        queue.push(Utterance::new("tea is ready", Priority::Alarm));
+        queue.playing = Some(Priority::Response);
+        queue.preempting = true;
+
+        assert_eq!(queue.barge_in(), 2);
+        assert!(!queue.preempting);
+        queue.sounds.push_back(PathBuf::from("wake.wav"));
+        queue.push(Utterance::new("new answer", Priority::Response));
+        // Earcons still play while the user talks, speech waits
+        assert_eq!(texts(&mut queue), ["wake.wav"]);
+
+        queue.resume();
+        assert_eq!(texts(&mut queue), ["tea is ready", "new answer"]);
+    }
+
+    #[test]
+    fn speech_posted_while_handing_over_preempts_once_started() {
+        let mut queue = Queue::default();
+        queue.push(Utterance::new("long answer", Priority::Response));
+        assert!(queue.pop().is_some());
+
+        // The alarm arrives before the processor has started on the answer, so an interrupt
+        // would be ignored; it has to happen once it has
+        queue.push(Utterance::new("tea is ready", Priority::Alarm));
+        assert!(!queue.should_preempt(Priority::Alarm));
+        assert!(queue.start(Priority::Response));
+        assert!(queue.preempting);
+
+        // Nothing more urgent waiting, nothing to cut off
+        let mut queue = Queue::default();
+        queue.push(Utterance::new("later", Priority::Announcement));
+        assert!(!queue.start(Priority::Response));
+
+        // A barge-in during the hand-over cuts it off without saying it again later
+        let mut queue = Queue::default();
+        queue.barge_in();
+        assert!(queue.start(Priority::Response));
+        assert!(!queue.preempting);
+    }
+
+    #[test]
+    fn only_higher_priority_preempts_once() {
+        let mut queue = Queue::default();
+        assert!(!queue.should_preempt(Priority::Alarm));
+
+        queue.playing = Some(Priority::Response);
+        assert!(!queue.should_preempt(Priority::Announcement));
+        assert!(!queue.should_preempt(Priority::Response));
+        assert!(queue.should_preempt(Priority::Alarm));
+
+        queue.preempting = true;
+        assert!(!queue.should_preempt(Priority::Alarm));
+    }
+}
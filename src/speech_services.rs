@@ -0,0 +1,113 @@
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use color_eyre::eyre::Result;
+use oww_rs::oww::OWW_MODEL_CHUNK_SIZE;
+use tracing::info;
+
+use crate::audio_frontend::{AudioFrontEnd, Tap};
+use crate::audio_resampler::Downmix;
+use crate::config::{AssistantConfig, ResamplerQuality};
+use crate::create_speech_to_text_client;
+use crate::metrics::METRICS;
+use crate::speech::SpeechToTextClient;
+use crate::speech_listener::WakeWordDetector;
+use crate::tts_client::TtsClient;
+
+/// Streaming wake word detection for one audio stream.
+pub trait WakeWordSession {
+    /// Feed interleaved samples; returns the model's probability when the wake word is heard.
+    fn process(&mut self, samples: &[f32]) -> Option<f32>;
+}
+
+/// The wake word detector behind a front end converting the stream to what it expects.
+struct DetectorSession {
+    front_end: AudioFrontEnd,
+    tap: Tap,
+    detector: WakeWordDetector,
+}
+
+impl WakeWordSession for DetectorSession {
+    fn process(&mut self, samples: &[f32]) -> Option<f32> {
+        self.front_end.process(samples);
+        self.detector.detect(self.front_end.chunks(self.tap))
+    }
+}
+
+/// The models behind the network services, shared by all connections.
+pub trait Backend: Send + Sync {
+    /// Transcribe 16 kHz mono audio.
+    fn transcribe(&self, audio: Vec<f32>) -> Result<String>;
+    /// Generate speech for `text`, handing each chunk of mono samples and its sample rate to
+    /// `on_audio`.
+    fn synthesize(
+        &self,
+        text: &str,
+        on_audio: &mut dyn FnMut(u32, &[f32]) -> Result<()>,
+    ) -> Result<()>;
+    /// Start detecting the wake word in a stream with the given format.
+    fn wake_word_session(&self, rate: u32, channels: u16) -> Result<Box<dyn WakeWordSession>>;
+}
+
+/// whisper, the TTS processor and openWakeWord, loaded from the assistant's config.
+pub struct LocalBackend {
+    stt: SpeechToTextClient,
+    /// The processor handles one request at a time
+    tts: Mutex<TtsClient>,
+    wake_word_model_path: Option<PathBuf>,
+    wake_word_threshold: f32,
+}
+
+impl LocalBackend {
+    /// Load the whisper model and start the TTS processor.
+    pub fn load(config: &AssistantConfig) -> Result<Self> {
+        info!("loading whisper model");
+        let stt = create_speech_to_text_client(&config.stt)?;
+        info!("starting TTS processor");
+        let tts = TtsClient::new(&config.tts.model_path, &config.tts.voice_path)?;
+        Ok(Self {
+            stt,
+            tts: Mutex::new(tts),
+            wake_word_model_path: config.wake_word.model_path.clone(),
+            wake_word_threshold: config.wake_word.threshold,
+        })
+    }
+}
+
+impl Backend for LocalBackend {
+    fn transcribe(&self, audio: Vec<f32>) -> Result<String> {
+        let _timer = METRICS.whisper_transcription_seconds.start_timer();
+        let segments = self.stt.process(audio)?;
+        Ok(segments
+            .iter()
+            .map(|segment| segment.text.as_str())
+            .collect::<String>()
+            .trim()
+            .to_string())
+    }
+
+    fn synthesize(
+        &self,
+        text: &str,
+        on_audio: &mut dyn FnMut(u32, &[f32]) -> Result<()>,
+    ) -> Result<()> {
+        self.tts
+            .lock()
+            .unwrap()
+            .synthesize(text.to_string(), on_audio)
+    }
+
+    fn wake_word_session(&self, rate: u32, channels: u16) -> Result<Box<dyn WakeWordSession>> {
+        let mut front_end =
+            AudioFrontEnd::new(rate, channels, Downmix::Average, ResamplerQuality::Balanced)?;
+        let tap = front_end.tap(OWW_MODEL_CHUNK_SIZE);
+        Ok(Box::new(DetectorSession {
+            front_end,
+            tap,
+            detector: WakeWordDetector::new(
+                self.wake_word_model_path.as_deref(),
+                self.wake_word_threshold,
+            )?,
+        }))
+    }
+}
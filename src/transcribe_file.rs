@@ -0,0 +1,164 @@
+use std::path::Path;
+
+use color_eyre::eyre::{Context, Result};
+use cpal::{BufferSize, StreamConfig};
+
+use crate::config::AssistantConfig;
+use crate::speech_listener::{SpeechEvent, SpeechPipeline};
+use crate::{clean_text_segments, create_speech_to_text_client, speech_pipeline_config};
+
+/// Decode a WAV file into interleaved f32 samples in the -1.0 to 1.0 range.
+fn read_wav(path: &Path) -> Result<(Vec<f32>, hound::WavSpec)> {
+    let mut reader = hound::WavReader::open(path)
+        .wrap_err_with(|| format!("failed to open WAV file {}", path.display()))?;
+    let spec = reader.spec();
+
+    let samples = match spec.sample_format {
+        hound::SampleFormat::Float => reader
+            .samples::<f32>()
+            .collect::<Result<Vec<_>, _>>()
+            .wrap_err("failed to read WAV samples")?,
+        hound::SampleFormat::Int => {
+            // Integer samples of any bit depth are scaled so full scale maps to -1.0 to 1.0
+            let scale = (1_i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|sample| sample.map(|s| s as f32 / scale))
+                .collect::<Result<Vec<_>, _>>()
+                .wrap_err("failed to read WAV samples")?
+        }
+    };
+
+    Ok((samples, spec))
+}
+
+/// Feed a WAV file through the same `SpeechPipeline` the microphone stream uses, in blocks of
+/// `block_frames` frames, and print every utterance with its timing and transcript.
+pub fn transcribe_file(path: &Path, block_frames: usize, config: &AssistantConfig) -> Result<()> {
+    if block_frames == 0 {
+        return Err(color_eyre::eyre::eyre!(
+            "--block-frames must be greater than 0"
+        ));
+    }
+
+    let (mut samples, spec) = read_wav(path)?;
+    let channels = spec.channels as usize;
+    let sample_rate = spec.sample_rate;
+    println!(
+        "Loaded {}: {} Hz, {} channel{}, {:.2} seconds",
+        path.display(),
+        sample_rate,
+        channels,
+        if channels == 1 { "" } else { "s" },
+        samples.len() as f64 / channels as f64 / sample_rate as f64
+    );
+
+    let stream_config = StreamConfig {
+        channels: spec.channels,
+        sample_rate,
+        buffer_size: BufferSize::Default,
+    };
+    let mut pipeline = SpeechPipeline::new(
+        &stream_config,
+        &speech_pipeline_config(config, Default::default(), Default::default()),
+    )?;
+    let speech_to_text_client = create_speech_to_text_client(&config.stt)?;
+    let voice_activation_text = config.wake_word.activation_text.to_lowercase();
+
+    // Pad with silence so speech running up to the end of the file still gets an end-of-speech
+    let trailing_frames = ((config.audio.silence_seconds + 0.5) * sample_rate as f64) as usize;
+    samples.extend(std::iter::repeat_n(0.0, trailing_frames * channels));
+
+    let mut frames_processed = 0;
+    let mut wake_word_at = None;
+    let mut utterances = 0;
+    for block in samples.chunks(block_frames * channels) {
+        frames_processed += block.len() / channels;
+        let position = frames_processed as f64 / sample_rate as f64;
+
+        match pipeline.process(block) {
+            Some(SpeechEvent::WakeWordDetected { probability }) => {
+                wake_word_at = Some((position, probability));
+            }
+            Some(SpeechEvent::SpeechDetected(audio) | SpeechEvent::MaxUtteranceReached(audio)) => {
+                utterances += 1;
+                let segments = speech_to_text_client.process(audio)?;
+                let transcript = segments
+                    .iter()
+                    .map(|segment| segment.text.as_str())
+                    .collect::<String>();
+                let cleaned_text = clean_text_segments(segments, &voice_activation_text);
+
+                println!("Utterance {}", utterances);
+                match wake_word_at.take() {
+                    Some((wake_word_at, probability)) => println!(
+                        "  wake word:     {:.2}s (probability {:.2})",
+                        wake_word_at, probability
+                    ),
+                    None => println!("  wake word:     -"),
+                }
+                println!("  end of speech: {:.2}s", position);
+                println!("  transcript:    {:?}", transcript.trim());
+                println!("  cleaned:       {:?}", cleaned_text);
+            }
+            Some(SpeechEvent::NoSpeech) => {
+                if let Some((wake_word_at, _)) = wake_word_at.take() {
+                    println!(
+                        "Wake word detected at {:.2}s but nobody spoke, cancelled at {:.2}s",
+                        wake_word_at, position
+                    );
+                }
+            }
+            Some(SpeechEvent::FollowUpExpired) | None => {}
+        }
+    }
+
+    if let Some((wake_word_at, _)) = wake_word_at {
+        println!(
+            "Wake word detected at {:.2}s but speech never ended before the end of the file",
+            wake_word_at
+        );
+    }
+    println!(
+        "{} utterance{} detected",
+        utterances,
+        if utterances == 1 { "" } else { "s" }
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_wav_scales_int_samples() {
+        let path = std::env::temp_dir().join(format!(
+            "voice-assistant-read-wav-{}.wav",
+            std::process::id()
+        ));
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 48000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for sample in [0_i16, 16384, -32768, 32767] {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let (samples, read_spec) = read_wav(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(read_spec.channels, 2);
+        assert_eq!(read_spec.sample_rate, 48000);
+        assert_eq!(samples.len(), 4);
+        assert_eq!(samples[0], 0.0);
+        assert_eq!(samples[1], 0.5);
+        assert_eq!(samples[2], -1.0);
+        assert!((samples[3] - 1.0).abs() < 1e-4);
+    }
+}
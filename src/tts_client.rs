@@ -1,20 +1,80 @@
 use color_eyre::eyre::Result;
 use std::io::{Read, Write};
+use std::net::Shutdown;
 use std::os::unix::net::UnixStream;
-use std::path::PathBuf;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
 use std::process::{Child, Command, Stdio};
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
 use tts_processor::{TtsCommand, TtsResponse, deserialize_response, serialize_command};
 
+use crate::metrics::METRICS;
+use crate::speech_listener::PlaybackState;
+
+/// How long the TTS processor gets to exit after the connection is closed before it is killed.
+const PROCESS_EXIT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long [`TtsClient::has_exited`] waits for a failing processor to be seen as exited.
+const PROCESS_EXIT_GRACE: Duration = Duration::from_millis(100);
+
 pub struct TtsClient {
     stream: UnixStream,
-    _process: Child,
+    /// Write half of `stream`, shared with [`TtsInterrupter`]s so their commands are never
+    /// interleaved with ours
+    writer: Arc<Mutex<UnixStream>>,
+    process: Child,
     socket_path: PathBuf,
+    playback: Option<Arc<PlaybackState>>,
+}
+
+/// How a `generate_audio` call ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Playback {
+    Finished,
+    /// Cut short by [`TtsInterrupter::interrupt`]
+    Interrupted,
+}
+
+/// Cuts off whatever the TTS processor is saying, from any thread, while the owning
+/// [`TtsClient`] is blocked in `generate_audio`.
+#[derive(Clone)]
+pub struct TtsInterrupter {
+    writer: Arc<Mutex<UnixStream>>,
+}
+
+impl TtsInterrupter {
+    /// Stop the current speech, if any. The interrupted `generate_audio` returns
+    /// [`Playback::Interrupted`]; when nothing is playing this does nothing.
+    pub fn interrupt(&self) -> Result<()> {
+        let cmd_bytes = serialize_command(&TtsCommand::Interrupt)?;
+        write_length_prefixed_message(&self.writer, &cmd_bytes)
+    }
+}
+
+/// How long a WAV file plays for.
+fn sound_duration(path: &Path) -> Result<Duration> {
+    let reader = hound::WavReader::open(path)?;
+    Ok(Duration::from_secs_f64(
+        reader.duration() as f64 / reader.spec().sample_rate as f64,
+    ))
+}
+
+fn write_length_prefixed_message(writer: &Mutex<UnixStream>, data: &[u8]) -> Result<()> {
+    let mut message = Vec::with_capacity(4 + data.len());
+    message.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    message.extend_from_slice(data);
+    let mut writer = writer.lock().unwrap();
+    writer.write_all(&message)?;
+    writer.flush()?;
+    Ok(())
 }
 
 impl TtsClient {
-    /// Create a new TTS client, spawning the TTS processor process
-    pub fn new() -> Result<Self> {
+    /// Create a new TTS client, spawning the TTS processor process.
+    /// `model_path` and `voice_path` are handed to the processor and resolved relative to the cwd.
+    pub fn new(model_path: &Path, voice_path: &Path) -> Result<Self> {
         // Generate unique socket path
         let socket_path =
             std::env::temp_dir().join(format!("voice-assistant-tts-{}.sock", std::process::id()));
@@ -31,28 +91,31 @@ impl TtsClient {
             None
         };
 
-        let mut process = if let Some(bin_path) = binary_path {
+        let mut command = if let Some(bin_path) = binary_path {
             // Use built binary if available
             Command::new(bin_path)
-                .env("TTS_SOCKET_PATH", &socket_path)
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped())
-                .spawn()
         } else {
             // Fall back to cargo run
-            Command::new("cargo")
-                .args([
-                    "run",
-                    "--bin",
-                    "tts-processor",
-                    "--manifest-path",
-                    "tts-processor/Cargo.toml",
-                ])
-                .env("TTS_SOCKET_PATH", &socket_path)
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped())
-                .spawn()
-        }
+            let mut command = Command::new("cargo");
+            command.args([
+                "run",
+                "--bin",
+                "tts-processor",
+                "--manifest-path",
+                "tts-processor/Cargo.toml",
+            ]);
+            command
+        };
+        let mut process = command
+            .env("TTS_SOCKET_PATH", &socket_path)
+            .env("TTS_MODEL_PATH", model_path)
+            .env("TTS_VOICE_PATH", voice_path)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // Own process group, so Ctrl-C in the terminal reaches only the assistant, which then
+            // shuts the processor down after the current sentence
+            .process_group(0)
+            .spawn()
         .map_err(|e| {
             color_eyre::eyre::eyre!(
                 "Failed to spawn TTS processor: {}. Make sure you're running from the workspace root and the TTS processor is built.",
@@ -92,10 +155,14 @@ impl TtsClient {
         let stream = UnixStream::connect(&socket_path)
             .map_err(|e| color_eyre::eyre::eyre!("Failed to connect to TTS socket: {}", e))?;
 
+        let writer = Arc::new(Mutex::new(stream.try_clone()?));
+
         Ok(Self {
             stream,
-            _process: process,
+            writer,
+            process,
             socket_path,
+            playback: None,
         })
     }
 
@@ -110,15 +177,60 @@ impl TtsClient {
     }
 
     fn write_length_prefixed_message(&mut self, data: &[u8]) -> Result<()> {
-        let len = data.len() as u32;
-        self.stream.write_all(&len.to_le_bytes())?;
-        self.stream.write_all(data)?;
-        self.stream.flush()?;
-        Ok(())
+        write_length_prefixed_message(&self.writer, data)
+    }
+
+    /// Whether the TTS processor has gone away. A connection error shows up a moment before the
+    /// process is reaped, so this gives it [`PROCESS_EXIT_GRACE`] to finish exiting.
+    pub fn has_exited(&mut self) -> bool {
+        let deadline = Instant::now() + PROCESS_EXIT_GRACE;
+        loop {
+            match self.process.try_wait() {
+                Ok(None) if Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                Ok(None) => return false,
+                _ => return true,
+            }
+        }
+    }
+
+    /// A handle that can cut off playback from another thread.
+    pub fn interrupter(&self) -> TtsInterrupter {
+        TtsInterrupter {
+            writer: self.writer.clone(),
+        }
+    }
+
+    /// Mark `playback` as playing for as long as each `generate_audio` call lasts.
+    pub fn report_playback(&mut self, playback: Arc<PlaybackState>) {
+        self.playback = Some(playback);
     }
 
     /// Generate audio from text and play it
-    pub fn generate_audio(&mut self, text: String) -> Result<()> {
+    pub fn generate_audio(&mut self, text: String) -> Result<Playback> {
+        self.generate_audio_notifying(text, || {})
+    }
+
+    /// Like [`TtsClient::generate_audio`], calling `on_started` once the processor has taken the
+    /// text on. An interrupt only cuts off speech from then on; sent earlier it is ignored.
+    pub fn generate_audio_notifying(
+        &mut self,
+        text: String,
+        on_started: impl FnOnce(),
+    ) -> Result<Playback> {
+        if let Some(playback) = &self.playback {
+            playback.set_playing(true);
+        }
+        let result = self.speak(text, on_started);
+        if let Some(playback) = &self.playback {
+            playback.set_playing(false);
+        }
+        result
+    }
+
+    fn speak(&mut self, text: String, on_started: impl FnOnce()) -> Result<Playback> {
+        let requested_at = Instant::now();
         let cmd = TtsCommand::GenerateAudio(text);
         let cmd_bytes = serialize_command(&cmd)?;
         self.write_length_prefixed_message(&cmd_bytes)?;
@@ -129,15 +241,69 @@ impl TtsClient {
 
         match resp {
             TtsResponse::Started => {
-                // Wait for finished response
+                on_started();
+                // Chunks are reported as they are generated, then finished once playback ends
+                let mut first_chunk = true;
+                loop {
+                    let resp_bytes = self.read_length_prefixed_message()?;
+                    let resp = deserialize_response(&resp_bytes)?;
+                    match resp {
+                        TtsResponse::ChunkGenerated(_) => {
+                            if first_chunk {
+                                first_chunk = false;
+                                METRICS
+                                    .tts_time_to_first_audio_seconds
+                                    .observe(requested_at.elapsed().as_secs_f64());
+                            }
+                        }
+                        TtsResponse::Finished => return Ok(Playback::Finished),
+                        TtsResponse::Interrupted => return Ok(Playback::Interrupted),
+                        TtsResponse::Error(e) => {
+                            return Err(color_eyre::eyre::eyre!("TTS error: {}", e));
+                        }
+                        _ => {
+                            return Err(color_eyre::eyre::eyre!("Unexpected response: {:?}", resp));
+                        }
+                    }
+                }
+            }
+            TtsResponse::Error(e) => Err(color_eyre::eyre::eyre!("TTS error: {}", e)),
+            _ => Err(color_eyre::eyre::eyre!("Unexpected response: {:?}", resp)),
+        }
+    }
+
+    /// Generate audio from text without playing it, handing each chunk of mono samples and its
+    /// sample rate to `on_audio` as it is generated.
+    pub fn synthesize(
+        &mut self,
+        text: String,
+        mut on_audio: impl FnMut(u32, &[f32]) -> Result<()>,
+    ) -> Result<()> {
+        let cmd = TtsCommand::Synthesize(text);
+        let cmd_bytes = serialize_command(&cmd)?;
+        self.write_length_prefixed_message(&cmd_bytes)?;
+
+        let resp_bytes = self.read_length_prefixed_message()?;
+        let resp = deserialize_response(&resp_bytes)?;
+
+        match resp {
+            TtsResponse::Started => loop {
                 let resp_bytes = self.read_length_prefixed_message()?;
                 let resp = deserialize_response(&resp_bytes)?;
                 match resp {
-                    TtsResponse::Finished => Ok(()),
-                    TtsResponse::Error(e) => Err(color_eyre::eyre::eyre!("TTS error: {}", e)),
-                    _ => Err(color_eyre::eyre::eyre!("Unexpected response: {:?}", resp)),
+                    TtsResponse::Audio {
+                        sample_rate,
+                        samples,
+                    } => on_audio(sample_rate, &samples)?,
+                    TtsResponse::Finished => return Ok(()),
+                    TtsResponse::Error(e) => {
+                        return Err(color_eyre::eyre::eyre!("TTS error: {}", e));
+                    }
+                    _ => {
+                        return Err(color_eyre::eyre::eyre!("Unexpected response: {:?}", resp));
+                    }
                 }
-            }
+            },
             TtsResponse::Error(e) => Err(color_eyre::eyre::eyre!("TTS error: {}", e)),
             _ => Err(color_eyre::eyre::eyre!("Unexpected response: {:?}", resp)),
         }
@@ -159,6 +325,34 @@ impl TtsClient {
         }
     }
 
+    /// Play a short WAV file, such as an earcon, without waiting for it to finish. Relative paths
+    /// are resolved against the cwd.
+    pub fn play_sound(&mut self, path: &Path) -> Result<()> {
+        let cmd = TtsCommand::PlaySound(path.to_string_lossy().into_owned());
+        let cmd_bytes = serialize_command(&cmd)?;
+        self.write_length_prefixed_message(&cmd_bytes)?;
+
+        let resp_bytes = self.read_length_prefixed_message()?;
+        let resp = deserialize_response(&resp_bytes)?;
+
+        match resp {
+            TtsResponse::SoundPlaying => {
+                // The processor does not wait for sounds, so their length comes from the file
+                if let Some(playback) = &self.playback {
+                    match sound_duration(path) {
+                        Ok(duration) => playback.play_for(duration),
+                        Err(e) => {
+                            warn!(error = %e, sound = %path.display(), "unknown sound length")
+                        }
+                    }
+                }
+                Ok(())
+            }
+            TtsResponse::Error(e) => Err(color_eyre::eyre::eyre!("TTS error: {}", e)),
+            _ => Err(color_eyre::eyre::eyre!("Unexpected response: {:?}", resp)),
+        }
+    }
+
     /// Stop current playback
     #[allow(dead_code)]
     pub fn stop(&mut self) -> Result<()> {
@@ -195,9 +389,20 @@ impl TtsClient {
 
 impl Drop for TtsClient {
     fn drop(&mut self) {
-        // Try to stop the process gracefully
-        let _ = self._process.kill();
-        let _ = self._process.wait();
+        // Closing the connection makes the processor stop playback and exit on its own; only kill
+        // it if it does not go away in time
+        let _ = self.stream.shutdown(Shutdown::Both);
+        let deadline = Instant::now() + PROCESS_EXIT_TIMEOUT;
+        while Instant::now() < deadline {
+            match self.process.try_wait() {
+                Ok(None) => std::thread::sleep(Duration::from_millis(50)),
+                _ => break,
+            }
+        }
+        if let Ok(None) = self.process.try_wait() {
+            let _ = self.process.kill();
+        }
+        let _ = self.process.wait();
 
         // Clean up socket file
         if self.socket_path.exists() {
@@ -205,3 +410,15 @@ impl Drop for TtsClient {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundled_earcons_have_a_length() {
+        let duration = sound_duration(Path::new("sounds/wake.wav")).unwrap();
+        assert!(duration > Duration::ZERO && duration < Duration::from_secs(2));
+        assert!(sound_duration(Path::new("sounds/missing.wav")).is_err());
+    }
+}
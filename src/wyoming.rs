@@ -0,0 +1,694 @@
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use color_eyre::eyre::{Context, Result};
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use tracing::{debug, info, info_span, warn};
+
+use crate::config::AssistantConfig;
+use crate::metrics::METRICS;
+use crate::pcm::{f32_to_pcm, pcm_to_f32, to_whisper_input};
+use crate::speech_listener::SAMPLE_RATE;
+use crate::speech_services::{Backend, LocalBackend, WakeWordSession};
+
+/// Protocol version sent in every event header.
+const WYOMING_VERSION: &str = "1.5.4";
+
+/// Largest header line, data or payload accepted, far above any real event, so a corrupt or
+/// hostile length cannot make the server allocate gigabytes.
+const MAX_EVENT_PART_LENGTH: usize = 16 * 1024 * 1024;
+
+/// Seconds of audio after a detection during which the wake word is not reported again, since
+/// the model keeps firing for as long as the word is still in its window.
+const WAKE_WORD_REFRACTORY_SECONDS: f64 = 2.0;
+
+/// One Wyoming event: a JSON header line, then `data_length` bytes of JSON data and
+/// `payload_length` bytes of binary payload.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub kind: String,
+    pub data: Map<String, Value>,
+    pub payload: Vec<u8>,
+}
+
+impl Event {
+    pub fn new(kind: &str, data: Value) -> Self {
+        let data = match data {
+            Value::Object(data) => data,
+            _ => Map::new(),
+        };
+        Self {
+            kind: kind.to_string(),
+            data,
+            payload: Vec::new(),
+        }
+    }
+
+    fn with_payload(mut self, payload: Vec<u8>) -> Self {
+        self.payload = payload;
+        self
+    }
+}
+
+#[derive(Deserialize)]
+struct Header {
+    #[serde(rename = "type")]
+    kind: String,
+    /// Older peers put the data in the header itself
+    #[serde(default)]
+    data: Option<Map<String, Value>>,
+    #[serde(default)]
+    data_length: Option<usize>,
+    #[serde(default)]
+    payload_length: Option<usize>,
+}
+
+/// Read the next event, or `None` once the peer has closed the connection.
+pub fn read_event(reader: &mut impl BufRead) -> Result<Option<Event>> {
+    let mut line = String::new();
+    let read = reader
+        .take(MAX_EVENT_PART_LENGTH as u64)
+        .read_line(&mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') && read == MAX_EVENT_PART_LENGTH {
+        return Err(color_eyre::eyre::eyre!(
+            "Wyoming event header is longer than {} bytes",
+            MAX_EVENT_PART_LENGTH
+        ));
+    }
+    let header: Header = serde_json::from_str(&line)
+        .wrap_err_with(|| format!("invalid Wyoming event header: {}", line.trim()))?;
+
+    for (name, length) in [
+        ("data", header.data_length),
+        ("payload", header.payload_length),
+    ] {
+        let length = length.unwrap_or(0);
+        if length > MAX_EVENT_PART_LENGTH {
+            return Err(color_eyre::eyre::eyre!(
+                "Wyoming event {} of {} bytes is too long",
+                name,
+                length
+            ));
+        }
+    }
+
+    let mut data = header.data.unwrap_or_default();
+    if let Some(length) = header.data_length.filter(|&length| length > 0) {
+        let mut bytes = vec![0; length];
+        reader.read_exact(&mut bytes)?;
+        let extra: Map<String, Value> =
+            serde_json::from_slice(&bytes).wrap_err("invalid Wyoming event data")?;
+        data.extend(extra);
+    }
+
+    let mut payload = vec![0; header.payload_length.unwrap_or(0)];
+    reader.read_exact(&mut payload)?;
+
+    Ok(Some(Event {
+        kind: header.kind,
+        data,
+        payload,
+    }))
+}
+
+pub fn write_event(writer: &mut impl Write, event: &Event) -> Result<()> {
+    let data = if event.data.is_empty() {
+        Vec::new()
+    } else {
+        serde_json::to_vec(&event.data)?
+    };
+    let mut header = json!({ "type": event.kind, "version": WYOMING_VERSION });
+    if !data.is_empty() {
+        header["data_length"] = data.len().into();
+    }
+    if !event.payload.is_empty() {
+        header["payload_length"] = event.payload.len().into();
+    }
+
+    let mut message = serde_json::to_vec(&header)?;
+    message.push(b'\n');
+    message.extend_from_slice(&data);
+    message.extend_from_slice(&event.payload);
+    writer.write_all(&message)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Sample format from `audio-start` and `audio-chunk` data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct AudioFormat {
+    rate: u32,
+    channels: u16,
+}
+
+impl AudioFormat {
+    fn from_data(data: &Map<String, Value>) -> Result<Self> {
+        let field = |name: &str| {
+            data.get(name)
+                .and_then(Value::as_u64)
+                .ok_or_else(|| color_eyre::eyre::eyre!("audio event without {}", name))
+        };
+        let width = field("width")?;
+        if width != 2 {
+            return Err(color_eyre::eyre::eyre!(
+                "only 16-bit audio is supported (got width {})",
+                width
+            ));
+        }
+        Ok(Self {
+            rate: field("rate")? as u32,
+            channels: field("channels")? as u16,
+        })
+    }
+}
+
+/// What the server offers, answered to `describe`.
+pub struct Services {
+    pub backend: Box<dyn Backend>,
+    /// Name reported in `detection` events
+    pub wake_word_name: String,
+    /// Data of the `info` event
+    pub info: Value,
+}
+
+/// The name of a model as shown in Home Assistant: its file name without the extension.
+fn model_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.display().to_string())
+}
+
+/// Describe the configured whisper model, TTS voice and wake word for the `info` event.
+fn describe(config: &AssistantConfig, wake_word_name: &str) -> Value {
+    let whisper =
+        json!({ "name": "whisper.cpp", "url": "https://github.com/ggerganov/whisper.cpp" });
+    let pocket_tts =
+        json!({ "name": "Kyutai", "url": "https://github.com/kyutai-labs/pocket-tts" });
+    let openwakeword = json!({
+        "name": "openWakeWord",
+        "url": "https://github.com/dscripka/openWakeWord",
+    });
+    let languages = match config.stt.language.as_str() {
+        "auto" => Vec::new(),
+        language => vec![language.to_string()],
+    };
+    let version = env!("CARGO_PKG_VERSION");
+    json!({
+        "asr": [{
+            "name": "whisper",
+            "description": "whisper.cpp speech to text",
+            "attribution": whisper,
+            "installed": true,
+            "version": version,
+            "models": [{
+                "name": model_name(&config.stt.model_path),
+                "description": "whisper model",
+                "attribution": whisper,
+                "installed": true,
+                "version": version,
+                "languages": languages,
+            }],
+        }],
+        "tts": [{
+            "name": "pocket-tts",
+            "description": "pocket-tts text to speech",
+            "attribution": pocket_tts,
+            "installed": true,
+            "version": version,
+            "voices": [{
+                "name": model_name(&config.tts.voice_path),
+                "description": "cloned voice",
+                "attribution": pocket_tts,
+                "installed": true,
+                "version": version,
+                "languages": ["en"],
+            }],
+        }],
+        "wake": [{
+            "name": "openwakeword",
+            "description": "openWakeWord wake word detection",
+            "attribution": openwakeword,
+            "installed": true,
+            "version": version,
+            "models": [{
+                "name": wake_word_name,
+                "description": "wake word model",
+                "phrase": config.wake_word.activation_text,
+                "attribution": openwakeword,
+                "installed": true,
+                "version": version,
+                "languages": ["en"],
+            }],
+        }],
+    })
+}
+
+/// What the audio of the current stream is for.
+enum Stream {
+    /// Collected until `audio-stop`, then transcribed
+    Transcribe {
+        format: AudioFormat,
+        samples: Vec<f32>,
+    },
+    /// Checked for the wake word chunk by chunk
+    Detect {
+        format: AudioFormat,
+        session: Box<dyn WakeWordSession>,
+        /// Frames received so far, for detection timestamps
+        frames: usize,
+        last_detection: Option<usize>,
+        detected: bool,
+    },
+}
+
+/// Per-connection protocol state.
+struct Session<'a, W: Write> {
+    services: &'a Services,
+    writer: W,
+    /// A `detect` was received, so the next stream is for the wake word
+    detect_next: bool,
+    stream: Option<Stream>,
+}
+
+impl<W: Write> Session<'_, W> {
+    fn send(&mut self, event: Event) -> Result<()> {
+        write_event(&mut self.writer, &event)
+    }
+
+    fn handle(&mut self, event: Event) -> Result<()> {
+        match event.kind.as_str() {
+            "describe" => self.send(Event::new("info", self.services.info.clone())),
+            "ping" => self.send(Event::new("pong", Value::Object(event.data))),
+            "transcribe" => {
+                self.detect_next = false;
+                Ok(())
+            }
+            "detect" => {
+                self.detect_next = true;
+                Ok(())
+            }
+            "audio-start" => self.start_stream(AudioFormat::from_data(&event.data)?),
+            "audio-chunk" => self.process_chunk(&event),
+            "audio-stop" => self.stop_stream(),
+            "synthesize" => {
+                let text = event
+                    .data
+                    .get("text")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string();
+                self.synthesize(&text)
+            }
+            other => {
+                debug!(event = other, "ignoring Wyoming event");
+                Ok(())
+            }
+        }
+    }
+
+    fn start_stream(&mut self, format: AudioFormat) -> Result<()> {
+        self.stream = Some(if std::mem::take(&mut self.detect_next) {
+            Stream::Detect {
+                format,
+                session: self
+                    .services
+                    .backend
+                    .wake_word_session(format.rate, format.channels)?,
+                frames: 0,
+                last_detection: None,
+                detected: false,
+            }
+        } else {
+            Stream::Transcribe {
+                format,
+                samples: Vec::new(),
+            }
+        });
+        Ok(())
+    }
+
+    fn process_chunk(&mut self, event: &Event) -> Result<()> {
+        // Peers may skip `audio-start`; take the format from the chunk then
+        if self.stream.is_none() {
+            self.start_stream(AudioFormat::from_data(&event.data)?)?;
+        }
+        let samples = pcm_to_f32(&event.payload);
+        let detection = match self.stream.as_mut() {
+            Some(Stream::Transcribe { samples: all, .. }) => {
+                all.extend_from_slice(&samples);
+                None
+            }
+            Some(Stream::Detect {
+                format,
+                session,
+                frames,
+                last_detection,
+                detected,
+            }) => {
+                *frames += samples.len() / format.channels.max(1) as usize;
+                let refractory = (WAKE_WORD_REFRACTORY_SECONDS * format.rate as f64) as usize;
+                match session.process(&samples) {
+                    Some(probability)
+                        if last_detection.is_none_or(|last| *frames - last > refractory) =>
+                    {
+                        *last_detection = Some(*frames);
+                        *detected = true;
+                        let timestamp_ms = *frames as u64 * 1000 / format.rate as u64;
+                        Some((probability, timestamp_ms))
+                    }
+                    _ => None,
+                }
+            }
+            None => None,
+        };
+
+        if let Some((probability, timestamp_ms)) = detection {
+            info!(probability, timestamp_ms, "wake word detected");
+            METRICS.wake_word_detections.inc();
+            let name = self.services.wake_word_name.clone();
+            self.send(Event::new(
+                "detection",
+                json!({ "name": name, "timestamp": timestamp_ms }),
+            ))?;
+        }
+        Ok(())
+    }
+
+    fn stop_stream(&mut self) -> Result<()> {
+        match self.stream.take() {
+            Some(Stream::Transcribe { format, samples }) => {
+                let audio = to_whisper_input(&samples, format.rate, format.channels)?;
+                let seconds = audio.len() as f64 / SAMPLE_RATE as f64;
+                let text = info_span!("transcribe", seconds)
+                    .in_scope(|| self.services.backend.transcribe(audio))?;
+                info!(text, "transcribed");
+                self.send(Event::new("transcript", json!({ "text": text })))
+            }
+            Some(Stream::Detect {
+                detected: false, ..
+            }) => self.send(Event::new("not-detected", json!({}))),
+            Some(Stream::Detect { .. }) | None => Ok(()),
+        }
+    }
+
+    fn synthesize(&mut self, text: &str) -> Result<()> {
+        let started = Instant::now();
+        let mut started_rate = None;
+        let writer = &mut self.writer;
+        let result = self
+            .services
+            .backend
+            .synthesize(text, &mut |rate, samples| {
+                if started_rate.is_none() {
+                    started_rate = Some(rate);
+                    write_event(writer, &audio_event("audio-start", rate))?;
+                }
+                write_event(
+                    writer,
+                    &audio_event("audio-chunk", rate).with_payload(f32_to_pcm(samples)),
+                )
+            });
+        if let Err(e) = result {
+            // Close the stream that was started, so the client is not left waiting on it
+            if let Some(rate) = started_rate {
+                let _ = self.send(audio_event("audio-stop", rate));
+            }
+            return Err(e);
+        }
+        // Always send a complete stream, even when nothing was generated
+        let rate = match started_rate {
+            Some(rate) => rate,
+            None => {
+                self.send(audio_event("audio-start", SAMPLE_RATE))?;
+                SAMPLE_RATE
+            }
+        };
+        debug!(
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "synthesized"
+        );
+        self.send(audio_event("audio-stop", rate))
+    }
+}
+
+fn audio_event(kind: &str, rate: u32) -> Event {
+    Event::new(kind, json!({ "rate": rate, "width": 2, "channels": 1 }))
+}
+
+fn handle_connection(stream: TcpStream, services: &Services) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut session = Session {
+        services,
+        writer: BufWriter::new(stream),
+        detect_next: false,
+        stream: None,
+    };
+    while let Some(event) = read_event(&mut reader)? {
+        let kind = event.kind.clone();
+        if let Err(e) = session.handle(event) {
+            // Report the failure and keep the connection; the next request may well succeed
+            warn!(error = %e, event = kind, "failed to handle Wyoming event");
+            session.stream = None;
+            session.send(Event::new("error", json!({ "text": e.to_string() })))?;
+        }
+    }
+    Ok(())
+}
+
+/// Accept connections forever, each on its own thread.
+pub fn serve(listener: TcpListener, services: Arc<Services>) -> Result<()> {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!(error = %e, "failed to accept Wyoming connection");
+                continue;
+            }
+        };
+        let peer = stream.peer_addr().ok();
+        debug!(?peer, "Wyoming connection");
+        let services = services.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, &services) {
+                warn!(error = %e, ?peer, "Wyoming connection failed");
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Serve the wake word, speech to text and text to speech as Wyoming services on
+/// `wyoming.listen_address`, until the process is stopped.
+pub fn run_wyoming_server(config: &AssistantConfig) -> Result<()> {
+    let wake_word_name = config
+        .wake_word
+        .model_path
+        .as_deref()
+        .map(model_name)
+        .unwrap_or_else(|| "alexa".to_string());
+    let info = describe(config, &wake_word_name);
+
+    let services = Arc::new(Services {
+        backend: Box::new(LocalBackend::load(config)?),
+        wake_word_name,
+        info,
+    });
+
+    let address = config.wyoming.listen_address;
+    let listener =
+        TcpListener::bind(address).wrap_err_with(|| format!("failed to listen on {}", address))?;
+    info!(%address, "serving Wyoming protocol");
+    serve(listener, services)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Transcribes to the number of samples received, speaks two 10-sample chunks per request
+    /// (failing after the first when asked to "stammer") and hears the wake word in any chunk
+    /// louder than half scale.
+    struct FakeBackend;
+
+    struct FakeWakeWord;
+
+    impl WakeWordSession for FakeWakeWord {
+        fn process(&mut self, samples: &[f32]) -> Option<f32> {
+            samples.iter().any(|s| s.abs() > 0.5).then_some(0.9)
+        }
+    }
+
+    impl Backend for FakeBackend {
+        fn transcribe(&self, audio: Vec<f32>) -> Result<String> {
+            Ok(format!("{} samples", audio.len()))
+        }
+
+        fn synthesize(
+            &self,
+            text: &str,
+            on_audio: &mut dyn FnMut(u32, &[f32]) -> Result<()>,
+        ) -> Result<()> {
+            if text.is_empty() {
+                return Err(color_eyre::eyre::eyre!("nothing to say"));
+            }
+            on_audio(24000, &[0.5; 10])?;
+            if text == "stammer" {
+                return Err(color_eyre::eyre::eyre!("lost for words"));
+            }
+            on_audio(24000, &[-0.5; 10])
+        }
+
+        fn wake_word_session(&self, _: u32, _: u16) -> Result<Box<dyn WakeWordSession>> {
+            Ok(Box::new(FakeWakeWord))
+        }
+    }
+
+    /// A Wyoming client stand-in talking to a server on a random local port.
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        fn connect() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            let services = Arc::new(Services {
+                backend: Box::new(FakeBackend),
+                wake_word_name: "alexa".to_string(),
+                info: json!({ "asr": [{ "name": "whisper" }] }),
+            });
+            thread::spawn(move || serve(listener, services));
+            let writer = TcpStream::connect(address).unwrap();
+            Self {
+                reader: BufReader::new(writer.try_clone().unwrap()),
+                writer,
+            }
+        }
+
+        fn send(&mut self, event: Event) {
+            write_event(&mut self.writer, &event).unwrap();
+        }
+
+        fn receive(&mut self) -> Event {
+            read_event(&mut self.reader).unwrap().unwrap()
+        }
+
+        fn send_audio(&mut self, rate: u32, channels: u16, chunks: &[Vec<f32>]) {
+            let format = json!({ "rate": rate, "width": 2, "channels": channels });
+            self.send(Event::new("audio-start", format.clone()));
+            for chunk in chunks {
+                self.send(
+                    Event::new("audio-chunk", format.clone()).with_payload(f32_to_pcm(chunk)),
+                );
+            }
+            self.send(Event::new("audio-stop", json!({})));
+        }
+    }
+
+    #[test]
+    fn events_round_trip_and_accept_inline_data() {
+        let event = Event::new(
+            "audio-chunk",
+            json!({ "rate": 16000, "width": 2, "channels": 1 }),
+        )
+        .with_payload(vec![1, 2, 3, 4]);
+        let mut bytes = Vec::new();
+        write_event(&mut bytes, &event).unwrap();
+        write_event(&mut bytes, &Event::new("describe", json!({}))).unwrap();
+        // Older clients put the data in the header line
+        bytes.extend_from_slice(b"{\"type\": \"transcribe\", \"data\": {\"language\": \"en\"}}\n");
+
+        let mut reader = &bytes[..];
+        assert_eq!(read_event(&mut reader).unwrap().unwrap(), event);
+        let describe = read_event(&mut reader).unwrap().unwrap();
+        assert_eq!(describe.kind, "describe");
+        assert!(describe.data.is_empty());
+        let transcribe = read_event(&mut reader).unwrap().unwrap();
+        assert_eq!(transcribe.data["language"], "en");
+        assert!(read_event(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn read_event_rejects_oversized_lengths() {
+        for header in [
+            "{\"type\": \"audio-chunk\", \"payload_length\": 18446744073709551615}\n",
+            "{\"type\": \"transcribe\", \"data_length\": 1000000000}\n",
+        ] {
+            let mut reader = header.as_bytes();
+            let message = read_event(&mut reader).unwrap_err().to_string();
+            assert!(message.contains("too long"), "{}", message);
+        }
+
+        let line = vec![b' '; MAX_EVENT_PART_LENGTH + 1];
+        let message = read_event(&mut &line[..]).unwrap_err().to_string();
+        assert!(message.contains("longer than"), "{}", message);
+    }
+
+    #[test]
+    fn server_describes_transcribes_synthesizes_and_detects() {
+        let mut client = Client::connect();
+
+        client.send(Event::new("describe", json!({})));
+        let info = client.receive();
+        assert_eq!(info.kind, "info");
+        assert_eq!(info.data["asr"][0]["name"], "whisper");
+
+        client.send(Event::new("transcribe", json!({ "language": "en" })));
+        client.send_audio(16000, 1, &[vec![0.1; 800], vec![0.1; 800]]);
+        let transcript = client.receive();
+        assert_eq!(transcript.kind, "transcript");
+        assert_eq!(transcript.data["text"], "1600 samples");
+
+        client.send(Event::new("synthesize", json!({ "text": "Hello" })));
+        let kinds = (0..4)
+            .map(|_| client.receive())
+            .map(|event| {
+                if event.kind != "audio-stop" {
+                    assert_eq!(event.data["rate"], 24000);
+                }
+                (event.kind, event.payload.len())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                ("audio-start".to_string(), 0),
+                ("audio-chunk".to_string(), 20),
+                ("audio-chunk".to_string(), 20),
+                ("audio-stop".to_string(), 0),
+            ]
+        );
+
+        client.send(Event::new("synthesize", json!({ "text": "" })));
+        assert_eq!(client.receive().kind, "error");
+
+        // A stream that breaks off is still closed before the error
+        client.send(Event::new("synthesize", json!({ "text": "stammer" })));
+        let kinds = (0..4).map(|_| client.receive().kind).collect::<Vec<_>>();
+        assert_eq!(kinds, ["audio-start", "audio-chunk", "audio-stop", "error"]);
+
+        client.send(Event::new("detect", json!({ "names": ["alexa"] })));
+        client.send_audio(
+            16000,
+            1,
+            &[vec![0.0; 1600], vec![0.9; 1600], vec![0.9; 1600]],
+        );
+        let detection = client.receive();
+        assert_eq!(detection.kind, "detection");
+        assert_eq!(detection.data["name"], "alexa");
+        assert_eq!(detection.data["timestamp"], 200);
+
+        client.send(Event::new("detect", json!({})));
+        client.send_audio(16000, 1, &[vec![0.0; 1600]]);
+        assert_eq!(client.receive().kind, "not-detected");
+    }
+}
@@ -14,6 +14,15 @@ pub enum TtsCommand {
     WaitUntilFinished,
     /// Set the volume for audio playback (0.0 to 1.0)
     SetVolume(f32),
+    /// Play a WAV file, e.g. an earcon, alongside any speech. Answered as soon as it starts.
+    PlaySound(String),
+    /// Cut the current `GenerateAudio` short, which then ends with `Interrupted` instead of
+    /// `Finished`. May be sent while another command is in flight; ignored when nothing is
+    /// playing and never answered on its own.
+    Interrupt,
+    /// Generate audio from text and send it back in `Audio` responses instead of playing it,
+    /// ending with `Finished`
+    Synthesize(String),
 }
 
 /// Responses from the TTS processor
@@ -32,6 +41,12 @@ pub enum TtsResponse {
     Error(String),
     /// Volume has been set
     VolumeSet,
+    /// Generation and playback were cut short by `Interrupt`
+    Interrupted,
+    /// A sound has started playing
+    SoundPlaying,
+    /// Mono samples generated for `Synthesize`
+    Audio { sample_rate: u32, samples: Vec<f32> },
 }
 
 /// Serialize a command to bytes
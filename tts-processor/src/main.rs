@@ -2,9 +2,11 @@ use color_eyre::eyre::Result;
 use pocket_tts::{ModelState, TTSModel};
 use rodio::source::Source;
 use std::collections::VecDeque;
+use std::fs::File;
 use std::io::{Read, Write};
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
@@ -185,6 +187,81 @@ impl AudioState {
     }
 }
 
+/// Start playing the WAV file at `path` on its own sink, so the speech that follows does not cut
+/// it off.
+fn play_sound(path: &str, mixer: &rodio::mixer::Mixer, volume: f32) -> Result<()> {
+    let file = File::open(path)?;
+    let source = rodio::Decoder::try_from(file)?;
+    let sink = rodio::Sink::connect_new(mixer);
+    sink.set_volume(volume);
+    sink.append(source);
+    sink.detach();
+    Ok(())
+}
+
+/// The samples of the first channel of a chunk from `generate_stream`.
+fn first_channel<E: std::fmt::Display>(
+    chunk: std::result::Result<candle_core::Tensor, E>,
+) -> Result<Option<Vec<f32>>> {
+    let audio_chunk =
+        chunk.map_err(|e| color_eyre::eyre::eyre!("Failed to get audio chunk: {}", e))?;
+
+    let audio_chunk_2d = audio_chunk
+        .squeeze(0)
+        .map_err(|e| color_eyre::eyre::eyre!("Failed to squeeze tensor: {}", e))?;
+
+    let audio_data_2d = audio_chunk_2d
+        .to_vec2::<f32>()
+        .map_err(|e| color_eyre::eyre::eyre!("Failed to convert tensor to vec: {}", e))?;
+
+    Ok(audio_data_2d.into_iter().next())
+}
+
+/// A command read from the connection, or why it could not be decoded
+enum Incoming {
+    Command(TtsCommand),
+    Invalid(String),
+}
+
+/// Read commands on their own thread, so an `Interrupt` can be seen while a `GenerateAudio` is
+/// still being handled. The channel closes when the connection does.
+fn spawn_command_reader(mut stream: UnixStream) -> Receiver<Incoming> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || loop {
+        let cmd_bytes = match read_length_prefixed_message(&mut stream) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                eprintln!("Error reading command: {}", e);
+                break;
+            }
+        };
+        let incoming = match deserialize_command(&cmd_bytes) {
+            Ok(cmd) => Incoming::Command(cmd),
+            Err(e) => {
+                eprintln!("Error deserializing command: {}", e);
+                Incoming::Invalid(e.to_string())
+            }
+        };
+        if tx.send(incoming).is_err() {
+            break;
+        }
+    });
+    rx
+}
+
+/// Whether playback should be cut short: an `Interrupt` arrived or the client went away. Other
+/// commands that arrive in the meantime are kept for afterwards.
+fn take_interrupt(commands: &Receiver<Incoming>, pending: &mut VecDeque<Incoming>) -> bool {
+    loop {
+        match commands.try_recv() {
+            Ok(Incoming::Command(TtsCommand::Interrupt)) => return true,
+            Ok(incoming) => pending.push_back(incoming),
+            Err(TryRecvError::Empty) => return false,
+            Err(TryRecvError::Disconnected) => return true,
+        }
+    }
+}
+
 fn handle_connection(
     mut stream: UnixStream,
     model: &TTSModel,
@@ -192,19 +269,21 @@ fn handle_connection(
     mixer: &rodio::mixer::Mixer,
     audio_state: &AudioState,
 ) -> Result<()> {
+    let commands = spawn_command_reader(stream.try_clone()?);
+    let mut pending = VecDeque::new();
+
     loop {
-        let cmd_bytes = match read_length_prefixed_message(&mut stream) {
-            Ok(bytes) => bytes,
-            Err(e) => {
-                eprintln!("Error reading command: {}", e);
-                break;
-            }
+        let incoming = match pending.pop_front() {
+            Some(incoming) => incoming,
+            None => match commands.recv() {
+                Ok(incoming) => incoming,
+                Err(_) => break,
+            },
         };
 
-        let cmd = match deserialize_command(&cmd_bytes) {
-            Ok(cmd) => cmd,
-            Err(e) => {
-                eprintln!("Error deserializing command: {}", e);
+        let cmd = match incoming {
+            Incoming::Command(cmd) => cmd,
+            Incoming::Invalid(e) => {
                 let resp = serialize_response(&TtsResponse::Error(format!(
                     "Failed to deserialize command: {}",
                     e
@@ -233,25 +312,23 @@ fn handle_connection(
                 sink.append(source);
                 *audio_state.current_sink.lock().unwrap() = Some(sink);
 
-                // Generate and stream audio chunks
-                for chunk in model.generate_stream(&text, voice_state) {
-                    let audio_chunk = chunk
-                        .map_err(|e| color_eyre::eyre::eyre!("Failed to get audio chunk: {}", e))?;
-
-                    let audio_chunk_2d = audio_chunk
-                        .squeeze(0)
-                        .map_err(|e| color_eyre::eyre::eyre!("Failed to squeeze tensor: {}", e))?;
-
-                    let audio_data_2d = audio_chunk_2d.to_vec2::<f32>().map_err(|e| {
-                        color_eyre::eyre::eyre!("Failed to convert tensor to vec: {}", e)
-                    })?;
+                // Generate and stream audio chunks, reporting each one so the client can
+                // measure latency
+                let mut interrupted = false;
+                for (index, chunk) in model.generate_stream(&text, voice_state).enumerate() {
+                    if take_interrupt(&commands, &mut pending) {
+                        interrupted = true;
+                        break;
+                    }
 
-                    let first_channel = audio_data_2d.into_iter().next();
-                    if let Some(channel) = first_channel {
+                    if let Some(channel) = first_channel(chunk)? {
                         if let Some(ref handle) = *audio_state.streaming_handle.lock().unwrap() {
                             handle.push_chunk(channel);
                         }
                     }
+
+                    let resp = serialize_response(&TtsResponse::ChunkGenerated(index as u32))?;
+                    write_length_prefixed_message(&mut stream, &resp)?;
                 }
 
                 // Mark streaming as finished
@@ -260,17 +337,50 @@ fn handle_connection(
                 }
 
                 // Wait until playback is finished
-                while !audio_state.is_finished() {
+                while !interrupted && !audio_state.is_finished() {
+                    interrupted = take_interrupt(&commands, &mut pending);
                     thread::sleep(Duration::from_millis(10));
                 }
 
                 // Clean up
-                *audio_state.streaming_handle.lock().unwrap() = None;
+                let resp = if interrupted {
+                    audio_state.stop();
+                    TtsResponse::Interrupted
+                } else {
+                    *audio_state.streaming_handle.lock().unwrap() = None;
+                    TtsResponse::Finished
+                };
+                let resp = serialize_response(&resp)?;
+                write_length_prefixed_message(&mut stream, &resp)?;
+            }
+            TtsCommand::Synthesize(text) => {
+                let resp = serialize_response(&TtsResponse::Started)?;
+                write_length_prefixed_message(&mut stream, &resp)?;
+
+                for chunk in model.generate_stream(&text, voice_state) {
+                    if let Some(samples) = first_channel(chunk)? {
+                        let resp = serialize_response(&TtsResponse::Audio {
+                            sample_rate: model.sample_rate as u32,
+                            samples,
+                        })?;
+                        write_length_prefixed_message(&mut stream, &resp)?;
+                    }
+                }
 
-                // Send finished response
                 let resp = serialize_response(&TtsResponse::Finished)?;
                 write_length_prefixed_message(&mut stream, &resp)?;
             }
+            TtsCommand::PlaySound(path) => {
+                let resp = match play_sound(&path, mixer, audio_state.get_volume()) {
+                    Ok(()) => TtsResponse::SoundPlaying,
+                    Err(e) => TtsResponse::Error(format!("Failed to play {}: {}", path, e)),
+                };
+                let resp = serialize_response(&resp)?;
+                write_length_prefixed_message(&mut stream, &resp)?;
+            }
+            TtsCommand::Interrupt => {
+                // Nothing is playing, so there is nothing to cut short
+            }
             TtsCommand::Stop => {
                 audio_state.stop();
                 let resp = serialize_response(&TtsResponse::Stopped)?;
@@ -303,9 +413,11 @@ fn main() -> Result<()> {
         .map(PathBuf::from)
         .map_err(|_| color_eyre::eyre::eyre!("TTS_SOCKET_PATH environment variable not set"))?;
 
-    // Load TTS model
+    // Load TTS model, paths can be overridden by the voice assistant's config
     let cwd = std::env::current_dir()?;
-    let model_path = cwd.join("model/tts_b6369a24.safetensors");
+    let model_path = cwd.join(
+        std::env::var("TTS_MODEL_PATH").unwrap_or("model/tts_b6369a24.safetensors".to_string()),
+    );
     let model_path_str = model_path.to_str().ok_or(color_eyre::eyre::eyre!(
         "Failed to convert model path to string"
     ))?;
@@ -314,7 +426,8 @@ fn main() -> Result<()> {
     let model = TTSModel::load(model_path_str)
         .map_err(|e| color_eyre::eyre::eyre!("Failed to load model: {}", e))?;
 
-    let voice_path = cwd.join("model/p303_023.wav");
+    let voice_path =
+        cwd.join(std::env::var("TTS_VOICE_PATH").unwrap_or("model/p303_023.wav".to_string()));
     let voice_path_str = voice_path.to_str().ok_or(color_eyre::eyre::eyre!(
         "Failed to convert voice path to string"
     ))?;
@@ -339,7 +452,8 @@ fn main() -> Result<()> {
     // Create shared audio state
     let audio_state = AudioState::new();
 
-    // Accept connections
+    // Each voice assistant spawns its own processor, so serve the first connection and exit once
+    // it closes, letting the assistant shut down without killing playback mid-sentence
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
@@ -350,6 +464,7 @@ fn main() -> Result<()> {
                 {
                     eprintln!("Error handling connection: {}", e);
                 }
+                break;
             }
             Err(e) => {
                 eprintln!("Error accepting connection: {}", e);
@@ -357,5 +472,8 @@ fn main() -> Result<()> {
         }
     }
 
+    audio_state.stop();
+    let _ = std::fs::remove_file(&socket_path);
+
     Ok(())
 }